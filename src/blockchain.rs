@@ -1,3 +1,7 @@
 pub mod block;
+#[allow(clippy::module_inception)]
 pub mod blockchain;
+pub mod miner;
+pub mod pow;
 pub mod utxoset;
+pub mod validation;
@@ -1,27 +1,121 @@
 //! Block implement of blockchain
 
+use crate::blockchain::miner::Miner;
+use crate::blockchain::pow::{self, ConsensusParams};
+use crate::blockchain::validation::ValidationError;
 use crate::crypto::transaction::*;
 use crate::Result;
 use bincode::serialize;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use failure::format_err;
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
-const INITIAL_DIFFICULTY: usize = 4;
-const DESIRED_BLOCK_TIME: u128 = 10_000;
-/// Block keeps block headers
+/// BlockHeader keeps the fields a block's hash commits to
+///
+/// Headers are small enough to fetch and check a whole chain of them before
+/// downloading any transactions. The height is not hashed; it is checked
+/// against the parent instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    prev_block_hash: String,
+    /// Root of the merkle tree of the block's transactions
+    merkle_root: Vec<u8>,
+    timestamp: u128,
+    /// Proof-of-work target in compact form
+    bits: u32,
+    nonce: u32,
+    height: i32,
+}
+
+/// Block keeps a header and the transactions it commits to
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
-    timestamp: u128,
-    transactions: Vec<Transaction>,
-    prev_block_hash: String,
+    header: BlockHeader,
     hash: String,
-    nonce: i32,
-    height: i32,
-    difficulty: usize,
+    transactions: Vec<Transaction>,
+}
+
+impl BlockHeader {
+    pub fn get_prev_hash(&self) -> String {
+        self.prev_block_hash.clone()
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Work is the expected number of hashes needed to mine the header
+    pub fn work(&self) -> u128 {
+        pow::work(self.bits)
+    }
+
+    /// Hash returns the header's hash, which is the block's hash
+    pub fn hash(&self) -> Result<String> {
+        self.hash_with_nonce(self.nonce)
+    }
+
+    /// HashWithNonce hashes the header with `nonce` in place of its own
+    pub(crate) fn hash_with_nonce(&self, nonce: u32) -> Result<String> {
+        let content = (
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.bits,
+            nonce,
+        );
+        let mut hasher = Sha256::new();
+        hasher.input(&serialize(&content)?);
+        Ok(hasher.result_str())
+    }
+
+    /// Check checks the header's proof-of-work and its place after `parent`
+    ///
+    /// `parent` is `None` only for a genesis header, and `bits` is the
+    /// target the chain demands of the header.
+    pub fn check(
+        &self,
+        parent: Option<&BlockHeader>,
+        bits: u32,
+    ) -> std::result::Result<(), ValidationError> {
+        if self.bits != bits {
+            return Err(ValidationError::BadBits {
+                expected: bits,
+                found: self.bits,
+            });
+        }
+        match self.hash() {
+            Ok(hash) if pow::meets(&hash, self.bits) => {}
+            _ => return Err(ValidationError::InsufficientWork),
+        }
+
+        let height = match parent {
+            Some(parent) if parent.hash().ok() == Some(self.prev_block_hash.clone()) => {
+                parent.height + 1
+            }
+            None if self.prev_block_hash.is_empty() => 0,
+            _ => return Err(ValidationError::UnknownParent(self.prev_block_hash.clone())),
+        };
+
+        if self.height != height {
+            return Err(ValidationError::BadHeight {
+                expected: height,
+                found: self.height,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Block {
@@ -29,8 +123,12 @@ impl Block {
         self.hash.clone()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.get_prev_hash()
     }
 
     pub fn get_transaction(&self) -> &Vec<Transaction> {
@@ -38,95 +136,132 @@ impl Block {
     }
 
     pub fn get_height(&self) -> i32 {
-        self.height
+        self.header.height
     }
 
-    /// NewBlock creates and returns Block
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
+    /// Work is the expected number of hashes needed to mine the block
+    pub fn work(&self) -> u128 {
+        self.header.work()
+    }
+
+    /// NewBlock creates and returns Block, mining it on every core
+    ///
+    /// `timestamp` is in milliseconds and `bits` the compact target the
+    /// block is mined to.
     pub fn new_block(
         transactions: Vec<Transaction>,
         prev_block_hash: String,
         height: i32,
-        difficulty: usize,
+        timestamp: u128,
+        bits: u32,
     ) -> Result<Block> {
+        let template = Block::new_template(transactions, prev_block_hash, height, timestamp, bits)?;
+        let miner = Miner::default();
+        miner
+            .mine(template, miner.epoch())?
+            .ok_or_else(|| format_err!("mining was cancelled"))
+    }
+
+    /// NewTemplate creates a Block that is not mined yet and has no hash
+    pub fn new_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        timestamp: u128,
+        bits: u32,
+    ) -> Result<Block> {
+        Ok(Block {
+            header: BlockHeader {
+                prev_block_hash,
+                merkle_root: hash_transactions(&transactions)?,
+                timestamp,
+                bits,
+                nonce: 0,
+                height,
+            },
+            hash: String::new(),
+            transactions,
+        })
+    }
+
+    /// NewGenesisBlock creates and returns genesis Block
+    pub fn new_genesis_block(coinbase: Transaction, params: &ConsensusParams) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
-        let mut block = Block {
+        Block::new_block(
+            vec![coinbase],
+            String::new(),
+            0,
             timestamp,
-            transactions,
-            prev_block_hash,
-            hash: String::new(),
-            nonce: 0,
-            height,
-            difficulty,
-        };
-        block.run_proof_of_work()?;
-        Ok(block)
+            params.pow_limit,
+        )
     }
 
-    /// NewGenesisBlock creates and returns genesis Block
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0, INITIAL_DIFFICULTY).unwrap()
+    /// CheckHeader checks the block's hash, merkle root and header rules
+    ///
+    /// See `check_body` and `BlockHeader::check` for `parent` and `bits`.
+    pub fn check_header(
+        &self,
+        parent: Option<&BlockHeader>,
+        bits: u32,
+    ) -> std::result::Result<(), ValidationError> {
+        self.check_body()?;
+        self.header.check(parent, bits)
     }
 
-    /// Run performs a proof-of-work
-    fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
+    /// CheckBody checks that the block's hash and merkle root match its
+    /// header and transactions
+    ///
+    /// The header commits to the transactions through the merkle root, so a
+    /// block whose transactions were altered fails here.
+    pub fn check_body(&self) -> std::result::Result<(), ValidationError> {
+        if self.header.hash().ok().as_ref() != Some(&self.hash) {
+            return Err(ValidationError::HashMismatch);
+        }
+        if hash_transactions(&self.transactions).ok().as_ref() != Some(&self.header.merkle_root) {
+            return Err(ValidationError::MerkleMismatch);
         }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
         Ok(())
     }
 
-    /// HashTransactions returns a hash of the transactions in the block
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
-        }
-        let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(transactions);
-
-        Ok(tree.root())
+    /// Seal sets the nonce a miner found and the hash it gives
+    pub(crate) fn seal(&mut self, nonce: u32) -> Result<()> {
+        self.header.nonce = nonce;
+        self.hash = self.header.hash()?;
+        Ok(())
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            self.difficulty,
-            self.nonce,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+    /// RollExtraNonce changes the coinbase, and so the merkle root, giving
+    /// a template whose nonces can be searched afresh
+    pub(crate) fn roll_extra_nonce(&mut self) -> Result<()> {
+        self.transactions
+            .iter_mut()
+            .find(|tx| tx.is_coinbase())
+            .ok_or_else(|| format_err!("block has no coinbase to roll"))?
+            .roll_extra_nonce()?;
+        self.header.merkle_root = hash_transactions(&self.transactions)?;
+        Ok(())
     }
+}
 
-    /// Validate validates block's PoW
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let hash_str = hasher.result_str();
-        let prefix = "0".repeat(self.difficulty);
-        Ok(hash_str.starts_with(&prefix))
+/// HashTransactions returns the merkle root of `transactions`
+fn hash_transactions(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let mut hashes = Vec::new();
+    for tx in transactions {
+        hashes.push(tx.hash()?.as_bytes().to_owned());
     }
+    let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(hashes);
 
-    pub fn adjust_difficulty(prev_block: &Block, current_timestamp: u128) -> usize {
-        let time_diff = current_timestamp - prev_block.timestamp;
-        let mut new_difficulty = prev_block.difficulty;
-
-        if time_diff < DESIRED_BLOCK_TIME {
-            new_difficulty += 1;
-        } else if time_diff > DESIRED_BLOCK_TIME && new_difficulty > 1 {
-            new_difficulty -= 1;
-        }
-
-        new_difficulty
-    }
+    Ok(tree.root())
 }
 
 struct MergeVu8 {}
@@ -143,3 +278,91 @@ impl Merge for MergeVu8 {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::wallets::Wallet;
+
+    /// About one hash in 256 meets this, so test blocks mine instantly
+    const EASY_BITS: u32 = 0x2000ffff;
+
+    /// Mines a block paying a throwaway address
+    fn mine(prev_hash: &str, height: i32, timestamp: u128, bits: u32) -> Block {
+        let address = Wallet::default().get_address();
+        let cbtx = Transaction::new_coinbase(address, String::new()).unwrap();
+        Block::new_block(vec![cbtx], prev_hash.to_string(), height, timestamp, bits).unwrap()
+    }
+
+    #[test]
+    fn test_check_header() {
+        let parent = mine("parent", 5, 1_000_000, EASY_BITS);
+        let block = mine(&parent.hash, 6, parent.get_timestamp() + 1, EASY_BITS);
+        let parent = parent.get_header();
+        assert_eq!(block.check_header(Some(parent), EASY_BITS), Ok(()));
+
+        let mut bad = block.clone();
+        bad.header.height = 7;
+        assert_eq!(
+            bad.check_header(Some(parent), EASY_BITS),
+            Err(ValidationError::BadHeight {
+                expected: 6,
+                found: 7
+            })
+        );
+
+        assert_eq!(
+            block.check_header(Some(parent), 0x1f00ffff),
+            Err(ValidationError::BadBits {
+                expected: 0x1f00ffff,
+                found: EASY_BITS
+            })
+        );
+
+        assert_eq!(
+            block.check_header(None, EASY_BITS),
+            Err(ValidationError::UnknownParent(parent.hash().unwrap()))
+        );
+        assert_eq!(
+            block.check_header(Some(block.get_header()), EASY_BITS),
+            Err(ValidationError::UnknownParent(parent.hash().unwrap()))
+        );
+
+        let genesis = mine("", 0, 1_000_000, EASY_BITS);
+        assert_eq!(genesis.check_header(None, EASY_BITS), Ok(()));
+        // A header alone passes the same rules
+        assert_eq!(genesis.get_header().check(None, EASY_BITS), Ok(()));
+        assert_eq!(genesis.get_header().hash().unwrap(), genesis.get_hash());
+    }
+
+    #[test]
+    fn test_check_header_rejects_tampering() {
+        let parent = mine("parent", 5, 1_000_000, EASY_BITS);
+        let block = mine(&parent.hash, 6, parent.get_timestamp() + 1, EASY_BITS);
+        let parent = parent.get_header();
+
+        let mut bad = block.clone();
+        bad.transactions[0].vout[0].value += 1;
+        assert_eq!(
+            bad.check_header(Some(parent), EASY_BITS),
+            Err(ValidationError::MerkleMismatch)
+        );
+
+        let mut bad = block.clone();
+        bad.header.nonce += 1;
+        assert_eq!(
+            bad.check_header(Some(parent), EASY_BITS),
+            Err(ValidationError::HashMismatch)
+        );
+
+        // A consistent hash that lacks the work its target demands
+        let hard = 0x1b00ffff;
+        let mut bad = block;
+        bad.header.bits = hard;
+        bad.hash = bad.header.hash().unwrap();
+        assert_eq!(
+            bad.check_header(Some(parent), hard),
+            Err(ValidationError::InsufficientWork)
+        );
+    }
+}
@@ -1,23 +1,57 @@
 //! Blockchain
 
 use crate::blockchain::block::*;
+use crate::blockchain::pow::ConsensusParams;
+use crate::blockchain::validation::{
+    check_block_transactions, check_timestamp, UtxoView, ValidationError,
+};
 use crate::crypto::traits::CryptoProvider;
 use crate::crypto::transaction::*;
 use crate::Result;
 use bincode::{deserialize, serialize};
-use failure::format_err;
+use failure::{format_err, Fail};
 use sled;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::SystemTime;
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+/// Tree holding every valid header, whether or not its block is stored
+const HEADERS_TREE: &str = "headers";
+/// Tree of the cumulative work of each header's chain, keyed like the headers
+const WORK_TREE: &str = "work";
+/// Tree of headers whose blocks break a rule, and of their descendants
+const INVALID_TREE: &str = "invalid";
+/// Tree of the best chain's block hashes by height
+const CHAIN_INDEX_TREE: &str = "chain_index";
+/// Tree of the best header chain's hashes by height
+const HEADER_INDEX_TREE: &str = "header_index";
+/// Key of the hash of the header chain with the most work
+const BEST_HEADER_KEY: &str = "BEST_HEADER";
+
 /// Blockchain implements interactions with a DB
 #[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
     pub db: sled::Db,
+    pub params: ConsensusParams,
+}
+
+/// TipChange lists the blocks that left and joined the best chain
+#[derive(Debug, Clone, Default)]
+pub struct TipChange {
+    /// Blocks no longer on the best chain, tip first
+    pub disconnected: Vec<Block>,
+    /// Blocks newly on the best chain, oldest first
+    pub connected: Vec<Block>,
+}
+
+impl TipChange {
+    /// IsEmpty reports whether the best chain stayed the same
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -26,6 +60,12 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+/// HeaderIterator walks stored headers back to genesis, yielding each with its hash
+pub struct HeaderIterator {
+    current_hash: String,
+    headers: Option<sled::Tree>,
+}
+
 impl Blockchain {
     /// NewBlockchain creates a new Blockchain db
     pub fn new() -> Result<Blockchain> {
@@ -42,7 +82,11 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { tip: lasthash, db })
+        Ok(Blockchain {
+            tip: lasthash,
+            db,
+            params: ConsensusParams::default(),
+        })
     }
 
     /// CreateBlockchain creates a new blockchain DB
@@ -53,84 +97,89 @@ impl Blockchain {
         let db = sled::open("data/blocks")?;
         debug!("Creating new block database");
         let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
+        let params = ConsensusParams::default();
+        let genesis: Block = Block::new_genesis_block(cbtx, &params)?;
+        let mut bc = Blockchain {
+            tip: String::new(),
             db,
+            params,
         };
-        bc.db.flush()?;
+        bc.add_block(genesis)?;
         Ok(bc)
     }
 
-    /// MineBlock mines a new block with the provided transactions
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        info!("mine a new block");
-
-        for tx in &transactions {
-            if !self.verify_transacton(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
-            }
-        }
+    /// BlockTemplate returns an unmined block on the tip holding `transactions`
+    ///
+    /// The transactions are checked against `view`, the unspent outputs as
+    /// of the tip. The template is not stored; once mined it goes through
+    /// `add_block`.
+    pub fn block_template(
+        &self,
+        transactions: Vec<Transaction>,
+        mut view: UtxoView,
+    ) -> Result<Block> {
+        let prev_hash = self.tip.clone();
+        view.fetch(&transactions)?;
+        check_block_transactions(&transactions, &mut view)?;
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-        let prev_hash = String::from_utf8(lasthash.to_vec())?;
-        let prev_block = self.get_block(&prev_hash)?;
-        let current_timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
-        let new_difficulty = Block::adjust_difficulty(&prev_block, current_timestamp);
+        let prev_header = self.get_block(&prev_hash)?.get_header().clone();
+        // A clock set back must not produce a block at or before the median time past
+        let current_timestamp = now()?.max(self.median_time_past(&prev_hash) + 1);
+        let bits = self.next_bits(Some(&prev_header))?;
 
-        let newblock = Block::new_block(
+        Block::new_template(
             transactions,
             prev_hash,
-            self.get_best_height()? + 1,
-            new_difficulty,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-
-        self.tip = newblock.get_hash();
-        Ok(newblock)
+            prev_header.get_height() + 1,
+            current_timestamp,
+            bits,
+        )
     }
 
     /// Iterator returns a BlockchainIterat
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
+        self.iter_from(&self.tip)
+    }
+
+    /// IterFrom iterates from the block `hash` back to the genesis block
+    pub fn iter_from(&self, hash: &str) -> BlockchainIterator<'_> {
         BlockchainIterator {
-            current_hash: self.tip.clone(),
+            current_hash: hash.to_string(),
             bc: self,
         }
     }
 
+    /// IterHeadersFrom iterates from the header `hash` back to the genesis header
+    pub fn iter_headers_from(&self, hash: &str) -> HeaderIterator {
+        HeaderIterator {
+            current_hash: hash.to_string(),
+            headers: self.headers().ok(),
+        }
+    }
+
     /// FindUTXO finds and returns all unspent transaction outputs
     pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
         let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
         let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
 
         for block in self.iter() {
-            for tx in block.get_transaction() {
+            // Backwards, so a spend in the same block is seen before its output
+            for tx in block.get_transaction().iter().rev() {
                 for index in 0..tx.vout.len() {
+                    let vout = index as i32;
                     if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
+                        if ids.contains(&vout) {
                             continue;
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs {
+                            outputs: BTreeMap::new(),
+                        })
+                        .outputs
+                        .insert(vout, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -193,28 +242,485 @@ impl Blockchain {
         tx.verify(prev_TXs)
     }
 
+    /// AddHeader checks a header against the header chain it extends and stores it
+    ///
+    /// Headers are accepted ahead of their blocks, so a chain's proof-of-work
+    /// can be checked before any of its transactions are downloaded. The
+    /// parent header must already be known and not invalid. Returns the
+    /// header's hash.
+    pub fn add_header(&mut self, header: &BlockHeader) -> Result<String> {
+        let hash = header.hash()?;
+        let invalid = |e| rule_broken(format!("Invalid header {}", hash), e);
+        if self.is_invalid(&hash)? {
+            return Err(invalid(ValidationError::KnownInvalid));
+        }
+        let headers = self.headers()?;
+        if headers.contains_key(&hash)? {
+            return Ok(hash);
+        }
+
+        let prev_hash = header.get_prev_hash();
+        if self.is_invalid(&prev_hash)? {
+            return Err(invalid(ValidationError::InvalidAncestor(prev_hash)));
+        }
+        let parent = self.get_header(&prev_hash)?;
+        let bits = self.next_bits(parent.as_ref())?;
+        header.check(parent.as_ref(), bits).map_err(invalid)?;
+        let median_time_past = parent.as_ref().map(|_| self.median_time_past(&prev_hash));
+        check_timestamp(header, median_time_past, now()?, &self.params).map_err(invalid)?;
+
+        let work = self.chain_work(&prev_hash).saturating_add(header.work());
+        self.db
+            .open_tree(WORK_TREE)?
+            .insert(&hash, &work.to_be_bytes())?;
+        headers.insert(&hash, serialize(header)?)?;
+        if work > self.chain_work(&self.best_header()?) {
+            self.db.insert(BEST_HEADER_KEY, hash.as_bytes())?;
+        }
+        Ok(hash)
+    }
+
     /// AddBlock saves the block into the blockchain
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
+    ///
+    /// The block is checked against the chain it extends, so its parent must
+    /// already be stored. Blocks on side branches are kept; the best chain is
+    /// the one with the most cumulative work, and ties keep the chain seen
+    /// first. Returns how the best chain changed.
+    ///
+    /// Transactions are checked against outputs replayed from the blocks;
+    /// a chain with a UTXO set adds blocks through it instead.
+    pub fn add_block(&mut self, block: Block) -> Result<TipChange> {
+        self.add_block_with(block, UtxoView::replay)
+    }
+
+    /// AddBlockWith is AddBlock with the view a block's transactions are
+    /// checked against built by `view_at` from the chain and the parent's hash
+    ///
+    /// The header rules are left to `add_header`; the view is only built
+    /// once the header has passed them.
+    pub fn add_block_with(
+        &mut self,
+        block: Block,
+        view_at: impl FnOnce(&Blockchain, &str) -> Result<UtxoView>,
+    ) -> Result<TipChange> {
         let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+        let hash = block.get_hash();
+        if self.has_block(&hash)? {
+            return Ok(TipChange::default());
+        }
+
+        let invalid = |e| rule_broken(format!("Invalid block {}", hash), e);
+        if self.is_invalid(&hash)? {
+            return Err(invalid(ValidationError::KnownInvalid));
         }
-        self.db.insert(block.get_hash(), data)?;
+        let prev_hash = block.get_prev_hash();
+        block.check_body().map_err(invalid)?;
+        self.add_header(block.get_header())?;
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash)? {
+            return Err(format_err!(
+                "Block {} arrived before its parent {}",
+                block.get_hash(),
+                prev_hash
+            ));
+        }
+        let mut view = view_at(self, &prev_hash)?;
+        view.fetch(block.get_transaction())?;
+        if let Err(e) = check_block_transactions(block.get_transaction(), &mut view) {
+            // The header is stored, but neither it nor anything built on it
+            // may lead the best header chain any more
+            self.invalidate(&hash)?;
+            return Err(invalid(e));
+        }
+
+        self.db.insert(&hash, data)?;
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
+        let work = self.chain_work(&prev_hash).saturating_add(block.work());
+        if work <= self.chain_work(&self.tip) {
             self.db.flush()?;
+            return Ok(TipChange::default());
+        }
+
+        let change = self.tip_change(&self.tip, &hash)?;
+        if !change.disconnected.is_empty() {
+            info!(
+                "Reorganising: {} blocks disconnected, {} connected",
+                change.disconnected.len(),
+                change.connected.len()
+            );
+        }
+        self.db.insert("LAST", hash.as_bytes())?;
+        self.tip = hash;
+        self.db.flush()?;
+        Ok(change)
+    }
+
+    /// NextBits returns the target a header on `parent` must meet
+    ///
+    /// The target changes only at the first block of each retarget window;
+    /// a genesis block, with no parent, uses the proof-of-work limit.
+    pub fn next_bits(&self, parent: Option<&BlockHeader>) -> Result<u32> {
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return Ok(self.params.pow_limit),
+        };
+        let window = self.params.retarget_window.max(2);
+        if (parent.get_height() + 1) % window != 0 {
+            return Ok(parent.get_bits());
+        }
+
+        let parent_hash = parent.hash()?;
+        let (first_hash, _) = self
+            .iter_headers_from(&parent_hash)
+            .nth(window as usize - 1)
+            .ok_or_else(|| format_err!("Missing ancestors of block {}", parent_hash))?;
+        // Median times past only move forward, however skewed single timestamps are
+        let timespan = self
+            .median_time_past(&parent_hash)
+            .saturating_sub(self.median_time_past(&first_hash));
+        Ok(self.params.retarget(parent.get_bits(), timespan))
+    }
+
+    /// MedianTimePast returns the median timestamp of the block `hash` and
+    /// its nearest ancestors, or zero when there are none
+    pub fn median_time_past(&self, hash: &str) -> u128 {
+        let mut timestamps: Vec<u128> = self
+            .iter_headers_from(hash)
+            .take(self.params.median_time_span.max(1))
+            .map(|(_, header)| header.get_timestamp())
+            .collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// ChainWork returns the total work of the header `hash` and its ancestors
+    ///
+    /// It is stored with each header; headers stored before that are summed
+    /// once and the totals kept.
+    pub fn chain_work(&self, hash: &str) -> u128 {
+        self.stored_work(hash).unwrap_or_else(|e| {
+            warn!("Cannot read the chain work of {}: {}", hash, e);
+            0
+        })
+    }
+
+    fn stored_work(&self, hash: &str) -> Result<u128> {
+        let works = self.db.open_tree(WORK_TREE)?;
+        let read = |data: sled::IVec| -> Result<u128> {
+            let bytes = <[u8; 16]>::try_from(data.as_ref())
+                .map_err(|_| format_err!("Malformed chain work of {}", hash))?;
+            Ok(u128::from_be_bytes(bytes))
+        };
+
+        if let Some(data) = works.get(hash)? {
+            return read(data);
+        }
+
+        let mut unsummed = Vec::new();
+        let mut work = 0u128;
+        for (ancestor, header) in self.iter_headers_from(hash) {
+            if let Some(data) = works.get(&ancestor)? {
+                work = read(data)?;
+                break;
+            }
+            unsummed.push((ancestor, header));
+        }
+        for (ancestor, header) in unsummed.into_iter().rev() {
+            work = work.saturating_add(header.work());
+            works.insert(ancestor.as_bytes(), &work.to_be_bytes())?;
+        }
+        Ok(work)
+    }
+
+    /// GetHeader returns a stored header, or None if it is unknown
+    pub fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>> {
+        match self.headers()?.get(hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// HasBlock reports whether the block `hash` itself, not just its header, is stored
+    pub fn has_block(&self, hash: &str) -> Result<bool> {
+        Ok(!hash.is_empty() && self.db.contains_key(hash)?)
+    }
+
+    /// BestHeader returns the hash of the header chain with the most work
+    ///
+    /// It is the tip itself unless headers have been accepted ahead of
+    /// their blocks.
+    pub fn best_header(&self) -> Result<String> {
+        match self.db.get(BEST_HEADER_KEY)? {
+            Some(hash) => Ok(String::from_utf8(hash.to_vec())?),
+            None => Ok(self.tip.clone()),
+        }
+    }
+
+    /// Locator returns hashes of the header chain ending at `hash`, newest
+    /// first
+    ///
+    /// The first ten are consecutive and the gaps then double, always
+    /// ending at genesis, so a peer finds where its chain forks from ours
+    /// in a few dozen hashes however long the chains are.
+    pub fn locator(&self, hash: &str) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut skip = 0;
+        let mut last = None;
+        for (hash, _) in self.iter_headers_from(hash) {
+            if skip == 0 {
+                locator.push(hash.clone());
+                if locator.len() >= 10 {
+                    step *= 2;
+                }
+                skip = step - 1;
+            } else {
+                skip -= 1;
+            }
+            last = Some(hash);
+        }
+        if let Some(genesis) = last {
+            if locator.last() != Some(&genesis) {
+                locator.push(genesis);
+            }
+        }
+        locator
+    }
+
+    /// HeadersAfter returns up to `limit` headers of the best chain following
+    /// the fork point of `locator`, oldest first
+    ///
+    /// The fork point is the newest locator hash on the best chain; a
+    /// locator sharing none gets the chain from genesis.
+    pub fn headers_after(&self, locator: &[String], limit: usize) -> Vec<BlockHeader> {
+        self.chain_after(locator, limit)
+            .into_iter()
+            .map(|(_, header)| header)
+            .collect()
+    }
+
+    /// HashesAfter returns up to `limit` hashes of the best chain following
+    /// the fork point of `locator`, oldest first
+    pub fn hashes_after(&self, locator: &[String], limit: usize) -> Vec<String> {
+        self.chain_after(locator, limit)
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect()
+    }
+
+    /// MissingBlocks returns up to `limit` hashes of the best header chain
+    /// whose blocks are not stored yet, oldest first
+    pub fn missing_blocks(&self, limit: usize) -> Result<Vec<String>> {
+        let best = self.best_header()?;
+        let index = self.chain_index(HEADER_INDEX_TREE, &best)?;
+        let top = self.height_of(&best)?;
+
+        // A block is only stored once its parent is, so the stored blocks
+        // of the chain are a prefix of it
+        let (mut low, mut high) = (0, top + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match index.get(height_key(mid))? {
+                Some(hash) if self.db.contains_key(&hash)? => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+
+        let mut missing = Vec::new();
+        for height in (low..=top).take(limit) {
+            if let Some(hash) = index.get(height_key(height))? {
+                missing.push(String::from_utf8(hash.to_vec())?);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// ChainAfter returns the best chain following the fork point of `locator`
+    fn chain_after(&self, locator: &[String], limit: usize) -> Vec<(String, BlockHeader)> {
+        match self.try_chain_after(locator, limit) {
+            Ok(chain) => chain,
+            Err(e) => {
+                warn!("Cannot read the best chain: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn try_chain_after(
+        &self,
+        locator: &[String],
+        limit: usize,
+    ) -> Result<Vec<(String, BlockHeader)>> {
+        let index = self.chain_index(CHAIN_INDEX_TREE, &self.tip)?;
+        let on_chain = |hash: &String| -> Result<Option<i32>> {
+            let height = match self.get_header(hash)? {
+                Some(header) => header.get_height(),
+                None => return Ok(None),
+            };
+            let indexed = index.get(height_key(height))?;
+            Ok((indexed.as_deref() == Some(hash.as_bytes())).then_some(height))
+        };
+
+        let mut start = 0;
+        for hash in locator {
+            if let Some(height) = on_chain(hash)? {
+                start = height + 1;
+                break;
+            }
+        }
+
+        let mut chain = Vec::new();
+        for height in (start..).take(limit) {
+            let hash = match index.get(height_key(height))? {
+                Some(hash) => String::from_utf8(hash.to_vec())?,
+                None => break,
+            };
+            let header = self
+                .get_header(&hash)?
+                .ok_or_else(|| format_err!("Missing header {}", hash))?;
+            chain.push((hash, header));
+        }
+        Ok(chain)
+    }
+
+    /// ChainIndex returns the index tree `name` of hashes by height,
+    /// pointed at the chain ending at `tip`
+    ///
+    /// Only heights above where `tip` forks from the indexed chain are
+    /// rewritten, so following the chain costs as much as the fork is deep.
+    fn chain_index(&self, name: &str, tip: &str) -> Result<sled::Tree> {
+        let index = self.db.open_tree(name)?;
+        let top = self.height_of(tip)?;
+        let indexed = |height: i32, hash: &str| -> Result<bool> {
+            Ok(index.get(height_key(height))?.as_deref() == Some(hash.as_bytes()))
+        };
+        let last = index.last()?.map(|(key, _)| key);
+        if indexed(top, tip)? && last.as_deref() == Some(&height_key(top)[..]) {
+            return Ok(index);
+        }
+
+        for kv in index.range(height_key(top + 1)..) {
+            let (key, _) = kv?;
+            index.remove(key)?;
+        }
+        let mut fork = Vec::new();
+        for (hash, header) in self.iter_headers_from(tip) {
+            if indexed(header.get_height(), &hash)? {
+                break;
+            }
+            fork.push((header.get_height(), hash));
+        }
+        // Oldest first, so the tip is only indexed once the chain below it is
+        for (height, hash) in fork.into_iter().rev() {
+            index.insert(height_key(height), hash.as_bytes())?;
         }
+        Ok(index)
+    }
+
+    /// HeightOf returns the height of a stored header, or -1 for the empty
+    /// parent of genesis
+    fn height_of(&self, hash: &str) -> Result<i32> {
+        if hash.is_empty() {
+            return Ok(-1);
+        }
+        self.get_header(hash)?
+            .map(|header| header.get_height())
+            .ok_or_else(|| format_err!("Missing header {}", hash))
+    }
+
+    /// IsInvalid reports whether the header `hash` was marked invalid
+    fn is_invalid(&self, hash: &str) -> Result<bool> {
+        Ok(self.db.open_tree(INVALID_TREE)?.contains_key(hash)?)
+    }
+
+    /// Invalidate marks a header and every known descendant invalid and,
+    /// if the best header was among them, picks the best remaining one
+    ///
+    /// Invalid blocks need their proof-of-work solved, so the scans over
+    /// every header are rare.
+    fn invalidate(&self, hash: &str) -> Result<()> {
+        let headers = self.headers()?;
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for kv in headers.iter() {
+            let (key, data) = kv?;
+            let header: BlockHeader = deserialize(&data)?;
+            children
+                .entry(header.get_prev_hash())
+                .or_default()
+                .push(String::from_utf8(key.to_vec())?);
+        }
+        let mut stack = vec![hash.to_string()];
+        while let Some(hash) = stack.pop() {
+            invalid.insert(hash.as_bytes(), &[])?;
+            stack.extend(children.remove(&hash).unwrap_or_default());
+        }
+
+        if !invalid.contains_key(self.best_header()?)? {
+            return Ok(());
+        }
+        // The tip's blocks were all checked, so it is always a candidate
+        let mut best = (self.chain_work(&self.tip), self.tip.clone());
+        for kv in headers.iter() {
+            let (key, _) = kv?;
+            if invalid.contains_key(&key)? {
+                continue;
+            }
+            let candidate = String::from_utf8(key.to_vec())?;
+            let work = self.chain_work(&candidate);
+            if work > best.0 {
+                best = (work, candidate);
+            }
+        }
+        info!("Best header {} was invalid, now {}", hash, best.1);
+        self.db.insert(BEST_HEADER_KEY, best.1.as_bytes())?;
         Ok(())
     }
 
+    fn headers(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(HEADERS_TREE)?)
+    }
+
+    /// TipChange walks both tips back to their common ancestor
+    fn tip_change(&self, old_tip: &str, new_tip: &str) -> Result<TipChange> {
+        let mut change = TipChange::default();
+        let mut old = self.find_block(old_tip)?;
+        let mut new = self.find_block(new_tip)?;
+
+        loop {
+            let step_old = match (&old, &new) {
+                (Some(o), Some(n)) if o.get_hash() == n.get_hash() => break,
+                (Some(o), Some(n)) => o.get_height() >= n.get_height(),
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if step_old {
+                let o = old.take().unwrap();
+                old = self.find_block(&o.get_prev_hash())?;
+                change.disconnected.push(o);
+            } else {
+                let n = new.take().unwrap();
+                new = self.find_block(&n.get_prev_hash())?;
+                change.connected.push(n);
+            }
+        }
+
+        change.connected.reverse();
+        Ok(change)
+    }
+
+    /// FindBlock returns a block by hash, or None for the empty parent of genesis
+    fn find_block(&self, block_hash: &str) -> Result<Option<Block>> {
+        match self.db.get(block_hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data)?;
-        Ok(block)
+        self.find_block(block_hash)?
+            .ok_or_else(|| format_err!("block {} not found", block_hash))
     }
 
     /// GetBestHeight returns the height of the latest block
@@ -239,6 +745,36 @@ impl Blockchain {
     }
 }
 
+/// RuleBroken describes a rule `what` breaks, keeping the `ValidationError`
+/// as the cause so callers can tell invalid data from other failures
+fn rule_broken(what: String, e: ValidationError) -> failure::Error {
+    let message = format!("{}: {}", what, e);
+    e.context(message).into()
+}
+
+/// HeightKey orders index keys by height
+fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
+
+/// Now returns the local clock in milliseconds, the unit of block timestamps
+fn now() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis())
+}
+
+impl Iterator for HeaderIterator {
+    type Item = (String, BlockHeader);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.headers.as_ref()?.get(&self.current_hash).ok()??;
+        let header: BlockHeader = deserialize(&data).ok()?;
+        let hash = std::mem::replace(&mut self.current_hash, header.get_prev_hash());
+        Some((hash, header))
+    }
+}
+
 impl Iterator for BlockchainIterator<'_> {
     type Item = Block;
 
@@ -259,3 +795,329 @@ impl Iterator for BlockchainIterator<'_> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::wallets::Wallet;
+
+    /// About one hash in 256 meets this, so test blocks mine instantly
+    const EASY_BITS: u32 = 0x2000ffff;
+    /// Slower than the block time, which cannot ease the limit further
+    const SLOW: u128 = 20_000;
+    /// Much faster than the block time, so the next retarget hardens
+    const FAST: u128 = 1;
+
+    /// Opens an empty chain with short retarget windows in a throwaway database
+    fn empty_chain() -> Blockchain {
+        Blockchain {
+            tip: String::new(),
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            params: ConsensusParams {
+                pow_limit: EASY_BITS,
+                retarget_window: 4,
+                ..ConsensusParams::default()
+            },
+        }
+    }
+
+    fn coinbase() -> Transaction {
+        let address = Wallet::default().get_address();
+        Transaction::new_coinbase(address, String::new()).unwrap()
+    }
+
+    /// Mines a block on `parent` `interval` milliseconds after it, as a
+    /// competing miner would
+    fn mine_on(bc: &Blockchain, parent: &Block, interval: u128) -> Block {
+        Block::new_block(
+            vec![coinbase()],
+            parent.get_hash(),
+            parent.get_height() + 1,
+            parent.get_timestamp() + interval,
+            bc.next_bits(Some(parent.get_header())).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<String> {
+        blocks.iter().map(Block::get_hash).collect()
+    }
+
+    #[test]
+    fn test_competing_miners() {
+        let mut bc = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &bc.params).unwrap();
+        let change = bc.add_block(genesis.clone()).unwrap();
+        assert_eq!(hashes(&change.connected), [genesis.get_hash()]);
+
+        // Two miners find a block on genesis; the first one seen wins the tie
+        let a1 = mine_on(&bc, &genesis, FAST);
+        let b1 = mine_on(&bc, &genesis, SLOW);
+        assert_eq!(
+            hashes(&bc.add_block(a1.clone()).unwrap().connected),
+            [a1.get_hash()]
+        );
+        assert!(bc.add_block(b1.clone()).unwrap().is_empty());
+        assert_eq!(bc.tip, a1.get_hash());
+
+        // The side branch grows past the tip and takes over
+        let b2 = mine_on(&bc, &b1, SLOW);
+        let change = bc.add_block(b2.clone()).unwrap();
+        assert_eq!(hashes(&change.disconnected), [a1.get_hash()]);
+        assert_eq!(hashes(&change.connected), hashes(&[b1.clone(), b2.clone()]));
+        assert_eq!(bc.tip, b2.get_hash());
+
+        let mut branch_b = vec![b1, b2];
+        for _ in 0..3 {
+            let next = mine_on(&bc, branch_b.last().unwrap(), SLOW);
+            bc.add_block(next.clone()).unwrap();
+            branch_b.push(next);
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 5);
+        assert_eq!(branch_b[3].get_bits(), EASY_BITS);
+
+        // A fast window makes the next target harder, so a shorter branch
+        // can carry more work than a longer one
+        let a2 = mine_on(&bc, &a1, FAST);
+        assert!(bc.add_block(a2.clone()).unwrap().is_empty());
+        let a3 = mine_on(&bc, &a2, FAST);
+        assert!(bc.add_block(a3.clone()).unwrap().is_empty());
+
+        let lazy = Block::new_block(
+            vec![coinbase()],
+            a3.get_hash(),
+            4,
+            a3.get_timestamp() + FAST,
+            EASY_BITS,
+        )
+        .unwrap();
+        let err = bc.add_block(lazy).unwrap_err();
+        assert!(err.to_string().contains("bits"), "{}", err);
+
+        let a4 = mine_on(&bc, &a3, FAST);
+        assert_eq!(a4.work(), 4 * a1.work());
+        let change = bc.add_block(a4.clone()).unwrap();
+        branch_b.reverse();
+        assert_eq!(hashes(&change.disconnected), hashes(&branch_b));
+        let branch_a = [a1, a2, a3, a4.clone()];
+        assert_eq!(hashes(&change.connected), hashes(&branch_a));
+        assert_eq!(bc.tip, a4.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 4);
+        assert_eq!(bc.chain_work(&bc.tip), 8 * genesis.work());
+
+        // Chains stored before their work was kept are summed once
+        let works = bc.db.open_tree(WORK_TREE).unwrap();
+        works.clear().unwrap();
+        assert_eq!(bc.chain_work(&bc.tip), 8 * genesis.work());
+        assert!(works.contains_key(genesis.get_hash()).unwrap());
+
+        // Heights of the abandoned longer branch are no longer served
+        assert_eq!(bc.hashes_after(&[], 10)[1..], hashes(&branch_a));
+
+        // Known blocks change nothing
+        assert!(bc.add_block(genesis).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_timestamp_rules() {
+        let mut bc = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &bc.params).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let b1 = mine_on(&bc, &genesis, SLOW);
+        bc.add_block(b1.clone()).unwrap();
+
+        // The median of two timestamps is the later one, so the next block
+        // must come after b1 rather than merely after genesis
+        assert_eq!(bc.median_time_past(&b1.get_hash()), b1.get_timestamp());
+        let err = bc.add_block(mine_on(&bc, &b1, 0)).unwrap_err();
+        assert!(err.to_string().contains("median time past"), "{}", err);
+
+        let drift = bc.params.max_future_drift;
+        let err = bc.add_block(mine_on(&bc, &b1, drift + SLOW)).unwrap_err();
+        assert!(err.to_string().contains("future"), "{}", err);
+
+        // One block claiming to be an hour late cannot ease the next target:
+        // the window is measured between medians, which ignore it
+        let b2 = mine_on(&bc, &b1, FAST);
+        bc.add_block(b2.clone()).unwrap();
+        let b3 = mine_on(&bc, &b2, 60 * 60 * 1000);
+        bc.add_block(b3.clone()).unwrap();
+        assert_eq!(bc.median_time_past(&b3.get_hash()), b2.get_timestamp());
+        assert_ne!(bc.next_bits(Some(b3.get_header())).unwrap(), EASY_BITS);
+    }
+
+    #[test]
+    fn test_headers_first() {
+        let mut source = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &source.params).unwrap();
+        source.add_block(genesis.clone()).unwrap();
+        let mut blocks = vec![genesis];
+        for _ in 0..5 {
+            let next = mine_on(&source, blocks.last().unwrap(), SLOW);
+            source.add_block(next.clone()).unwrap();
+            blocks.push(next);
+        }
+
+        // Served oldest first, after the last hash the peer already has
+        let headers = source.headers_after(&[blocks[1].get_hash()], 3);
+        assert_eq!(
+            headers,
+            [2, 3, 4].map(|i| blocks[i].get_header().clone()).to_vec()
+        );
+        assert_eq!(
+            source.headers_after(&[String::from("unknown")], 100).len(),
+            6
+        );
+
+        let mut bc = empty_chain();
+        for header in source.headers_after(&[], 100) {
+            bc.add_header(&header).unwrap();
+        }
+        assert_eq!(bc.best_header().unwrap(), source.tip);
+        assert!(bc.tip.is_empty());
+        assert_eq!(bc.missing_blocks(2).unwrap(), hashes(&blocks[..2]));
+
+        // A body needs its parent's body, not just its header
+        let err = bc.add_block(blocks[1].clone()).unwrap_err();
+        assert!(err.to_string().contains("before its parent"), "{}", err);
+
+        for block in &blocks {
+            bc.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(bc.tip, source.tip);
+        assert!(bc.missing_blocks(10).unwrap().is_empty());
+
+        // Headers that do not follow the rules are turned away
+        let lazy = Block::new_block(
+            vec![coinbase()],
+            source.tip.clone(),
+            6,
+            blocks[5].get_timestamp() + SLOW,
+            0x2100ffff,
+        )
+        .unwrap();
+        let err = bc.add_header(lazy.get_header()).unwrap_err();
+        assert!(err.to_string().contains("bits"), "{}", err);
+    }
+
+    #[test]
+    fn test_coinbase_must_come_first() {
+        let mut bc = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &bc.params).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+
+        // Valid proof-of-work, but the coinbase follows another transaction
+        let mut spend = coinbase();
+        spend.vin[0].txid = genesis.get_transaction()[0].id.clone();
+        spend.vin[0].vout = 0;
+        spend.id = spend.hash().unwrap();
+        let crafted = Block::new_block(
+            vec![spend, coinbase()],
+            genesis.get_hash(),
+            1,
+            genesis.get_timestamp() + SLOW,
+            bc.next_bits(Some(genesis.get_header())).unwrap(),
+        )
+        .unwrap();
+        let err = bc.add_block(crafted).unwrap_err();
+        assert!(err.to_string().contains("not the first"), "{}", err);
+        assert_eq!(bc.tip, genesis.get_hash());
+    }
+
+    #[test]
+    fn test_invalid_blocks_leave_the_best_header_chain() {
+        let mut bc = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &bc.params).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+
+        // Valid proof-of-work, but the coinbase claims more than the subsidy
+        let mut greedy = coinbase();
+        greedy.vout[0].value = SUBSIDY + 1;
+        greedy.id = greedy.unsigned_hash().unwrap();
+        let bad = Block::new_block(
+            vec![greedy],
+            genesis.get_hash(),
+            1,
+            genesis.get_timestamp() + SLOW,
+            bc.next_bits(Some(genesis.get_header())).unwrap(),
+        )
+        .unwrap();
+        let child = mine_on(&bc, &bad, SLOW);
+        bc.add_header(bad.get_header()).unwrap();
+        bc.add_header(child.get_header()).unwrap();
+        assert_eq!(bc.best_header().unwrap(), child.get_hash());
+        assert_eq!(
+            bc.missing_blocks(10).unwrap(),
+            hashes(&[bad.clone(), child.clone()])
+        );
+
+        let err = bc.add_block(bad.clone()).unwrap_err();
+        assert!(err.to_string().contains("coinbase"), "{}", err);
+        assert_eq!(bc.best_header().unwrap(), genesis.get_hash());
+        assert!(bc.missing_blocks(10).unwrap().is_empty());
+
+        // Neither the block nor anything built on it is taken again
+        let err = bc.add_block(bad.clone()).unwrap_err();
+        assert!(err.to_string().contains("known to be invalid"), "{}", err);
+        let err = bc.add_header(child.get_header()).unwrap_err();
+        assert!(err.to_string().contains("known to be invalid"), "{}", err);
+        let grandchild = mine_on(&bc, &child, SLOW);
+        let err = bc.add_header(grandchild.get_header()).unwrap_err();
+        assert!(err.to_string().contains("invalid block"), "{}", err);
+
+        // An honest block at the same height still extends the chain
+        let good = mine_on(&bc, &genesis, SLOW);
+        bc.add_block(good.clone()).unwrap();
+        assert_eq!(bc.best_header().unwrap(), good.get_hash());
+        assert_eq!(bc.tip, good.get_hash());
+    }
+
+    #[test]
+    fn test_add_block_rejects_orphans() {
+        let mut bc = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &bc.params).unwrap();
+        let block = mine_on(&bc, &genesis, SLOW);
+
+        let err = bc.add_block(block.clone()).unwrap_err();
+        assert!(err.to_string().contains("unknown parent"), "{}", err);
+        assert!(bc.tip.is_empty());
+
+        bc.add_block(genesis).unwrap();
+        bc.add_block(block.clone()).unwrap();
+        assert_eq!(bc.tip, block.get_hash());
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut source = empty_chain();
+        let genesis = Block::new_genesis_block(coinbase(), &source.params).unwrap();
+        source.add_block(genesis.clone()).unwrap();
+        let mut blocks = vec![genesis];
+        for _ in 0..25 {
+            let next = mine_on(&source, blocks.last().unwrap(), SLOW);
+            source.add_block(next.clone()).unwrap();
+            blocks.push(next);
+        }
+
+        // Ten consecutive hashes from the tip, then doubling gaps down to genesis
+        let locator = source.locator(&source.tip);
+        let heights = [25, 24, 23, 22, 21, 20, 19, 18, 17, 16, 14, 10, 2, 0];
+        assert_eq!(locator, heights.map(|i| blocks[i].get_hash()).to_vec());
+        assert!(source.locator("unknown").is_empty());
+
+        // A peer on a stale fork gets the best chain from where it forked
+        let mut peer = empty_chain();
+        for block in &blocks[..=12] {
+            peer.add_block(block.clone()).unwrap();
+        }
+        let stale = mine_on(&peer, &blocks[12], FAST);
+        peer.add_block(stale).unwrap();
+        let missing = source.hashes_after(&peer.locator(&peer.tip), 10);
+        assert_eq!(missing, hashes(&blocks[13..23]));
+
+        // Batches continue from the last hash of the previous one
+        let rest = source.hashes_after(&[missing[9].clone()], 10);
+        assert_eq!(rest, hashes(&blocks[23..]));
+        assert!(source.hashes_after(&[source.tip.clone()], 10).is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+//! Multi-threaded proof-of-work search
+//!
+//! Worker threads split a template's 32-bit nonce space between them, each
+//! starting at its own index and stepping by the number of workers. Once the
+//! whole space is exhausted the coinbase extra nonce is rolled, which changes
+//! the merkle root and gives a fresh nonce space.
+//!
+//! A search belongs to an epoch. `Miner::cancel` starts a new one, and every
+//! search begun in an earlier epoch stops at its next check, so a node can
+//! abandon its template as soon as a peer moves the tip.
+
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::pow;
+use crate::Result;
+use failure::format_err;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hashes a worker tries between checks for cancellation
+const CHECK_INTERVAL: u64 = 1024;
+/// Time between hash rate reports of a running search
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// MiningStats counts the hashes a miner tried and the time it spent
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    /// HashRate returns hashes per second
+    pub fn hash_rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.hashes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Miner searches for proof-of-work on worker threads
+///
+/// Clones share their epoch and statistics, so any clone can cancel a
+/// search another one is running.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+    epoch: Arc<AtomicU64>,
+    stats: Arc<Mutex<MiningStats>>,
+}
+
+impl Default for Miner {
+    /// Default uses one worker per available core
+    fn default() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, usize::from))
+    }
+}
+
+impl Miner {
+    /// New creates a miner with `threads` workers, at least one
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.clamp(1, u32::MAX as usize),
+            epoch: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(Mutex::new(MiningStats::default())),
+        }
+    }
+
+    /// Epoch returns the current epoch; take it before building a template
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Cancel stops every search begun in the current epoch
+    pub fn cancel(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// CancelEpoch cancels searches begun in `epoch`, unless it is already over
+    pub fn cancel_epoch(&self, epoch: u64) {
+        let _ = self
+            .epoch
+            .compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Stats returns the totals of every search so far
+    pub fn stats(&self) -> MiningStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Mine searches for a nonce meeting the template's target
+    ///
+    /// Returns `None` if the miner was cancelled after `epoch` was taken.
+    pub fn mine(&self, mut block: Block, epoch: u64) -> Result<Option<Block>> {
+        let height = block.get_height();
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let result = loop {
+            if self.is_cancelled(epoch) {
+                break None;
+            }
+            match self.search(&block, epoch, &hashes, start)? {
+                Some(nonce) => {
+                    block.seal(nonce)?;
+                    break Some(block);
+                }
+                None if self.is_cancelled(epoch) => break None,
+                None => {
+                    debug!("Nonces exhausted at height {}", height);
+                    block.roll_extra_nonce()?;
+                }
+            }
+        };
+
+        let job = MiningStats {
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        };
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.hashes += job.hashes;
+            stats.elapsed += job.elapsed;
+        }
+        match &result {
+            Some(block) => info!(
+                "Mined block {} at height {}: {} hashes at {:.0} H/s",
+                block.get_hash(),
+                height,
+                job.hashes,
+                job.hash_rate()
+            ),
+            None => info!(
+                "Mining at height {} cancelled after {} hashes",
+                height, job.hashes
+            ),
+        }
+        Ok(result)
+    }
+
+    /// Search runs the workers over one template's nonce space
+    fn search(
+        &self,
+        block: &Block,
+        epoch: u64,
+        hashes: &AtomicU64,
+        start: Instant,
+    ) -> Result<Option<u32>> {
+        let found = Mutex::new(None);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..self.threads as u32)
+                .map(|first| {
+                    let worker = Worker {
+                        miner: self,
+                        header: block.get_header(),
+                        epoch,
+                        found: &found,
+                        done: &done,
+                        hashes,
+                    };
+                    s.spawn(move || worker.run(first, start))
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| format_err!("mining thread panicked"))?
+            })
+        })?;
+        Ok(found.into_inner().unwrap())
+    }
+
+    fn is_cancelled(&self, epoch: u64) -> bool {
+        self.epoch() != epoch
+    }
+}
+
+/// Worker is one thread's share of a search
+struct Worker<'a> {
+    miner: &'a Miner,
+    header: &'a BlockHeader,
+    epoch: u64,
+    found: &'a Mutex<Option<u32>>,
+    done: &'a AtomicBool,
+    hashes: &'a AtomicU64,
+}
+
+impl Worker<'_> {
+    /// Run tries every nonce from `first` in steps of the number of workers
+    fn run(&self, first: u32, start: Instant) -> Result<()> {
+        let step = self.miner.threads as u32;
+        let bits = self.header.get_bits();
+        let mut last_report = start;
+        let mut batch = 0;
+        let mut nonce = Some(first);
+
+        while let Some(n) = nonce {
+            if pow::meets(&self.header.hash_with_nonce(n)?, bits) {
+                self.found.lock().unwrap().get_or_insert(n);
+                self.done.store(true, Ordering::SeqCst);
+                break;
+            }
+
+            batch += 1;
+            if batch == CHECK_INTERVAL {
+                self.hashes.fetch_add(batch, Ordering::Relaxed);
+                batch = 0;
+                if self.done.load(Ordering::SeqCst) || self.miner.is_cancelled(self.epoch) {
+                    break;
+                }
+                // The first worker reports for all of them
+                if first == 0 && last_report.elapsed() >= REPORT_INTERVAL {
+                    last_report = Instant::now();
+                    let progress = MiningStats {
+                        hashes: self.hashes.load(Ordering::Relaxed),
+                        elapsed: start.elapsed(),
+                    };
+                    info!(
+                        "Mining at height {}: {:.0} H/s",
+                        self.header.get_height(),
+                        progress.hash_rate()
+                    );
+                }
+            }
+            nonce = n.checked_add(step);
+        }
+
+        self.hashes.fetch_add(batch, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::transaction::Transaction;
+    use crate::crypto::wallets::Wallet;
+
+    fn template(bits: u32) -> Block {
+        let address = Wallet::default().get_address();
+        let cbtx = Transaction::new_coinbase(address, String::new()).unwrap();
+        Block::new_template(vec![cbtx], String::new(), 0, 0, bits).unwrap()
+    }
+
+    #[test]
+    fn test_workers_find_a_valid_nonce() {
+        let miner = Miner::new(4);
+        let block = miner
+            .mine(template(0x2000ffff), miner.epoch())
+            .unwrap()
+            .unwrap();
+        assert!(block.check_header(None, 0x2000ffff).is_ok());
+
+        let stats = miner.stats();
+        assert!(stats.hashes > 0);
+        assert!(stats.hash_rate() >= 0.0);
+    }
+
+    #[test]
+    fn test_cancel_stops_mining() {
+        let miner = Miner::new(2);
+        let epoch = miner.epoch();
+        miner.cancel();
+        assert!(miner.mine(template(0x2000ffff), epoch).unwrap().is_none());
+
+        // A target no hash meets keeps the workers busy until cancelled
+        let epoch = miner.epoch();
+        let worker = miner.clone();
+        let search = thread::spawn(move || worker.mine(template(0x1000ffff), epoch));
+        thread::sleep(Duration::from_millis(50));
+        miner.cancel();
+        assert!(search.join().unwrap().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_roll_extra_nonce_changes_the_header() {
+        let mut block = template(0x2000ffff);
+        let header = block.get_header().clone();
+        block.roll_extra_nonce().unwrap();
+        assert_ne!(block.get_header().hash().unwrap(), header.hash().unwrap());
+
+        let coinbase = &block.get_transaction()[0];
+        assert_eq!(coinbase.id, coinbase.unsigned_hash().unwrap());
+    }
+}
@@ -0,0 +1,170 @@
+//! Proof-of-work targets and retargeting
+//!
+//! A block's hash, read as a 256-bit big-endian number, must not exceed the
+//! target its `bits` encode. `bits` is the compact form Bitcoin uses for
+//! `nBits`: the top byte is the length of the target in bytes and the low
+//! three bytes are its most significant digits.
+//!
+//! The target is retargeted at the first block of every window of
+//! `retarget_window` blocks, scaling the previous target by how long the
+//! previous window took against how long it should have taken.
+
+use num_bigint::BigUint;
+
+/// ConsensusParams holds the proof-of-work and timestamp rules of a chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsensusParams {
+    /// Easiest target allowed, in compact form; the genesis block uses it
+    pub pow_limit: u32,
+    /// Number of blocks between retargets
+    pub retarget_window: i32,
+    /// Desired time between blocks, in milliseconds
+    pub block_time: u128,
+    /// Largest factor the target may move by in one retarget
+    pub max_retarget_factor: u32,
+    /// Number of blocks whose median timestamp a new block must exceed
+    pub median_time_span: usize,
+    /// How far ahead of our clock a block's timestamp may be, in milliseconds
+    pub max_future_drift: u128,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            // About 65536 hashes per block
+            pow_limit: 0x1f00ffff,
+            retarget_window: 10,
+            block_time: 10_000,
+            max_retarget_factor: 4,
+            median_time_span: 11,
+            max_future_drift: 2 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl ConsensusParams {
+    /// Retarget returns the bits of the first block of a window
+    ///
+    /// `bits` are those of the last block of the previous window and
+    /// `timespan` the time from that window's first block to its last, which
+    /// covers `retarget_window - 1` block intervals. Callers measure it
+    /// between median times past, so one skewed timestamp cannot swing it.
+    pub fn retarget(&self, bits: u32, timespan: u128) -> u32 {
+        let expected = self.block_time * (self.retarget_window.max(2) - 1) as u128;
+        let factor = u128::from(self.max_retarget_factor.max(1));
+        let timespan = timespan.clamp(expected / factor, expected * factor);
+
+        let target = decode(bits) * BigUint::from(timespan) / BigUint::from(expected);
+        encode(&target.min(decode(self.pow_limit)))
+    }
+}
+
+/// Decode expands compact bits into a target
+///
+/// Bits with the sign flag set encode a negative number and give a zero
+/// target, which no hash meets.
+pub fn decode(bits: u32) -> BigUint {
+    let size = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 {
+        return BigUint::default();
+    }
+    if size <= 3 {
+        BigUint::from(mantissa >> (8 * (3 - size)))
+    } else {
+        BigUint::from(mantissa) << (8 * (size - 3))
+    }
+}
+
+/// Encode packs a target into compact bits, dropping all but its top three bytes
+pub fn encode(target: &BigUint) -> u32 {
+    let bytes = target.to_bytes_be();
+    let mut size = if *target == BigUint::default() {
+        0
+    } else {
+        bytes.len() as u32
+    };
+    let mut mantissa = bytes
+        .iter()
+        .take(3)
+        .fold(0u32, |m, byte| (m << 8) | u32::from(*byte));
+    if size < 3 {
+        mantissa <<= 8 * (3 - size);
+    }
+    // The top mantissa bit is a sign flag; move a set one into the next byte
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    (size << 24) | mantissa
+}
+
+/// Meets reports whether a hex block hash is at or below the target of `bits`
+pub fn meets(hash: &str, bits: u32) -> bool {
+    match hex::decode(hash) {
+        Ok(bytes) => BigUint::from_bytes_be(&bytes) <= decode(bits),
+        Err(_) => false,
+    }
+}
+
+/// Work is the expected number of hashes needed to meet the target of `bits`
+pub fn work(bits: u32) -> u128 {
+    let work = (BigUint::from(1u8) << 256u32) / (decode(bits) + BigUint::from(1u8));
+    u128::try_from(&work).unwrap_or(u128::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compact_round_trip() {
+        // Bitcoin's genesis bits and a few encodings from its test suite
+        assert_eq!(decode(0x1d00ffff), BigUint::from(0xffffu32) << (8 * 26));
+        assert_eq!(encode(&decode(0x1d00ffff)), 0x1d00ffff);
+        assert_eq!(decode(0x01123456), BigUint::from(0x12u32));
+        assert_eq!(encode(&BigUint::from(0x12u32)), 0x01120000);
+        assert_eq!(encode(&BigUint::from(0x80u32)), 0x02008000);
+        assert_eq!(decode(0x04923456), BigUint::default());
+        assert_eq!(encode(&BigUint::default()), 0);
+
+        let params = ConsensusParams::default();
+        assert_eq!(encode(&decode(params.pow_limit)), params.pow_limit);
+        assert_eq!(work(params.pow_limit), 65537);
+    }
+
+    #[test]
+    fn test_meets() {
+        let bits = ConsensusParams::default().pow_limit;
+        assert!(meets(&format!("0000ffff{}", "0".repeat(56)), bits));
+        assert!(!meets(&format!("0000ffff{}1", "0".repeat(55)), bits));
+        assert!(!meets(&format!("0001{}", "0".repeat(60)), bits));
+        assert!(!meets("not hex", bits));
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = ConsensusParams::default();
+        let bits = 0x1e00ffff;
+        let expected = params.block_time * (params.retarget_window as u128 - 1);
+
+        assert_eq!(params.retarget(bits, expected), bits);
+        // Twice as fast halves the target, which doubles the work
+        let faster = params.retarget(bits, expected / 2);
+        assert_eq!(decode(faster), decode(bits) / BigUint::from(2u8));
+
+        // Moves are clamped to the factor, even for absurd timestamps
+        let clamped = decode(bits) / BigUint::from(4u8);
+        assert_eq!(decode(params.retarget(bits, 0)), clamped);
+        assert_eq!(
+            decode(params.retarget(bits, expected * 100)),
+            decode(bits) * BigUint::from(4u8)
+        );
+
+        // Never easier than the limit
+        assert_eq!(
+            params.retarget(params.pow_limit, expected * 4),
+            params.pow_limit
+        );
+    }
+}
@@ -1,17 +1,95 @@
 use crate::blockchain::block::*;
 use crate::blockchain::blockchain::*;
+use crate::blockchain::miner::Miner;
+use crate::blockchain::validation::UtxoView;
 use crate::crypto::transaction::*;
+use crate::crypto::wallets::hash_pub_key;
 use crate::Result;
 use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::Serialize;
 use sled;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
     pub blockchain: Blockchain,
+    pub db: sled::Db,
+}
+
+/// HistoryEntry is an output paid to an address and, if spent, what spent it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: String,
+    pub vout: i32,
+    pub value: i32,
+    /// Height of the block the output was created in
+    pub height: i32,
+    pub spent: Option<Spend>,
+}
+
+/// Spend is the input that consumed an output
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Spend {
+    pub txid: String,
+    pub height: i32,
 }
 
 impl UTXOSet {
+    /// New opens the UTXO set of a blockchain kept in data/utxos
+    pub fn new(blockchain: Blockchain) -> Result<UTXOSet> {
+        UTXOSet::open(blockchain, sled::open("data/utxos")?)
+    }
+
+    /// Open uses the UTXO set in `db`, rebuilding it from the blockchain if
+    /// it was written in an older layout
+    pub fn open(blockchain: Blockchain, db: sled::Db) -> Result<UTXOSet> {
+        let utxo_set = UTXOSet { blockchain, db };
+        let version = utxo_set.db.open_tree(META_TREE)?.get(VERSION_KEY)?;
+        if version.as_deref() != Some(&[FORMAT_VERSION][..]) {
+            info!("UTXO set has an old layout, reindexing");
+            utxo_set.reindex()?;
+        }
+        Ok(utxo_set)
+    }
+
+    /// AddBlock checks a block against the unspent outputs it spends, stores
+    /// it and moves the set to the new best chain
+    ///
+    /// Returns how the best chain changed.
+    pub fn add_block(&mut self, block: Block) -> Result<TipChange> {
+        let db = &self.db;
+        let change = self
+            .blockchain
+            .add_block_with(block, |bc, hash| view_at(bc, db, hash))?;
+        self.update_tip(&change)?;
+        Ok(change)
+    }
+
+    /// BlockTemplate returns an unmined block on the tip holding `transactions`
+    pub fn block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let view = self.view_at(&self.blockchain.tip)?;
+        self.blockchain.block_template(transactions, view)
+    }
+
+    /// MineBlock mines a new block with the provided transactions on every core
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        info!("mine a new block");
+
+        let template = self.block_template(transactions)?;
+        let miner = Miner::default();
+        let newblock = miner
+            .mine(template, miner.epoch())?
+            .ok_or_else(|| format_err!("mining was cancelled"))?;
+        self.add_block(newblock.clone())?;
+        Ok(newblock)
+    }
+
+    /// ViewAt returns the unspent outputs as of the block `hash`
+    pub fn view_at(&self, hash: &str) -> Result<UtxoView> {
+        view_at(&self.blockchain, &self.db, hash)
+    }
+
     /// FindUnspentTransactions returns a list of transactions containing unspent outputs
     pub fn find_spendable_outputs(
         &self,
@@ -21,21 +99,15 @@ impl UTXOSet {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
 
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
+        for kv in self.db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
             let outs: TXOutputs = deserialize(&v)?;
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+            for (vout, out) in &outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
+                    accumulated += out.value;
+                    unspent_outputs.entry(txid.clone()).or_default().push(*vout);
                 }
             }
         }
@@ -44,19 +116,15 @@ impl UTXOSet {
     }
 
     /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
-
-        for kv in db.iter() {
+    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
+        for kv in self.db.iter() {
             let (_, v) = kv?;
             let outs: TXOutputs = deserialize(&v)?;
 
-            for out in outs.outputs {
+            for out in outs.outputs.into_values() {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.push(out)
                 }
             }
         }
@@ -64,11 +132,61 @@ impl UTXOSet {
         Ok(utxos)
     }
 
+    /// History lists every output paid to a public key hash, oldest first
+    ///
+    /// Spends are matched by the hash of the key each input reveals, so the
+    /// history of a watch-only address needs no secret key.
+    pub fn history(&self, pub_key_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+        let mut spends: HashMap<(String, i32), Spend> = HashMap::new();
+        let mut history = Vec::new();
+
+        // Walking from the tip and taking each block's spends before its
+        // outputs, an output's spend is always seen before it, even when it
+        // is spent in the block that created it
+        for block in self.blockchain.iter() {
+            for tx in block.get_transaction() {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for vin in &tx.vin {
+                    let mut hash = vin.pub_key.clone();
+                    hash_pub_key(&mut hash);
+                    if hash == pub_key_hash {
+                        spends.insert(
+                            (vin.txid.clone(), vin.vout),
+                            Spend {
+                                txid: tx.id.clone(),
+                                height: block.get_height(),
+                            },
+                        );
+                    }
+                }
+            }
+
+            for tx in block.get_transaction() {
+                for (vout, out) in tx.vout.iter().enumerate() {
+                    if out.is_locked_with_key(pub_key_hash) {
+                        history.push(HistoryEntry {
+                            txid: tx.id.clone(),
+                            vout: vout as i32,
+                            value: out.value,
+                            height: block.get_height(),
+                            spent: spends.remove(&(tx.id.clone(), vout as i32)),
+                        });
+                    }
+                }
+            }
+        }
+
+        // Stable, so outputs of one block keep their order
+        history.sort_by_key(|entry| entry.height);
+        Ok(history)
+    }
+
     /// CountTransactions returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
+        for kv in self.db.iter() {
             kv?;
             counter += 1;
         }
@@ -76,55 +194,513 @@ impl UTXOSet {
     }
 
     /// Reindex rebuilds the UTXO set
+    ///
+    /// Undo data is dropped with the old set, so blocks connected before a
+    /// reindex can only be disconnected by another reindex.
     pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("data/utxos").ok();
-        let db = sled::open("data/utxos")?;
+        self.db.clear()?;
+        self.db.open_tree(UNDO_TREE)?.clear()?;
 
         let utxos = self.blockchain.find_UTXO();
 
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+            self.db.insert(txid.as_bytes(), serialize(&outs)?)?;
+        }
+        let meta = self.db.open_tree(META_TREE)?;
+        meta.insert(TIP_KEY, self.blockchain.tip.as_bytes())?;
+        meta.insert(VERSION_KEY, &[FORMAT_VERSION])?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// UpdateTip follows the blockchain to a new best chain
+    ///
+    /// Disconnected blocks are rolled back with their undo data; the set is
+    /// only rebuilt when it is not at the block the change starts from or
+    /// undo data is missing.
+    pub fn update_tip(&self, change: &TipChange) -> Result<()> {
+        let start = match (change.disconnected.first(), change.connected.first()) {
+            (Some(block), _) => block.get_hash(),
+            (None, Some(block)) => block.get_prev_hash(),
+            (None, None) => return Ok(()),
+        };
+        if utxo_tip(&self.db)?.as_deref() != Some(start.as_str()) {
+            info!("UTXO set is not at {}, reindexing", start);
+            return self.reindex();
         }
 
+        for block in &change.disconnected {
+            if let Err(e) = disconnect_block(&self.db, block) {
+                warn!(
+                    "Cannot disconnect block {}, reindexing: {}",
+                    block.get_hash(),
+                    e
+                );
+                return self.reindex();
+            }
+        }
+        for block in &change.connected {
+            connect_block(&self.db, block)?;
+        }
         Ok(())
     }
 
     /// Update updates the UTXO set with transactions from the Block
     ///
-    /// The Block is considered to be the tip of a blockchain
+    /// The Block is considered to be the tip of a blockchain. The outputs it
+    /// spends are kept as undo data so it can be disconnected again.
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+        connect_block(&self.db, block)
+    }
 
+    /// Disconnect rolls the UTXO set back from the Block, its tip, to the Block's parent
+    pub fn disconnect(&self, block: &Block) -> Result<()> {
+        disconnect_block(&self.db, block)
+    }
+}
+
+/// Tree of undo data: per block hash, the outputs the block spent in spending order
+const UNDO_TREE: &str = "undo";
+/// Tree of bookkeeping kept apart from the outputs, which are keyed by txid
+const META_TREE: &str = "meta";
+/// Hash of the block the UTXO set was last moved to
+const TIP_KEY: &str = "TIP";
+/// Layout of the stored outputs; sets without it keep them as a list
+const VERSION_KEY: &str = "VERSION";
+/// Outputs are keyed by their index, so spent ones leave no gaps
+const FORMAT_VERSION: u8 = 2;
+
+type BlockUndo = Vec<(String, i32, TXOutput)>;
+
+fn utxo_tip(db: &sled::Db) -> Result<Option<String>> {
+    match db.open_tree(META_TREE)?.get(TIP_KEY)? {
+        Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+/// ViewAt builds the unspent outputs as of the block `hash` over the set in `db`
+///
+/// The set is rolled back in memory to where `hash` forks from its tip,
+/// using undo data, and forward along the branch to `hash`, so the work
+/// grows with the depth of the fork rather than the length of the chain.
+/// Without undo data the view is replayed from the blocks.
+fn view_at(bc: &Blockchain, db: &sled::Db, hash: &str) -> Result<UtxoView> {
+    let tip = utxo_tip(db)?.unwrap_or_default();
+    let (disconnect, connect) = match fork_path(bc, &tip, hash)? {
+        Some(path) => path,
+        None => {
+            warn!(
+                "UTXO set at {} is not on the chain, replaying {}",
+                tip, hash
+            );
+            return UtxoView::replay(bc, hash);
+        }
+    };
+
+    let mut view = UtxoView::over(db.clone());
+    let undo_tree = db.open_tree(UNDO_TREE)?;
+    for disconnected in &disconnect {
+        let block = bc.get_block(disconnected)?;
+        let undo: BlockUndo = match undo_tree.get(disconnected)? {
+            Some(data) => deserialize(&data)?,
+            None => {
+                warn!(
+                    "No undo data for block {}, replaying {}",
+                    disconnected, hash
+                );
+                return UtxoView::replay(bc, hash);
+            }
+        };
+        view.disconnect(&block, undo)?;
+    }
+    for connected in &connect {
+        let block = bc.get_block(connected)?;
+        view.fetch(block.get_transaction())?;
         for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
-                for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
+            view.apply(tx);
+        }
+    }
+    Ok(view)
+}
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
-                    }
+/// ForkPath returns the blocks to disconnect from `from`, newest first,
+/// and to connect on the way to `to`, oldest first
+///
+/// Returns None when either hash is not a known header.
+fn fork_path(bc: &Blockchain, from: &str, to: &str) -> Result<Option<(Vec<String>, Vec<String>)>> {
+    let height = |hash: &str| -> Result<Option<(i32, String)>> {
+        if hash.is_empty() {
+            return Ok(Some((-1, String::new())));
+        }
+        Ok(bc
+            .get_header(hash)?
+            .map(|header| (header.get_height(), header.get_prev_hash())))
+    };
+
+    let mut disconnect = Vec::new();
+    let mut connect = Vec::new();
+    let (mut old, mut new) = (from.to_string(), to.to_string());
+    while old != new {
+        let (old_height, old_prev) = match height(&old)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (new_height, new_prev) = match height(&new)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if old_height >= new_height {
+            disconnect.push(std::mem::replace(&mut old, old_prev));
+        } else {
+            connect.push(std::mem::replace(&mut new, new_prev));
+        }
+    }
+    connect.reverse();
+    Ok(Some((disconnect, connect)))
+}
+
+/// ConnectBlock spends the block's inputs and adds its outputs, recording undo data
+fn connect_block(db: &sled::Db, block: &Block) -> Result<()> {
+    let mut undo: BlockUndo = Vec::new();
+
+    for tx in block.get_transaction() {
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let data = db
+                    .get(&vin.txid)?
+                    .ok_or_else(|| format_err!("UTXO set has no outputs of {}", vin.txid))?;
+                let mut outs: TXOutputs = deserialize(&data)?;
+                let out = outs.outputs.remove(&vin.vout).ok_or_else(|| {
+                    format_err!("UTXO set has no output {}:{}", vin.txid, vin.vout)
+                })?;
+                undo.push((vin.txid.clone(), vin.vout, out));
+
+                if outs.outputs.is_empty() {
+                    db.remove(&vin.txid)?;
+                } else {
+                    db.insert(vin.txid.as_bytes(), serialize(&outs)?)?;
                 }
             }
+        }
+
+        let new_outputs = TXOutputs {
+            outputs: (0..).zip(tx.vout.iter().cloned()).collect(),
+        };
+        db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+    }
+
+    db.open_tree(UNDO_TREE)?
+        .insert(block.get_hash(), serialize(&undo)?)?;
+    db.open_tree(META_TREE)?
+        .insert(TIP_KEY, block.get_hash().as_bytes())?;
+    Ok(())
+}
+
+/// DisconnectBlock removes the block's outputs and restores what it spent
+///
+/// Transactions are undone last to first, so an output created and spent
+/// within the block is restored before its transaction is removed.
+fn disconnect_block(db: &sled::Db, block: &Block) -> Result<()> {
+    let hash = block.get_hash();
+    let tip = utxo_tip(db)?;
+    if tip.as_deref() != Some(hash.as_str()) {
+        return Err(format_err!(
+            "UTXO set is at {} not {}",
+            tip.unwrap_or_default(),
+            hash
+        ));
+    }
+    let undo_tree = db.open_tree(UNDO_TREE)?;
+    let data = undo_tree
+        .get(&hash)?
+        .ok_or_else(|| format_err!("No undo data for block {}", hash))?;
+    let mut undo: BlockUndo = deserialize(&data)?;
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
+    for tx in block.get_transaction().iter().rev() {
+        db.remove(&tx.id)?;
+        if tx.is_coinbase() {
+            continue;
+        }
+        for _ in &tx.vin {
+            let (txid, vout, out) = undo
+                .pop()
+                .ok_or_else(|| format_err!("Undo data of block {} is incomplete", hash))?;
+            let mut outs = match db.get(&txid)? {
+                Some(data) => deserialize(&data)?,
+                None => TXOutputs {
+                    outputs: BTreeMap::new(),
+                },
             };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            outs.outputs.insert(vout, out);
+            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+        }
+    }
+
+    undo_tree.remove(&hash)?;
+    db.open_tree(META_TREE)?
+        .insert(TIP_KEY, block.get_prev_hash().as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::pow::ConsensusParams;
+    use crate::crypto::transaction::TXInput;
+    use crate::crypto::types::EncryptionType;
+    use crate::crypto::wallets::Wallet;
+
+    fn output(value: i32) -> TXOutput {
+        TXOutput {
+            value,
+            pub_key_hash: vec![value as u8; 20],
+        }
+    }
+
+    /// Builds an unsigned transaction; connecting blocks trusts validation
+    fn tx(inputs: &[(&str, i32)], values: &[i32]) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: inputs
+                .iter()
+                .map(|(txid, vout)| TXInput {
+                    txid: txid.to_string(),
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                    encryption: EncryptionType::default(),
+                })
+                .collect(),
+            vout: values.iter().map(|value| output(*value)).collect(),
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    fn coinbase(tag: i32) -> Transaction {
+        let mut cb = tx(&[("", -1)], &[10]);
+        cb.vin[0].pub_key = vec![tag as u8];
+        cb.id = cb.hash().unwrap();
+        cb
+    }
+
+    fn block(prev: &str, height: i32, txs: Vec<Transaction>) -> Block {
+        // About one hash in 256 meets the target
+        Block::new_block(txs, prev.to_string(), height, 0, 0x2000ffff).unwrap()
+    }
+
+    /// Every unspent output as (txid, vout, value)
+    fn snapshot(db: &sled::Db) -> BTreeMap<(String, i32), i32> {
+        let mut utxos = BTreeMap::new();
+        for kv in db.iter() {
+            let (k, v) = kv.unwrap();
+            let outs: TXOutputs = deserialize(&v).unwrap();
+            for (vout, out) in outs.outputs {
+                utxos.insert((String::from_utf8(k.to_vec()).unwrap(), vout), out.value);
+            }
+        }
+        utxos
+    }
+
+    #[test]
+    fn test_disconnect_restores_spent_outputs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let cb1 = coinbase(1);
+        let b1 = block("", 0, vec![cb1.clone()]);
+        connect_block(&db, &b1).unwrap();
+        let after_b1 = snapshot(&db);
+
+        // Spends the first coinbase, then the change of that spend in the same block
+        let spend = tx(&[(&cb1.id, 0)], &[4, 6]);
+        let respend = tx(&[(&spend.id, 1)], &[6]);
+        let b2 = block(
+            &b1.get_hash(),
+            1,
+            vec![coinbase(2), spend.clone(), respend.clone()],
+        );
+        connect_block(&db, &b2).unwrap();
+        let after_b2 = snapshot(&db);
+        assert!(!after_b2.contains_key(&(cb1.id.clone(), 0)));
+        assert_eq!(after_b2[&(spend.id.clone(), 0)], 4);
+        assert!(!after_b2.contains_key(&(spend.id.clone(), 1)));
+        assert_eq!(after_b2[&(respend.id.clone(), 0)], 6);
+        assert_eq!(utxo_tip(&db).unwrap(), Some(b2.get_hash()));
+
+        // Only the tip can be disconnected
+        assert!(disconnect_block(&db, &b1).is_err());
+        disconnect_block(&db, &b2).unwrap();
+        assert_eq!(snapshot(&db), after_b1);
+        assert_eq!(utxo_tip(&db).unwrap(), Some(b1.get_hash()));
+        assert!(disconnect_block(&db, &b2).is_err());
+
+        // Undo data is rewritten when a block is connected again
+        connect_block(&db, &b2).unwrap();
+        assert_eq!(snapshot(&db), after_b2);
+        disconnect_block(&db, &b2).unwrap();
+        disconnect_block(&db, &b1).unwrap();
+        assert!(snapshot(&db).is_empty());
+    }
+
+    #[test]
+    fn test_history_sees_spends_within_a_block() {
+        let key = vec![7; 33];
+        let mut owner = key.clone();
+        hash_pub_key(&mut owner);
+        let pay = |mut tx: Transaction| {
+            for out in &mut tx.vout {
+                out.pub_key_hash = owner.clone();
             }
+            for vin in &mut tx.vin {
+                vin.pub_key = key.clone();
+            }
+            tx.id = tx.hash().unwrap();
+            tx
+        };
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+        let mut cb = pay(coinbase(1));
+        cb.vin[0].pub_key = vec![1];
+        cb.id = cb.hash().unwrap();
+        let spend = pay(tx(&[(&cb.id, 0)], &[4, 6]));
+        let respend = pay(tx(&[(&spend.id, 1)], &[6]));
+        let b1 = block("", 0, vec![cb.clone()]);
+        let b2 = block(&b1.get_hash(), 1, vec![coinbase(2), spend.clone(), respend]);
+
+        // History reads the stored blocks only
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for b in [&b1, &b2] {
+            db.insert(b.get_hash(), serialize(b).unwrap()).unwrap();
         }
-        Ok(())
+        let utxo_set = UTXOSet {
+            blockchain: Blockchain {
+                tip: b2.get_hash(),
+                db,
+                params: Default::default(),
+            },
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        };
+
+        let history = utxo_set.history(&owner).unwrap();
+        let spent: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.txid.as_str(), entry.vout, entry.spent.is_some()))
+            .collect();
+        assert_eq!(spent[0], (cb.id.as_str(), 0, true));
+        assert_eq!(spent[1], (spend.id.as_str(), 0, false));
+        assert_eq!(spent[2], (spend.id.as_str(), 1, true));
+    }
+
+    #[test]
+    fn test_view_at_follows_side_branches() {
+        let w = Wallet::default();
+        let mut utxo_set = UTXOSet {
+            blockchain: Blockchain {
+                tip: String::new(),
+                db: sled::Config::new().temporary(true).open().unwrap(),
+                params: ConsensusParams {
+                    pow_limit: 0x2000ffff,
+                    ..ConsensusParams::default()
+                },
+            },
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        };
+        let mine_on = |utxo_set: &UTXOSet, parent: &Block, txs: Vec<Transaction>| {
+            let mut txs = txs;
+            let cb = Transaction::new_coinbase(w.get_address(), String::new()).unwrap();
+            txs.insert(0, cb);
+            let bits = utxo_set
+                .blockchain
+                .next_bits(Some(parent.get_header()))
+                .unwrap();
+            let timestamp = parent.get_timestamp() + 1;
+            Block::new_block(
+                txs,
+                parent.get_hash(),
+                parent.get_height() + 1,
+                timestamp,
+                bits,
+            )
+            .unwrap()
+        };
+
+        let cb = Transaction::new_coinbase(w.get_address(), String::new()).unwrap();
+        let genesis = Block::new_genesis_block(cb.clone(), &utxo_set.blockchain.params).unwrap();
+        utxo_set.add_block(genesis.clone()).unwrap();
+
+        let mut pay = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: cb.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: w.public_key.clone(),
+                encryption: w.encryption,
+            }],
+            vout: vec![TXOutput::new(10, w.get_address()).unwrap()],
+        };
+        pay.id = pay.hash().unwrap();
+        let prev_TXs = HashMap::from([(cb.id.clone(), cb.clone())]);
+        pay.sign(&w.secret_key, prev_TXs, w.crypto_provider().as_ref())
+            .unwrap();
+
+        let a1 = mine_on(&utxo_set, &genesis, vec![pay.clone()]);
+        utxo_set.add_block(a1.clone()).unwrap();
+        let b1 = mine_on(&utxo_set, &genesis, Vec::new());
+        assert!(utxo_set.add_block(b1.clone()).unwrap().is_empty());
+        assert_eq!(utxo_tip(&utxo_set.db).unwrap(), Some(a1.get_hash()));
+
+        // The side branch is seen without the spend the tip made
+        let mut side = utxo_set.view_at(&b1.get_hash()).unwrap();
+        side.fetch([&pay, &a1.get_transaction()[0], &b1.get_transaction()[0]])
+            .unwrap();
+        assert!(side.get(&cb.id, 0).is_some());
+        assert!(!side.contains_transaction(&pay.id));
+        assert!(!side.contains_transaction(&a1.get_transaction()[0].id));
+        assert!(side.contains_transaction(&b1.get_transaction()[0].id));
+
+        // So the same spend is valid on it, and taking it over follows the branch
+        let b2 = mine_on(&utxo_set, &b1, vec![pay.clone()]);
+        let change = utxo_set.add_block(b2.clone()).unwrap();
+        assert_eq!(change.disconnected.len(), 1);
+        assert_eq!(utxo_tip(&utxo_set.db).unwrap(), Some(b2.get_hash()));
+        let mut tip = utxo_set.view_at(&b2.get_hash()).unwrap();
+        tip.fetch([&pay]).unwrap();
+        assert!(tip.get(&cb.id, 0).is_none());
+        assert!(tip.contains_transaction(&pay.id));
+    }
+
+    #[test]
+    fn test_open_rebuilds_old_layout() {
+        let cb = coinbase(1);
+        let b1 = block("", 0, vec![cb.clone()]);
+        let chain = sled::Config::new().temporary(true).open().unwrap();
+        chain
+            .insert(b1.get_hash(), serialize(&b1).unwrap())
+            .unwrap();
+
+        // Outputs used to be stored as a list
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(cb.id.as_bytes(), serialize(&cb.vout).unwrap())
+            .unwrap();
+        let blockchain = Blockchain {
+            tip: b1.get_hash(),
+            db: chain,
+            params: Default::default(),
+        };
+        let utxo_set = UTXOSet::open(blockchain, db).unwrap();
+        assert_eq!(snapshot(&utxo_set.db)[&(cb.id.clone(), 0)], 10);
+        assert_eq!(utxo_tip(&utxo_set.db).unwrap(), Some(b1.get_hash()));
+    }
+
+    #[test]
+    fn test_connect_rejects_missing_outputs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cb = coinbase(1);
+        connect_block(&db, &block("", 0, vec![cb.clone()])).unwrap();
+
+        let b = block("", 1, vec![coinbase(2), tx(&[(&cb.id, 1)], &[1])]);
+        assert!(connect_block(&db, &b).is_err());
     }
 }
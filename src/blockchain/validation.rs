@@ -0,0 +1,656 @@
+//! Consensus rules for transactions and blocks
+//!
+//! Rules are checked against a [`UtxoView`], the unspent outputs left by one
+//! block and its ancestors, so a block is judged by the chain it extends
+//! rather than by whatever the node's tip happens to be.
+
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::blockchain::Blockchain;
+use crate::blockchain::pow::ConsensusParams;
+use crate::crypto::transaction::{TXOutput, TXOutputs, Transaction, SUBSIDY};
+use crate::crypto::wallets::hash_pub_key;
+use crate::Result;
+use bincode::deserialize;
+use failure::format_err;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+/// ValidationError describes the consensus rule a transaction or block breaks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    NoInputs,
+    NoOutputs,
+    NegativeOutput { vout: usize, value: i32 },
+    IdMismatch,
+    DuplicateTransaction(String),
+    DuplicateInput { txid: String, vout: i32 },
+    MissingOutput { txid: String, vout: i32 },
+    KeyMismatch { txid: String, vout: i32 },
+    InsufficientInputs { inputs: i64, outputs: i64 },
+    InvalidSignature,
+    MalformedSignature(String),
+    NoCoinbase,
+    CoinbaseNotFirst,
+    MultipleCoinbases,
+    DoubleSpend { txid: String, vout: i32 },
+    CoinbaseTooLarge { allowed: i64, found: i64 },
+    UnknownParent(String),
+    InvalidAncestor(String),
+    KnownInvalid,
+    HashMismatch,
+    MerkleMismatch,
+    InsufficientWork,
+    BadHeight { expected: i32, found: i32 },
+    BadBits { expected: u32, found: u32 },
+    TimestampTooOld { median: u128, found: u128 },
+    TimestampTooNew { limit: u128, found: u128 },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::NoInputs => write!(f, "transaction has no inputs"),
+            ValidationError::NoOutputs => write!(f, "transaction has no outputs"),
+            ValidationError::NegativeOutput { vout, value } => {
+                write!(f, "output {} has negative value {}", vout, value)
+            }
+            ValidationError::IdMismatch => write!(f, "transaction id does not match its contents"),
+            ValidationError::DuplicateTransaction(id) => {
+                write!(f, "transaction {} already has unspent outputs", id)
+            }
+            ValidationError::DuplicateInput { txid, vout } => {
+                write!(
+                    f,
+                    "output {}:{} is spent twice by one transaction",
+                    txid, vout
+                )
+            }
+            ValidationError::MissingOutput { txid, vout } => {
+                write!(
+                    f,
+                    "output {}:{} does not exist or is already spent",
+                    txid, vout
+                )
+            }
+            ValidationError::KeyMismatch { txid, vout } => {
+                write!(f, "input key does not own output {}:{}", txid, vout)
+            }
+            ValidationError::InsufficientInputs { inputs, outputs } => {
+                write!(f, "outputs of {} exceed inputs of {}", outputs, inputs)
+            }
+            ValidationError::InvalidSignature => write!(f, "invalid signature"),
+            ValidationError::MalformedSignature(e) => write!(f, "malformed signature: {}", e),
+            ValidationError::NoCoinbase => write!(f, "block has no coinbase"),
+            ValidationError::CoinbaseNotFirst => {
+                write!(f, "coinbase is not the first transaction of the block")
+            }
+            ValidationError::MultipleCoinbases => write!(f, "block has more than one coinbase"),
+            ValidationError::DoubleSpend { txid, vout } => {
+                write!(f, "output {}:{} is spent twice in one block", txid, vout)
+            }
+            ValidationError::CoinbaseTooLarge { allowed, found } => {
+                write!(
+                    f,
+                    "coinbase pays {} but at most {} is allowed",
+                    found, allowed
+                )
+            }
+            ValidationError::UnknownParent(hash) => write!(f, "unknown parent block {}", hash),
+            ValidationError::InvalidAncestor(hash) => {
+                write!(f, "builds on invalid block {}", hash)
+            }
+            ValidationError::KnownInvalid => write!(f, "block is known to be invalid"),
+            ValidationError::HashMismatch => write!(f, "block hash does not match its header"),
+            ValidationError::MerkleMismatch => {
+                write!(f, "merkle root does not match the block's transactions")
+            }
+            ValidationError::InsufficientWork => {
+                write!(f, "block hash does not meet its target")
+            }
+            ValidationError::BadHeight { expected, found } => {
+                write!(f, "block height is {} but should be {}", found, expected)
+            }
+            ValidationError::BadBits { expected, found } => {
+                write!(
+                    f,
+                    "block bits are {:#010x} but should be {:#010x}",
+                    found, expected
+                )
+            }
+            ValidationError::TimestampTooOld { median, found } => write!(
+                f,
+                "block timestamp {} is not after the median time past {}",
+                found, median
+            ),
+            ValidationError::TimestampTooNew { limit, found } => write!(
+                f,
+                "block timestamp {} is too far in the future, limit {}",
+                found, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// UtxoView is the set of unspent outputs as of one block
+///
+/// It reads through to a UTXO set and keeps every change made on top of it
+/// in memory, so checking a block or the mempool never writes to the set.
+/// Outputs of the underlying set are only seen once fetched.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoView {
+    base: Option<sled::Db>,
+    /// Unspent outputs by txid; an empty map is a transaction with none left
+    outputs: HashMap<String, BTreeMap<i32, TXOutput>>,
+}
+
+impl UtxoView {
+    /// Over starts a view of the UTXO set stored in `db`
+    pub fn over(db: sled::Db) -> UtxoView {
+        UtxoView {
+            base: Some(db),
+            outputs: HashMap::new(),
+        }
+    }
+
+    /// Replay builds the view left by the block `hash` and its ancestors
+    /// from the blocks themselves, for a chain without a UTXO set
+    ///
+    /// An empty hash gives the empty view a genesis block is checked against.
+    pub fn replay(bc: &Blockchain, hash: &str) -> Result<UtxoView> {
+        let mut blocks: Vec<Block> = bc.iter_from(hash).collect();
+        blocks.reverse();
+
+        let mut view = UtxoView::default();
+        for block in &blocks {
+            for tx in block.get_transaction() {
+                view.apply(tx);
+            }
+        }
+        Ok(view)
+    }
+
+    /// Fetch reads the outputs `txs` spend or create from the underlying set
+    pub fn fetch<'a>(&mut self, txs: impl IntoIterator<Item = &'a Transaction>) -> Result<()> {
+        for tx in txs {
+            self.fetch_outputs(&tx.id)?;
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    self.fetch_outputs(&vin.txid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get returns an unspent output
+    pub fn get(&self, txid: &str, vout: i32) -> Option<&TXOutput> {
+        self.outputs.get(txid)?.get(&vout)
+    }
+
+    /// ContainsTransaction reports whether a transaction still has unspent outputs
+    pub fn contains_transaction(&self, txid: &str) -> bool {
+        self.outputs.get(txid).is_some_and(|outs| !outs.is_empty())
+    }
+
+    /// Apply spends a transaction's inputs and adds its outputs
+    pub fn apply(&mut self, tx: &Transaction) {
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                if let Some(outs) = self.outputs.get_mut(&vin.txid) {
+                    outs.remove(&vin.vout);
+                }
+            }
+        }
+        self.outputs
+            .insert(tx.id.clone(), (0..).zip(tx.vout.iter().cloned()).collect());
+    }
+
+    /// Disconnect undoes a block applied to the view, restoring the outputs
+    /// it spent from `undo`, which lists them in spending order
+    pub fn disconnect(
+        &mut self,
+        block: &Block,
+        mut undo: Vec<(String, i32, TXOutput)>,
+    ) -> Result<()> {
+        for tx in block.get_transaction().iter().rev() {
+            self.outputs.insert(tx.id.clone(), BTreeMap::new());
+            if tx.is_coinbase() {
+                continue;
+            }
+            for _ in &tx.vin {
+                let (txid, vout, out) = undo.pop().ok_or_else(|| {
+                    format_err!("Undo data of block {} is incomplete", block.get_hash())
+                })?;
+                self.fetch_outputs(&txid)?;
+                self.outputs.entry(txid).or_default().insert(vout, out);
+            }
+        }
+        Ok(())
+    }
+
+    /// FetchOutputs copies the unspent outputs of `txid` into the view unless it already has them
+    fn fetch_outputs(&mut self, txid: &str) -> Result<()> {
+        if self.outputs.contains_key(txid) {
+            return Ok(());
+        }
+        let base = match &self.base {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+        let outs = match base.get(txid)? {
+            Some(data) => deserialize::<TXOutputs>(&data)?.outputs,
+            None => BTreeMap::new(),
+        };
+        self.outputs.insert(txid.to_string(), outs);
+        Ok(())
+    }
+}
+
+/// CheckTransaction checks a non-coinbase transaction against a view and returns its fee
+pub fn check_transaction(
+    tx: &Transaction,
+    view: &UtxoView,
+) -> std::result::Result<i64, ValidationError> {
+    if tx.vin.is_empty() {
+        return Err(ValidationError::NoInputs);
+    }
+    let value_out = check_outputs(tx)?;
+    if view.contains_transaction(&tx.id) {
+        return Err(ValidationError::DuplicateTransaction(tx.id.clone()));
+    }
+
+    let mut outpoints = HashSet::new();
+    let mut prev_outs = Vec::with_capacity(tx.vin.len());
+    let mut value_in = 0i64;
+    for vin in &tx.vin {
+        if !outpoints.insert((vin.txid.as_str(), vin.vout)) {
+            return Err(ValidationError::DuplicateInput {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+            });
+        }
+        let prev_out =
+            view.get(&vin.txid, vin.vout)
+                .ok_or_else(|| ValidationError::MissingOutput {
+                    txid: vin.txid.clone(),
+                    vout: vin.vout,
+                })?;
+
+        // The signature only proves the key; the key must also own the output
+        let mut pub_key_hash = vin.pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        if !prev_out.is_locked_with_key(&pub_key_hash) {
+            return Err(ValidationError::KeyMismatch {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+            });
+        }
+
+        value_in += i64::from(prev_out.value);
+        prev_outs.push(prev_out.clone());
+    }
+
+    if value_in < value_out {
+        return Err(ValidationError::InsufficientInputs {
+            inputs: value_in,
+            outputs: value_out,
+        });
+    }
+
+    match tx.verify_spent(&prev_outs) {
+        Ok(true) => Ok(value_in - value_out),
+        Ok(false) => Err(ValidationError::InvalidSignature),
+        Err(e) => Err(ValidationError::MalformedSignature(e.to_string())),
+    }
+}
+
+/// CheckBlockTransactions checks a block's transactions in order and applies them to the view
+///
+/// The coinbase comes first and may claim the subsidy plus the fees of the
+/// other transactions. Returns the fees the block collects.
+pub fn check_block_transactions(
+    txs: &[Transaction],
+    view: &mut UtxoView,
+) -> std::result::Result<i64, ValidationError> {
+    if !txs.first().is_some_and(Transaction::is_coinbase) {
+        return Err(if txs.iter().any(Transaction::is_coinbase) {
+            ValidationError::CoinbaseNotFirst
+        } else {
+            ValidationError::NoCoinbase
+        });
+    }
+
+    let mut reward = None;
+    let mut spent = HashSet::new();
+    let mut fees = 0i64;
+
+    for tx in txs {
+        if tx.is_coinbase() {
+            if reward.is_some() {
+                return Err(ValidationError::MultipleCoinbases);
+            }
+            reward = Some(check_outputs(tx)?);
+            if view.contains_transaction(&tx.id) {
+                return Err(ValidationError::DuplicateTransaction(tx.id.clone()));
+            }
+        } else {
+            for vin in &tx.vin {
+                if spent.contains(&(vin.txid.clone(), vin.vout)) {
+                    return Err(ValidationError::DoubleSpend {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                    });
+                }
+            }
+            fees += check_transaction(tx, view)?;
+            spent.extend(tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)));
+        }
+        view.apply(tx);
+    }
+
+    let reward = reward.ok_or(ValidationError::NoCoinbase)?;
+    let allowed = i64::from(SUBSIDY) + fees;
+    if reward > allowed {
+        return Err(ValidationError::CoinbaseTooLarge {
+            allowed,
+            found: reward,
+        });
+    }
+    Ok(fees)
+}
+
+/// CheckTimestamp checks a header timestamp against the median time past and our clock
+///
+/// `median_time_past` is `None` for a genesis header, which has no lower bound.
+pub fn check_timestamp(
+    header: &BlockHeader,
+    median_time_past: Option<u128>,
+    now: u128,
+    params: &ConsensusParams,
+) -> std::result::Result<(), ValidationError> {
+    let found = header.get_timestamp();
+    if let Some(median) = median_time_past {
+        if found <= median {
+            return Err(ValidationError::TimestampTooOld { median, found });
+        }
+    }
+    let limit = now.saturating_add(params.max_future_drift);
+    if found > limit {
+        return Err(ValidationError::TimestampTooNew { limit, found });
+    }
+    Ok(())
+}
+
+/// CheckOutputs checks the outputs and id of a transaction and returns their total value
+fn check_outputs(tx: &Transaction) -> std::result::Result<i64, ValidationError> {
+    if tx.vout.is_empty() {
+        return Err(ValidationError::NoOutputs);
+    }
+    let mut value_out = 0i64;
+    for (vout, out) in tx.vout.iter().enumerate() {
+        if out.value < 0 {
+            return Err(ValidationError::NegativeOutput {
+                vout,
+                value: out.value,
+            });
+        }
+        value_out += i64::from(out.value);
+    }
+
+    match tx.unsigned_hash() {
+        Ok(hash) if hash == tx.id => Ok(value_out),
+        _ => Err(ValidationError::IdMismatch),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::transaction::TXInput;
+    use crate::crypto::wallets::Wallet;
+
+    /// Builds a view holding one coinbase paying `w` and returns the coinbase
+    fn funded_view(w: &Wallet) -> (UtxoView, Transaction) {
+        let cb = Transaction::new_coinbase(w.get_address(), String::new()).unwrap();
+        let mut view = UtxoView::default();
+        view.apply(&cb);
+        (view, cb)
+    }
+
+    /// Builds and signs a transaction spending `inputs` with the key of `w`
+    fn spend(w: &Wallet, inputs: &[(&Transaction, i32)], values: &[i32]) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: inputs
+                .iter()
+                .map(|(prev, vout)| TXInput {
+                    txid: prev.id.clone(),
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: w.public_key.clone(),
+                    encryption: w.encryption,
+                })
+                .collect(),
+            vout: values
+                .iter()
+                .map(|value| TXOutput::new(*value, w.get_address()).unwrap())
+                .collect(),
+        };
+        tx.id = tx.hash().unwrap();
+        let prev_TXs = inputs
+            .iter()
+            .map(|(prev, _)| (prev.id.clone(), (*prev).clone()))
+            .collect();
+        tx.sign(&w.secret_key, prev_TXs, w.crypto_provider().as_ref())
+            .unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_valid_spend_pays_fee() {
+        let w = Wallet::default();
+        let (view, cb) = funded_view(&w);
+
+        let tx = spend(&w, &[(&cb, 0)], &[6, 3]);
+        assert_eq!(check_transaction(&tx, &view), Ok(1));
+
+        let tx = spend(&w, &[(&cb, 0)], &[10, 0]);
+        assert_eq!(check_transaction(&tx, &view), Ok(0));
+    }
+
+    #[test]
+    fn test_value_rules() {
+        let w = Wallet::default();
+        let (view, cb) = funded_view(&w);
+
+        let tx = spend(&w, &[(&cb, 0)], &[15, -5]);
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::NegativeOutput { vout: 1, value: -5 })
+        );
+
+        let tx = spend(&w, &[(&cb, 0)], &[8, 3]);
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::InsufficientInputs {
+                inputs: 10,
+                outputs: 11
+            })
+        );
+
+        // Summing in i32 would wrap around to a small total
+        let tx = spend(&w, &[(&cb, 0)], &[i32::MAX, i32::MAX, 4]);
+        assert!(matches!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::InsufficientInputs { .. })
+        ));
+
+        let tx = spend(&w, &[(&cb, 0)], &[]);
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::NoOutputs)
+        );
+
+        let tx = spend(&w, &[], &[1]);
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::NoInputs)
+        );
+    }
+
+    #[test]
+    fn test_outpoint_rules() {
+        let w = Wallet::default();
+        let (mut view, cb) = funded_view(&w);
+
+        let tx = spend(&w, &[(&cb, 0), (&cb, 0)], &[20]);
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::DuplicateInput {
+                txid: cb.id.clone(),
+                vout: 0
+            })
+        );
+
+        let tx = spend(&w, &[(&cb, 0)], &[10]);
+        let mut missing = tx.clone();
+        missing.vin[0].vout = 1;
+        missing.id = missing.unsigned_hash().unwrap();
+        assert!(matches!(
+            check_transaction(&missing, &view),
+            Err(ValidationError::MissingOutput { vout: 1, .. })
+        ));
+
+        // Once spent, the same outpoint cannot be spent again
+        assert_eq!(check_transaction(&tx, &view), Ok(0));
+        view.apply(&tx);
+        let again = spend(&w, &[(&cb, 0)], &[9]);
+        assert_eq!(
+            check_transaction(&again, &view),
+            Err(ValidationError::MissingOutput {
+                txid: cb.id,
+                vout: 0
+            })
+        );
+        assert_eq!(
+            check_transaction(&tx, &view),
+            Err(ValidationError::DuplicateTransaction(tx.id.clone()))
+        );
+    }
+
+    #[test]
+    fn test_ownership_and_signature_rules() {
+        let w = Wallet::default();
+        let thief = Wallet::default();
+        let (view, cb) = funded_view(&w);
+
+        // A signature that verifies, made with a key that does not own the output
+        let stolen = spend(&thief, &[(&cb, 0)], &[10]);
+        assert!(stolen.verify_spent(&[cb.vout[0].clone()]).unwrap());
+        assert_eq!(
+            check_transaction(&stolen, &view),
+            Err(ValidationError::KeyMismatch {
+                txid: cb.id.clone(),
+                vout: 0
+            })
+        );
+
+        let tx = spend(&w, &[(&cb, 0)], &[10]);
+        let mut bad_id = tx.clone();
+        bad_id.id = cb.id.clone();
+        assert_eq!(
+            check_transaction(&bad_id, &view),
+            Err(ValidationError::IdMismatch)
+        );
+
+        let mut bad_sig = tx.clone();
+        bad_sig.vin[0].signature[0] ^= 0xff;
+        assert_eq!(
+            check_transaction(&bad_sig, &view),
+            Err(ValidationError::InvalidSignature)
+        );
+
+        let mut truncated = tx.clone();
+        truncated.vin[0].signature.pop();
+        assert!(matches!(
+            check_transaction(&truncated, &view),
+            Err(ValidationError::MalformedSignature(_))
+        ));
+
+        // Outputs are covered by the signature as well as the id
+        let mut inflated = tx;
+        inflated.vout[0].value = 9;
+        inflated.id = inflated.unsigned_hash().unwrap();
+        assert_eq!(
+            check_transaction(&inflated, &view),
+            Err(ValidationError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_block_rules() {
+        let w = Wallet::default();
+        let (view, cb) = funded_view(&w);
+        let reward = Transaction::new_coinbase(w.get_address(), String::new()).unwrap();
+        let a = spend(&w, &[(&cb, 0)], &[10]);
+        let b = spend(&w, &[(&cb, 0)], &[9]);
+
+        assert_eq!(
+            check_block_transactions(std::slice::from_ref(&a), &mut view.clone()),
+            Err(ValidationError::NoCoinbase)
+        );
+        assert_eq!(
+            check_block_transactions(
+                &[reward.clone(), a.clone(), reward.clone()],
+                &mut view.clone()
+            ),
+            Err(ValidationError::MultipleCoinbases)
+        );
+        assert_eq!(
+            check_block_transactions(&[b.clone(), reward.clone()], &mut view.clone()),
+            Err(ValidationError::CoinbaseNotFirst)
+        );
+        assert_eq!(
+            check_block_transactions(&[reward.clone(), a.clone(), b.clone()], &mut view.clone()),
+            Err(ValidationError::DoubleSpend {
+                txid: cb.id.clone(),
+                vout: 0
+            })
+        );
+
+        let mut after = view.clone();
+        assert_eq!(
+            check_block_transactions(&[reward.clone(), b.clone()], &mut after),
+            Ok(1)
+        );
+        assert!(after.get(&cb.id, 0).is_none());
+        assert_eq!(after.get(&b.id, 0).unwrap().value, 9);
+        assert_eq!(after.get(&reward.id, 0).unwrap().value, 10);
+
+        let mut greedy = reward.clone();
+        greedy.vout[0].value = SUBSIDY + 2;
+        greedy.id = greedy.unsigned_hash().unwrap();
+        assert_eq!(
+            check_block_transactions(&[greedy.clone(), b.clone()], &mut view.clone()),
+            Err(ValidationError::CoinbaseTooLarge {
+                allowed: 11,
+                found: 12
+            })
+        );
+        greedy.vout[0].value = SUBSIDY + 1;
+        greedy.id = greedy.unsigned_hash().unwrap();
+        assert_eq!(
+            check_block_transactions(&[greedy, b.clone()], &mut view.clone()),
+            Ok(1)
+        );
+
+        // Outputs created earlier in a block may be spent later in it
+        let c = spend(&w, &[(&b, 0)], &[9]);
+        assert_eq!(
+            check_block_transactions(&[reward, b, c], &mut view.clone()),
+            Ok(1)
+        );
+    }
+}
@@ -7,5 +7,8 @@ pub fn cmd_list_address() -> Result<(), Box<dyn std::error::Error>> {
     for ad in addresses {
         println!("{}", ad);
     }
+    for ad in ws.get_watch_only_addresses() {
+        println!("{} (watch-only)", ad);
+    }
     Ok(())
 }
@@ -4,7 +4,7 @@ use failure::Error;
 
 pub fn cmd_reindex() -> Result<(), Error> {
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     utxo_set.count_transactions()?;
     Ok(())
@@ -1,27 +1,34 @@
 use crate::blockchain::blockchain::Blockchain;
 use crate::blockchain::utxoset::UTXOSet;
-use crate::network::server::Server;
+use crate::network::server::{Server, ShutdownHandle};
 use failure::Error;
 
+/// Starts a mining node on a thread of its own, calling `on_exit` once it
+/// has stopped or failed to start
 pub fn cmd_start_miner_from_api(
     host: &str,
     port: &str,
     bootstrap: Option<&str>,
     mining_address: &str,
-) -> Result<(), Error> {
+    on_exit: impl FnOnce() + Send + 'static,
+) -> Result<ShutdownHandle, Error> {
     println!("Start miner node...");
 
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     let server = Server::new(host, port, mining_address, bootstrap, utxo_set)?;
 
+    // The node gets a runtime of its own so it outlives the request that started it
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = server.shutdown_handle();
     std::thread::spawn(move || {
-        if let Err(e) = server.start_server() {
+        if let Err(e) = runtime.block_on(server.start_server()) {
             eprintln!("Failed to run miner server: {}", e);
         } else {
-            println!("Miner server started");
+            println!("Miner server stopped");
         }
+        on_exit();
     });
 
-    Ok(())
+    Ok(handle)
 }
@@ -1,24 +1,31 @@
 use crate::blockchain::blockchain::Blockchain;
 use crate::blockchain::utxoset::UTXOSet;
-use crate::network::server::Server;
+use crate::network::server::{Server, ShutdownHandle};
 use failure::Error;
 
+/// Starts a node on a thread of its own, calling `on_exit` once it has
+/// stopped or failed to start
 pub fn cmd_start_node_from_api(
     host: &str,
     port: &str,
     bootstrap: Option<&str>,
-) -> Result<(), Error> {
+    on_exit: impl FnOnce() + Send + 'static,
+) -> Result<ShutdownHandle, Error> {
     println!("Start node...");
 
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     let server = Server::new(host, port, "", bootstrap, utxo_set)?;
 
+    // The node gets a runtime of its own so it outlives the request that started it
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = server.shutdown_handle();
     std::thread::spawn(move || {
-        if let Err(e) = server.start_server() {
+        if let Err(e) = runtime.block_on(server.start_server()) {
             eprintln!("Failed to run node: {}", e);
         }
+        on_exit();
     });
 
-    Ok(())
+    Ok(handle)
 }
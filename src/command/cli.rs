@@ -1,18 +1,25 @@
 //! cli process
 
 use crate::blockchain::blockchain::*;
+use crate::blockchain::miner::Miner;
 use crate::blockchain::utxoset::*;
-use crate::crypto::fndsa::*;
 use crate::crypto::transaction::*;
 use crate::crypto::types::EncryptionType;
+use crate::crypto::walletfile::WalletFile;
 use crate::crypto::wallets::*;
 use crate::network::server::Server;
 use crate::webserver::webserver::WebServer;
 use crate::Result;
 use bitcoincash_addr::Address;
 use clap::{App, Arg, ArgMatches};
+use failure::format_err;
 use std::process::exit;
+use std::time::Duration;
 use std::vec;
+use zeroize::Zeroizing;
+
+/// Longest an external miner searches one template
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(30);
 
 pub struct Cli {}
 
@@ -37,11 +44,87 @@ impl Cli {
             .subcommand(
                 App::new("createwallet").about("create a wallet").arg(
                     Arg::from_usage("<encryption> 'encryption type'")
-                        .possible_values(&["ECDSA", "FNDSA"])
+                        .possible_values(&["ECDSA", "FNDSA", "MLDSA", "SLHDSA", "HYBRID"])
                         .default_value("FNDSA")
                         .help("encryption type"),
                 ),
             )
+            .subcommand(
+                App::new("wallet")
+                    .about("manage the mnemonic HD wallets are derived from")
+                    .subcommand(App::new("init").about("generate a new mnemonic"))
+                    .subcommand(App::new("mnemonic").about("print the mnemonic"))
+                    .subcommand(
+                        App::new("restore")
+                            .about("restore HD wallets from a mnemonic")
+                            .arg(Arg::from_usage("<mnemonic> 'recovery phrase, quoted'"))
+                            .arg(
+                                Arg::with_name("encryption")
+                                    .long("encryption")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .possible_values(&[
+                                        "ECDSA", "FNDSA", "MLDSA", "SLHDSA", "HYBRID",
+                                    ])
+                                    .default_value("FNDSA")
+                                    .help("encryption types to regenerate"),
+                            )
+                            .arg(
+                                Arg::with_name("account")
+                                    .long("account")
+                                    .takes_value(true)
+                                    .default_value("0")
+                                    .help("account to regenerate"),
+                            )
+                            .arg(
+                                Arg::with_name("count")
+                                    .long("count")
+                                    .takes_value(true)
+                                    .default_value("10")
+                                    .help("addresses to regenerate per encryption type"),
+                            ),
+                    ),
+            )
+            .subcommand(
+                App::new("exportwallet")
+                    .about("export a wallet to a portable file")
+                    .arg(Arg::from_usage("<address> 'wallet address'"))
+                    .arg(Arg::from_usage(
+                        "-o --output [file] 'write to a file instead of stdout'",
+                    ))
+                    .arg(Arg::from_usage("--watch-only 'leave the secret key out'"))
+                    .arg(Arg::from_usage(
+                        "--encrypt 'protect a plaintext key with a passphrase'",
+                    )),
+            )
+            .subcommand(
+                App::new("importwallet")
+                    .about("import a wallet file, or watch an address or public key")
+                    .arg(Arg::from_usage("[file] 'wallet file to import'"))
+                    .arg(Arg::from_usage("--address [address] 'address to watch'"))
+                    .arg(Arg::from_usage(
+                        "--public-key [public_key] 'hex public key to watch'",
+                    ))
+                    .arg(
+                        Arg::with_name("encryption")
+                            .long("encryption")
+                            .takes_value(true)
+                            .possible_values(&["ECDSA", "FNDSA", "MLDSA", "SLHDSA", "HYBRID"])
+                            .default_value("FNDSA")
+                            .help("encryption type of --public-key"),
+                    ),
+            )
+            .subcommand(
+                App::new("encryptwallet")
+                    .about("encrypt a wallet's secret key with a passphrase")
+                    .arg(Arg::from_usage("<address> 'wallet address'")),
+            )
+            .subcommand(
+                App::new("changepassphrase")
+                    .about("change the passphrase of an encrypted wallet")
+                    .arg(Arg::from_usage("<address> 'wallet address'")),
+            )
             .subcommand(App::new("listaddresses").about("list all addresses"))
             .subcommand(App::new("reindex").about("reindex UTXO"))
             .subcommand(App::new("server").about("run server"))
@@ -61,13 +144,29 @@ impl Cli {
                             .long("bootstrap")
                             .takes_value(true)
                             .help("the address of an existing node (host:port) to connect first"),
+                    )
+                    .arg(
+                        Arg::with_name("unlock")
+                            .long("unlock")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("encrypted wallet to unlock for remote signing"),
                     ),
             )
             .subcommand(
                 App::new("startminer")
                     .about("start the minner server")
                     .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
-                    .arg(Arg::from_usage("<address> 'wallet address'")),
+                    .arg(Arg::from_usage("<address> 'wallet address'"))
+                    .arg(
+                        Arg::with_name("unlock")
+                            .long("unlock")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("encrypted wallet to unlock for remote signing"),
+                    ),
             )
             .subcommand(
                 App::new("getbalance")
@@ -76,6 +175,13 @@ impl Cli {
                         "<address> 'The address to get balance for'",
                     )),
             )
+            .subcommand(
+                App::new("history")
+                    .about("list outputs received and spent by an address")
+                    .arg(Arg::from_usage(
+                        "<address> 'The address to list history for'",
+                    )),
+            )
             .subcommand(App::new("createblockchain").about("create blockchain").arg(
                 Arg::from_usage("<address> 'The address to send genesis block reward to'"),
             ))
@@ -108,6 +214,27 @@ impl Cli {
                         "-m --mine 'mine immediately on the remote node'",
                     )),
             )
+            .subcommand(
+                App::new("mineremote")
+                    .about("mine blocks from templates a remote node builds")
+                    .arg(Arg::from_usage("<node> 'Remote node address (host:port)'"))
+                    .arg(Arg::from_usage(
+                        "<address> 'The address to send block rewards to'",
+                    ))
+                    .arg(
+                        Arg::with_name("threads")
+                            .long("threads")
+                            .takes_value(true)
+                            .help("worker threads, one per core by default"),
+                    )
+                    .arg(
+                        Arg::with_name("count")
+                            .long("count")
+                            .takes_value(true)
+                            .default_value("1")
+                            .help("blocks to mine before exiting"),
+                    ),
+            )
             .get_matches();
 
         match matches.subcommand() {
@@ -117,14 +244,124 @@ impl Cli {
                     println!("Balance: {}\n", balance);
                 }
             }
+            ("history", Some(sub_m)) => {
+                let address = get_value("address", sub_m)?;
+                let history = cmd_history(address)?;
+                for entry in &history {
+                    match &entry.spent {
+                        Some(spend) => println!(
+                            "height {:>6}  received {:>6}  {}:{}  spent at height {} in {}",
+                            entry.height,
+                            entry.value,
+                            entry.txid,
+                            entry.vout,
+                            spend.height,
+                            spend.txid
+                        ),
+                        None => println!(
+                            "height {:>6}  received {:>6}  {}:{}  unspent",
+                            entry.height, entry.value, entry.txid, entry.vout
+                        ),
+                    }
+                }
+                println!("Balance: {}\n", cmd_get_balance(address)?);
+            }
             ("createwallet", Some(sub_m)) => {
                 let encryption = sub_m.value_of("encryption").unwrap().trim();
                 let encryption: EncryptionType = match encryption {
                     "ECDSA" => EncryptionType::ECDSA,
                     "FNDSA" => EncryptionType::FNDSA,
+                    "MLDSA" => EncryptionType::MLDSA,
+                    "SLHDSA" => EncryptionType::SLHDSA,
+                    "HYBRID" => EncryptionType::HYBRID,
                     _ => EncryptionType::FNDSA,
                 };
-                println!("address: {}", cmd_create_wallet(encryption)?);
+                let passphrase = if Wallets::new()?.has_mnemonic() {
+                    Some(read_mnemonic_passphrase()?)
+                } else {
+                    None
+                };
+                println!(
+                    "address: {}",
+                    cmd_create_wallet(encryption, passphrase.as_deref())?
+                );
+            }
+            ("wallet", Some(sub_m)) => match sub_m.subcommand() {
+                ("init", Some(_)) => {
+                    let passphrase = read_new_passphrase()?;
+                    println!("mnemonic: {}", *cmd_init_mnemonic(&passphrase)?);
+                    println!("write these words down; they restore every HD wallet");
+                }
+                ("mnemonic", Some(_)) => {
+                    let passphrase = read_mnemonic_passphrase()?;
+                    println!("mnemonic: {}", *cmd_show_mnemonic(&passphrase)?);
+                }
+                ("restore", Some(restore_m)) => {
+                    let mnemonic = get_value("mnemonic", restore_m)?;
+                    let mut encryptions = Vec::new();
+                    for encryption in restore_m.values_of("encryption").into_iter().flatten() {
+                        encryptions.push(
+                            encryption
+                                .parse::<EncryptionType>()
+                                .map_err(|_| format_err!("unknown encryption: {}", encryption))?,
+                        );
+                    }
+                    let account: u32 = get_value("account", restore_m)?.parse()?;
+                    let count: u32 = get_value("count", restore_m)?.parse()?;
+                    let passphrase = read_mnemonic_passphrase()?;
+                    for address in
+                        cmd_restore_wallets(mnemonic, &passphrase, &encryptions, account, count)?
+                    {
+                        println!("address: {}", address);
+                    }
+                }
+                _ => println!("{}", sub_m.usage()),
+            },
+            ("exportwallet", Some(sub_m)) => {
+                let address = get_value("address", sub_m)?;
+                let passphrase = if sub_m.is_present("encrypt") {
+                    Some(read_new_passphrase()?)
+                } else {
+                    None
+                };
+                let json = cmd_export_wallet(
+                    address,
+                    sub_m.is_present("watch-only"),
+                    passphrase.as_deref(),
+                )?;
+                match sub_m.value_of("output") {
+                    Some(path) => std::fs::write(path, json)?,
+                    None => println!("{}", json),
+                }
+            }
+            ("importwallet", Some(sub_m)) => {
+                let address = if let Some(path) = sub_m.value_of("file") {
+                    cmd_import_wallet(&std::fs::read_to_string(path)?)?
+                } else if let Some(address) = sub_m.value_of("address") {
+                    cmd_watch_address(address)?;
+                    address.to_string()
+                } else if let Some(public_key) = sub_m.value_of("public-key") {
+                    let encryption = get_value("encryption", sub_m)?
+                        .parse::<EncryptionType>()
+                        .map_err(|_| format_err!("unknown encryption"))?;
+                    cmd_watch_public_key(public_key, encryption)?
+                } else {
+                    error_start_miner("file", sub_m.usage())
+                };
+                println!("address: {}", address);
+            }
+            ("encryptwallet", Some(sub_m)) => {
+                let address = get_value("address", sub_m)?;
+                let passphrase = read_new_passphrase()?;
+                cmd_encrypt_wallet(address, &passphrase)?;
+                println!("wallet encrypted: {}", address);
+            }
+            ("changepassphrase", Some(sub_m)) => {
+                let address = get_value("address", sub_m)?;
+                let old = rpassword::prompt_password("Current passphrase: ")?;
+                let new = read_new_passphrase()?;
+                cmd_change_passphrase(address, &old, &new)?;
+                println!("passphrase changed: {}", address);
             }
             ("printchain", Some(_)) => {
                 cmd_print_chain()?;
@@ -153,13 +390,23 @@ impl Cli {
                     error_start_miner("amount", sub_m.usage())
                 };
                 let target_node = sub_m.value_of("node");
-                cmd_send(from, to, amount, sub_m.is_present("mine"), target_node)?;
+                let passphrase = read_passphrase_if_encrypted(from)?;
+                cmd_send(
+                    from,
+                    to,
+                    amount,
+                    sub_m.is_present("mine"),
+                    target_node,
+                    passphrase.as_deref(),
+                )
+                .await?;
             }
             ("startnode", Some(sub_m)) => {
                 if let Some(port) = sub_m.value_of("port") {
+                    cmd_unlock_wallets(sub_m)?;
                     println!("Start node...");
                     let bc = Blockchain::new()?;
-                    let utxo_set = UTXOSet { blockchain: bc };
+                    let utxo_set = UTXOSet::new(bc)?;
                     let server = Server::new(
                         sub_m.value_of("host").unwrap_or("0.0.0.0"),
                         port,
@@ -167,15 +414,17 @@ impl Cli {
                         sub_m.value_of("bootstrap"),
                         utxo_set,
                     )?;
-                    server.start_server()?;
+                    server.shutdown_handle().stop_on_signal();
+                    server.start_server().await?;
                 }
             }
             ("startminer", Some(sub_m)) => {
                 let mining_address = get_value("address", sub_m)?;
                 let port = get_value("port", sub_m)?;
+                cmd_unlock_wallets(sub_m)?;
                 println!("Start miner node...");
                 let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
+                let utxo_set = UTXOSet::new(bc)?;
                 let server = Server::new(
                     sub_m.value_of("host").unwrap_or("0.0.0.0"),
                     port,
@@ -183,7 +432,8 @@ impl Cli {
                     sub_m.value_of("bootstrap"),
                     utxo_set,
                 )?;
-                server.start_server()?;
+                server.shutdown_handle().stop_on_signal();
+                server.start_server().await?;
             }
             ("remotesend", Some(sub_m)) => {
                 let from = sub_m.value_of("from").unwrap();
@@ -191,7 +441,17 @@ impl Cli {
                 let amount: i32 = sub_m.value_of("amount").unwrap().parse()?;
                 let node = sub_m.value_of("node").unwrap();
                 let mine = sub_m.is_present("mine");
-                cmd_remote_send(from, to, amount, node, mine)?;
+                cmd_remote_send(from, to, amount, node, mine).await?;
+            }
+            ("mineremote", Some(sub_m)) => {
+                let node = get_value("node", sub_m)?;
+                let address = get_value("address", sub_m)?;
+                let miner = match sub_m.value_of("threads") {
+                    Some(threads) => Miner::new(threads.parse()?),
+                    None => Miner::default(),
+                };
+                let count: u32 = get_value("count", sub_m)?.parse()?;
+                cmd_mine_remote(node, address, &miner, count).await?;
             }
             _ => {}
         }
@@ -206,27 +466,28 @@ async fn cmd_server() -> Result<()> {
     Ok(())
 }
 
-fn cmd_send(
+async fn cmd_send(
     from: &str,
     to: &str,
     amount: i32,
     mine_now: bool,
     target_node: Option<&str>,
+    passphrase: Option<&str>,
 ) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if let Some(passphrase) = passphrase {
+        wallets.unlock_wallet(from, passphrase)?;
+    }
+    let wallet = wallets.get_signing_wallet(from)?;
     let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    // TODO: 暗号化方式を選択
-    let crypto = FnDsaCrypto;
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set, &crypto)?;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let crypto = wallet.crypto_provider();
+    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set, crypto.as_ref())?;
     if mine_now {
         let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
-
-        utxo_set.update(&new_block)?;
+        utxo_set.mine_block(vec![cbtx, tx])?;
     } else {
-        Server::send_transaction(&tx, utxo_set, target_node.unwrap_or("0.0.0.0:7000"))?;
+        Server::send_transaction(&tx, utxo_set, target_node.unwrap_or("0.0.0.0:7000")).await?;
     }
 
     println!("success!");
@@ -247,16 +508,158 @@ fn error_start_miner(name: &str, usage: &str) -> ! {
     exit(1)
 }
 
-pub fn cmd_create_wallet(encryption: EncryptionType) -> Result<String> {
+/// Prompts for a passphrase when the wallet at `address` is encrypted
+fn read_passphrase_if_encrypted(address: &str) -> Result<Option<String>> {
+    match Wallets::new()?.get_wallet(address) {
+        Some(wallet) if wallet.is_locked() => Ok(Some(rpassword::prompt_password(format!(
+            "Passphrase for {}: ",
+            address
+        ))?)),
+        _ => Ok(None),
+    }
+}
+
+/// Prompts for the passphrase of the mnemonic, or for a new one to seal it
+/// under if it is not sealed yet
+fn read_mnemonic_passphrase() -> Result<String> {
+    if Wallets::new()?.is_mnemonic_sealed() {
+        Ok(rpassword::prompt_password("Mnemonic passphrase: ")?)
+    } else {
+        read_new_passphrase()
+    }
+}
+
+/// Prompts for a new passphrase twice
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(format_err!("passphrase must not be empty"));
+    }
+    if rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err(format_err!("passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// Unlocks every wallet named with `--unlock` for the lifetime of the process
+fn cmd_unlock_wallets(matches: &ArgMatches<'_>) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    for address in matches.values_of("unlock").into_iter().flatten() {
+        let passphrase = rpassword::prompt_password(format!("Passphrase for {}: ", address))?;
+        wallets.unlock_wallet(address, &passphrase)?;
+    }
+    Ok(())
+}
+
+pub fn cmd_encrypt_wallet(address: &str, passphrase: &str) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    ws.encrypt_wallet(address, passphrase)
+}
+
+pub fn cmd_unlock_wallet(address: &str, passphrase: &str) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    ws.unlock_wallet(address, passphrase)
+}
+
+pub fn cmd_lock_wallet(address: &str) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    ws.lock_wallet(address)
+}
+
+pub fn cmd_change_passphrase(address: &str, old: &str, new: &str) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    ws.change_passphrase(address, old, new)
+}
+
+/// Creates a wallet, derived from the mnemonic once one exists; the
+/// passphrase unlocks the mnemonic
+pub fn cmd_create_wallet(encryption: EncryptionType, passphrase: Option<&str>) -> Result<String> {
+    let mut ws = Wallets::new()?;
+    if let Some(passphrase) = passphrase {
+        ws.unlock_mnemonic(passphrase)?;
+    }
+    let address = if ws.has_mnemonic() {
+        ws.create_hd_wallet(encryption, 0)?
+    } else {
+        ws.create_wallet(encryption)
+    };
+    ws.save_all()?;
+    Ok(address)
+}
+
+/// Exports a wallet in the portable file format, as JSON
+pub fn cmd_export_wallet(
+    address: &str,
+    watch_only: bool,
+    passphrase: Option<&str>,
+) -> Result<String> {
+    let ws = Wallets::new()?;
+    ws.export_wallet(address, watch_only, passphrase)?.to_json()
+}
+
+/// Imports a wallet file, returning its address
+pub fn cmd_import_wallet(json: &str) -> Result<String> {
     let mut ws = Wallets::new()?;
-    let address = ws.create_wallet(encryption);
+    let address = ws.import_wallet(WalletFile::from_json(json)?)?;
     ws.save_all()?;
     Ok(address)
 }
 
+pub fn cmd_watch_address(address: &str) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    ws.watch_address(address)?;
+    ws.save_all()
+}
+
+/// Watches the address of a hex-encoded public key, returning the address
+pub fn cmd_watch_public_key(public_key: &str, encryption: EncryptionType) -> Result<String> {
+    let mut ws = Wallets::new()?;
+    let address = ws.watch_public_key(hex::decode(public_key.trim())?, encryption)?;
+    ws.save_all()?;
+    Ok(address)
+}
+
+fn cmd_init_mnemonic(passphrase: &str) -> Result<Zeroizing<String>> {
+    let mut ws = Wallets::new()?;
+    let mnemonic = ws.init_mnemonic(passphrase)?;
+    ws.save_all()?;
+    Ok(mnemonic)
+}
+
+fn cmd_show_mnemonic(passphrase: &str) -> Result<Zeroizing<String>> {
+    let mut ws = Wallets::new()?;
+    if !ws.has_mnemonic() {
+        return Err(format_err!("no mnemonic; run `wallet init` first"));
+    }
+    let mnemonic = ws.reveal_mnemonic(passphrase)?;
+    // A plaintext phrase from an older version is sealed now
+    ws.save_all()?;
+    Ok(mnemonic)
+}
+
+/// Regenerates the first `count` HD wallets of an account for each type
+fn cmd_restore_wallets(
+    mnemonic: &str,
+    passphrase: &str,
+    encryptions: &[EncryptionType],
+    account: u32,
+    count: u32,
+) -> Result<Vec<String>> {
+    let mut ws = Wallets::new()?;
+    ws.restore_mnemonic(mnemonic, passphrase)?;
+    let mut addresses = Vec::new();
+    for &encryption in encryptions {
+        for index in 0..count {
+            addresses.push(ws.derive_wallet(encryption, account, index)?);
+        }
+    }
+    ws.save_all()?;
+    Ok(addresses)
+}
+
 fn cmd_reindex() -> Result<i32> {
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     utxo_set.count_transactions()
 }
@@ -265,7 +668,7 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
     let address = String::from(address);
     let bc = Blockchain::create_blockchain(address)?;
 
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     println!("create blockchain");
     Ok(())
@@ -274,13 +677,23 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
 fn cmd_get_balance(address: &str) -> Result<i32> {
     let pub_key_hash = Address::decode(address).unwrap().body;
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
 
-    let balance = utxos.outputs.iter().map(|out| out.value).sum();
+    let balance = utxos.iter().map(|out| out.value).sum();
     Ok(balance)
 }
 
+/// Lists the outputs an address received, oldest first
+pub fn cmd_history(address: &str) -> Result<Vec<HistoryEntry>> {
+    let pub_key_hash = Address::decode(address)
+        .map_err(|e| format_err!("invalid address: {:?}", e))?
+        .body;
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet::new(bc)?;
+    utxo_set.history(&pub_key_hash)
+}
+
 pub fn cmd_print_chain() -> Result<()> {
     let bc = Blockchain::new()?;
     for b in bc.iter() {
@@ -296,12 +709,21 @@ fn cmd_list_address() -> Result<()> {
     for ad in addresses {
         println!("{}", ad);
     }
+    for ad in ws.get_watch_only_addresses() {
+        println!("{} (watch-only)", ad);
+    }
     Ok(())
 }
 
-fn cmd_remote_send(from: &str, to: &str, amount: i32, node: &str, _mine_now: bool) -> Result<()> {
+async fn cmd_remote_send(
+    from: &str,
+    to: &str,
+    amount: i32,
+    node: &str,
+    _mine_now: bool,
+) -> Result<()> {
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
 
     let tx = Transaction {
         id: String::new(),
@@ -311,14 +733,50 @@ fn cmd_remote_send(from: &str, to: &str, amount: i32, node: &str, _mine_now: boo
 
     let server = Server::new("0.0.0.0", "0", "", None, utxo_set)?;
 
-    let signed_tx = server.send_sign_request(node, from, &tx)?;
+    let signed_tx = server.send_sign_request(node, from, &tx).await?;
 
-    server.send_tx(node, &signed_tx)?;
+    server.send_tx(node, &signed_tx).await?;
+    // Wait for the queued transaction to be written before exiting
+    server.close_connections().await;
 
     println!("Transaction sent successfully!");
     Ok(())
 }
 
+/// Mines `count` blocks from templates `node` builds and hands them back
+///
+/// Templates go stale once the node's tip moves, so each one is searched
+/// for at most `TEMPLATE_REFRESH` before a fresh one is fetched.
+async fn cmd_mine_remote(node: &str, address: &str, miner: &Miner, count: u32) -> Result<()> {
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet::new(bc)?;
+    let server = Server::new("0.0.0.0", "0", "", None, utxo_set)?;
+
+    let mut mined = 0;
+    while mined < count {
+        let epoch = miner.epoch();
+        let template = server.get_block_template(node, address).await?;
+        let timer = miner.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(TEMPLATE_REFRESH).await;
+            timer.cancel_epoch(epoch);
+        });
+
+        let worker = miner.clone();
+        let found = tokio::task::spawn_blocking(move || worker.mine(template, epoch)).await??;
+        if let Some(block) = found {
+            server.submit_block(node, &block).await?;
+            println!(
+                "block {} accepted at height {}",
+                block.get_hash(),
+                block.get_height()
+            );
+            mined += 1;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -327,11 +785,11 @@ mod tests {
     type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
 
     /// ローカルで即時採掘を行う send コマンドのテスト
-    #[test]
-    fn test_cli_send_with_mine() -> TestResult {
+    #[tokio::test]
+    async fn test_cli_send_with_mine() -> TestResult {
         // 2 つのウォレットを作成
-        let addr1 = cmd_create_wallet(EncryptionType::FNDSA)?;
-        let addr2 = cmd_create_wallet(EncryptionType::FNDSA)?;
+        let addr1 = cmd_create_wallet(EncryptionType::FNDSA, None)?;
+        let addr2 = cmd_create_wallet(EncryptionType::FNDSA, None)?;
         // ジェネシスブロック作成：addr1 に初期報酬が入る（例では 10 とする）
         cmd_create_blockchain(&addr1)?;
 
@@ -342,7 +800,7 @@ mod tests {
         assert_eq!(balance2, 0);
 
         // addr1 から addr2 へ 5 単位送金（-m オプション：即時採掘モード、target_node は None）
-        cmd_send(&addr1, &addr2, 5, true, None)?;
+        cmd_send(&addr1, &addr2, 5, true, None, None).await?;
 
         // 採掘が行われたので、残高が更新されるはず
         let balance1_after = cmd_get_balance(&addr1)?;
@@ -351,8 +809,26 @@ mod tests {
         assert_eq!(balance1_after, 15);
         assert_eq!(balance2_after, 5);
 
+        // addr1: genesis reward spent by the send, then change and a new reward
+        let history1 = cmd_history(&addr1)?;
+        assert_eq!(history1.len(), 3);
+        assert_eq!(history1[0].height, 0);
+        assert_eq!(history1[0].value, 10);
+        let spend = history1[0].spent.as_ref().unwrap();
+        assert_eq!(spend.height, 1);
+        assert!(history1[1..]
+            .iter()
+            .all(|entry| entry.height == 1 && entry.spent.is_none()));
+        assert_eq!(history1.iter().map(|entry| entry.value).sum::<i32>(), 25);
+
+        let history2 = cmd_history(&addr2)?;
+        assert_eq!(history2.len(), 1);
+        assert_eq!(history2[0].value, 5);
+        assert_eq!(history2[0].txid, spend.txid);
+        assert!(history2[0].spent.is_none());
+
         // addr2 から addr1 へ、残高以上（15 単位）の送金を試みる → エラーとなるはず
-        let res = cmd_send(&addr2, &addr1, 15, true, None);
+        let res = cmd_send(&addr2, &addr1, 15, true, None, None).await;
         assert!(res.is_err());
 
         // 再度残高確認（変化はないはず）
@@ -364,10 +840,10 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn test_cli_send_with_target_node() -> TestResult {
-        let addr1 = cmd_create_wallet(EncryptionType::FNDSA)?;
-        let addr2 = cmd_create_wallet(EncryptionType::FNDSA)?;
+    #[tokio::test]
+    async fn test_cli_send_with_target_node() -> TestResult {
+        let addr1 = cmd_create_wallet(EncryptionType::FNDSA, None)?;
+        let addr2 = cmd_create_wallet(EncryptionType::FNDSA, None)?;
         cmd_create_blockchain(&addr1)?;
 
         let balance1 = cmd_get_balance(&addr1)?;
@@ -375,7 +851,29 @@ mod tests {
         assert_eq!(balance1, 10);
         assert_eq!(balance2, 0);
 
-        let _ = cmd_send(&addr1, &addr2, 5, false, Some("127.0.0.1:7000"));
+        let _ = cmd_send(&addr1, &addr2, 5, false, Some("127.0.0.1:7000"), None).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cli_send_requires_unlocked_wallet() -> TestResult {
+        let addr1 = cmd_create_wallet(EncryptionType::ECDSA, None)?;
+        let addr2 = cmd_create_wallet(EncryptionType::ECDSA, None)?;
+        cmd_encrypt_wallet(&addr1, "correct horse")?;
+
+        let res = cmd_send(&addr1, &addr2, 5, false, None, None).await;
+        assert!(res.unwrap_err().to_string().contains("locked"));
+
+        let res = cmd_send(&addr1, &addr2, 5, false, None, Some("battery staple")).await;
+        assert!(res.unwrap_err().to_string().contains("wrong passphrase"));
+
+        cmd_change_passphrase(&addr1, "correct horse", "battery staple")?;
+        assert!(cmd_unlock_wallet(&addr1, "correct horse").is_err());
+        cmd_unlock_wallet(&addr1, "battery staple")?;
+        assert!(!Wallets::new()?.get_wallet(&addr1).unwrap().is_locked());
+
+        cmd_lock_wallet(&addr1)?;
+        assert!(Wallets::new()?.get_wallet(&addr1).unwrap().is_locked());
         Ok(())
     }
 }
@@ -1,6 +1,11 @@
 pub mod ecdsa;
 pub mod fndsa;
+pub mod hd;
+pub mod hybrid;
+pub mod mldsa;
+pub mod slhdsa;
 pub mod traits;
 pub mod transaction;
 pub mod types;
+pub mod walletfile;
 pub mod wallets;
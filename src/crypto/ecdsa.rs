@@ -1,23 +1,49 @@
-use super::traits::CryptoProvider;
+use super::traits::{CryptoError, CryptoProvider};
+use super::types::EncryptionType;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use secp256k1::ecdsa::Signature;
 use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
 
 pub struct EcdsaCrypto;
 
+/// secp256k1 signs 32-byte digests, so arbitrary messages are hashed first
+fn message_digest(message: &[u8]) -> Message {
+    let mut hasher = Sha256::new();
+    hasher.input(message);
+    let mut digest = [0u8; 32];
+    hasher.result(&mut digest);
+    Message::from_digest(digest)
+}
+
 impl CryptoProvider for EcdsaCrypto {
-    fn sign(&self, private_key: &[u8], message: &[u8]) -> Vec<u8> {
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
         let secp = Secp256k1::signing_only();
-        let sk = SecretKey::from_slice(private_key).expect("Invalid private key");
-        let msg = Message::from_digest(message.try_into().expect("Invalid message"));
+        let sk = SecretKey::from_slice(private_key).map_err(|_| CryptoError::InvalidPrivateKey)?;
+        let msg = message_digest(message);
         let sig = secp.sign_ecdsa(&msg, &sk);
-        sig.serialize_compact().to_vec()
+        Ok(sig.serialize_compact().to_vec())
     }
 
-    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
         let secp = Secp256k1::verification_only();
-        let pk = PublicKey::from_slice(public_key).expect("Invalid public key");
-        let msg = Message::from_digest(message.try_into().expect("Invalid message"));
-        let sig = Signature::from_compact(signature).expect("Invalid signature");
-        secp.verify_ecdsa(&msg, &sig, &pk).is_ok()
+        let pk = PublicKey::from_slice(public_key).map_err(|_| CryptoError::InvalidPublicKey)?;
+        let msg = message_digest(message);
+        let sig = Signature::from_compact(signature).map_err(|_| CryptoError::InvalidSignature)?;
+        Ok(secp.verify_ecdsa(&msg, &sig, &pk).is_ok())
+    }
+
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        PublicKey::from_slice(public_key).map_err(|_| CryptoError::InvalidPublicKey)?;
+        Ok(())
+    }
+
+    fn encryption_type(&self) -> EncryptionType {
+        EncryptionType::ECDSA
     }
 }
@@ -1,4 +1,5 @@
-use super::traits::CryptoProvider;
+use super::traits::{CryptoError, CryptoProvider};
+use super::types::EncryptionType;
 use fn_dsa::{
     signature_size, SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard,
     DOMAIN_NONE, HASH_ID_RAW,
@@ -8,8 +9,9 @@ use rand_core::OsRng;
 pub struct FnDsaCrypto;
 
 impl CryptoProvider for FnDsaCrypto {
-    fn sign(&self, private_key: &[u8], message: &[u8]) -> Vec<u8> {
-        let mut sk = SigningKeyStandard::decode(private_key).unwrap();
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut sk =
+            SigningKeyStandard::decode(private_key).ok_or(CryptoError::InvalidPrivateKey)?;
         let mut signature = vec![0u8; signature_size(sk.get_logn())];
         sk.sign(
             &mut OsRng,
@@ -18,15 +20,30 @@ impl CryptoProvider for FnDsaCrypto {
             message,
             &mut signature,
         );
-        signature
+        Ok(signature)
     }
 
-    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
-        VerifyingKeyStandard::decode(public_key).unwrap().verify(
-            signature,
-            &DOMAIN_NONE,
-            &HASH_ID_RAW,
-            message,
-        )
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
+        let vk = VerifyingKeyStandard::decode(public_key).ok_or(CryptoError::InvalidPublicKey)?;
+        // The low nibble of the key header is the degree (logn)
+        let logn = (public_key[0] & 0x0f) as u32;
+        if signature.len() != signature_size(logn) {
+            return Err(CryptoError::InvalidSignature);
+        }
+        Ok(vk.verify(signature, &DOMAIN_NONE, &HASH_ID_RAW, message))
+    }
+
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        VerifyingKeyStandard::decode(public_key).ok_or(CryptoError::InvalidPublicKey)?;
+        Ok(())
+    }
+
+    fn encryption_type(&self) -> EncryptionType {
+        EncryptionType::FNDSA
     }
 }
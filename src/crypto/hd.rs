@@ -0,0 +1,101 @@
+//! Hierarchical deterministic key derivation from a BIP-39 mnemonic
+//!
+//! A child seed is derived for every (scheme, account, index) triple and fed
+//! to the scheme's deterministic key generation, so one mnemonic backs up
+//! every address the wallet will ever create.
+
+use super::types::EncryptionType;
+use crate::Result;
+use bip39::Mnemonic;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+use failure::format_err;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+/// Entropy of a new mnemonic; 32 bytes give 24 words
+const ENTROPY_LEN: usize = 32;
+
+/// HMAC key turning a BIP-39 seed into the master key
+const MASTER_KEY: &[u8] = b"polytorus hd seed";
+
+/// Length of the key material derived for one wallet
+pub const CHILD_SEED_LEN: usize = 64;
+
+/// NewMnemonic generates a fresh 24-word mnemonic
+pub fn new_mnemonic() -> Mnemonic {
+    let mut entropy = [0u8; ENTROPY_LEN];
+    OsRng.fill_bytes(&mut entropy);
+    Mnemonic::from_entropy(&entropy).expect("32 bytes is a valid entropy length")
+}
+
+/// ParseMnemonic checks the words and checksum of a mnemonic
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic> {
+    Mnemonic::parse_normalized(words.trim()).map_err(|e| format_err!("invalid mnemonic: {}", e))
+}
+
+/// ChildSeed derives the key material of one wallet from a BIP-39 seed
+///
+/// The scheme is part of the path, so the same index never yields related
+/// keys under two different schemes.
+pub fn child_seed(
+    seed: &[u8],
+    encryption: EncryptionType,
+    account: u32,
+    index: u32,
+) -> [u8; CHILD_SEED_LEN] {
+    let mut master = [0u8; 64];
+    let mut mac = Hmac::new(Sha512::new(), MASTER_KEY);
+    mac.input(seed);
+    mac.raw_result(&mut master);
+
+    let path = format!("{}/{}/{}", scheme_label(encryption), account, index);
+    let mut child = [0u8; CHILD_SEED_LEN];
+    let mut mac = Hmac::new(Sha512::new(), &master);
+    mac.input(path.as_bytes());
+    mac.raw_result(&mut child);
+    child
+}
+
+/// Stable path component for each scheme, independent of the enum's names
+fn scheme_label(encryption: EncryptionType) -> &'static str {
+    match encryption {
+        EncryptionType::ECDSA => "ecdsa",
+        EncryptionType::FNDSA => "fn-dsa-512",
+        EncryptionType::MLDSA => "ml-dsa-44",
+        EncryptionType::SLHDSA => "slh-dsa-sha2-128s",
+        EncryptionType::HYBRID => "ecdsa+fn-dsa-512",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon \
+                            abandon abandon abandon abandon abandon about";
+
+    /// BIP-39 reference vector for the all-zero entropy mnemonic
+    #[test]
+    fn test_mnemonic_seed() {
+        let mnemonic = parse_mnemonic(MNEMONIC).unwrap();
+        let seed = mnemonic.to_seed("TREZOR");
+        assert_eq!(seed[..8], [0xc5, 0x52, 0x57, 0xc3, 0x60, 0xc0, 0x7c, 0x72]);
+
+        assert!(parse_mnemonic("abandon abandon abandon").is_err());
+        assert!(parse_mnemonic(&MNEMONIC.replace("about", "abandon")).is_err());
+        assert_eq!(new_mnemonic().word_count(), 24);
+    }
+
+    #[test]
+    fn test_child_seed_paths_are_independent() {
+        let seed = parse_mnemonic(MNEMONIC).unwrap().to_seed("");
+        let child = child_seed(&seed, EncryptionType::FNDSA, 0, 0);
+        assert_eq!(child, child_seed(&seed, EncryptionType::FNDSA, 0, 0));
+        assert_ne!(child, child_seed(&seed, EncryptionType::FNDSA, 0, 1));
+        assert_ne!(child, child_seed(&seed, EncryptionType::FNDSA, 1, 0));
+        assert_ne!(child, child_seed(&seed, EncryptionType::ECDSA, 0, 0));
+        assert_ne!(child, child_seed(&[0; 64], EncryptionType::FNDSA, 0, 0));
+    }
+}
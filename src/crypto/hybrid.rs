@@ -0,0 +1,141 @@
+use super::ecdsa::EcdsaCrypto;
+use super::fndsa::FnDsaCrypto;
+use super::traits::{CryptoError, CryptoProvider};
+use super::types::EncryptionType;
+use fn_dsa::{sign_key_size, signature_size, vrfy_key_size, FN_DSA_LOGN_512};
+use secp256k1::constants::{COMPACT_SIGNATURE_SIZE, PUBLIC_KEY_SIZE, SECRET_KEY_SIZE};
+
+/// Composite secp256k1 + FN-DSA-512 signatures
+///
+/// Keys and signatures are the ECDSA encoding followed by the FN-DSA encoding,
+/// so a hybrid address (the hash of the concatenated public keys) commits to
+/// both keys. A signature is valid only if both halves verify, which keeps
+/// funds safe as long as either primitive remains unbroken.
+pub struct HybridCrypto;
+
+/// Length of a hybrid secret key
+pub const SECRET_KEY_LEN: usize = SECRET_KEY_SIZE + sign_key_size(FN_DSA_LOGN_512);
+/// Length of a hybrid public key
+pub const PUBLIC_KEY_LEN: usize = PUBLIC_KEY_SIZE + vrfy_key_size(FN_DSA_LOGN_512);
+/// Length of a hybrid signature
+pub const SIGNATURE_LEN: usize = COMPACT_SIGNATURE_SIZE + signature_size(FN_DSA_LOGN_512);
+
+impl CryptoProvider for HybridCrypto {
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if private_key.len() != SECRET_KEY_LEN {
+            return Err(CryptoError::InvalidPrivateKey);
+        }
+        let (ecdsa_key, fndsa_key) = private_key.split_at(SECRET_KEY_SIZE);
+        let mut signature = EcdsaCrypto.sign(ecdsa_key, message)?;
+        signature.extend(FnDsaCrypto.sign(fndsa_key, message)?);
+        Ok(signature)
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
+        if public_key.len() != PUBLIC_KEY_LEN {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        if signature.len() != SIGNATURE_LEN {
+            return Err(CryptoError::InvalidSignature);
+        }
+        let (ecdsa_key, fndsa_key) = public_key.split_at(PUBLIC_KEY_SIZE);
+        let (ecdsa_sig, fndsa_sig) = signature.split_at(COMPACT_SIGNATURE_SIZE);
+        // Evaluate both so a malformed half is always reported
+        let ecdsa_ok = EcdsaCrypto.verify(ecdsa_key, message, ecdsa_sig)?;
+        let fndsa_ok = FnDsaCrypto.verify(fndsa_key, message, fndsa_sig)?;
+        Ok(ecdsa_ok && fndsa_ok)
+    }
+
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        if public_key.len() != PUBLIC_KEY_LEN {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        let (ecdsa_key, fndsa_key) = public_key.split_at(PUBLIC_KEY_SIZE);
+        EcdsaCrypto.validate_public_key(ecdsa_key)?;
+        FnDsaCrypto.validate_public_key(fndsa_key)
+    }
+
+    fn encryption_type(&self) -> EncryptionType {
+        EncryptionType::HYBRID
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::wallets::Wallets;
+
+    #[test]
+    fn test_both_signatures_required() {
+        let mut ws = Wallets::new().unwrap();
+        let hybrid = ws.create_wallet(EncryptionType::HYBRID);
+        let other = ws.create_wallet(EncryptionType::HYBRID);
+        let w = ws.get_wallet(&hybrid).unwrap();
+        let o = ws.get_wallet(&other).unwrap();
+        assert_eq!(w.secret_key.len(), SECRET_KEY_LEN);
+        assert_eq!(w.public_key.len(), PUBLIC_KEY_LEN);
+
+        let sig = HybridCrypto.sign(&w.secret_key, b"message").unwrap();
+        assert_eq!(sig.len(), SIGNATURE_LEN);
+        assert_eq!(
+            HybridCrypto.verify(&w.public_key, b"message", &sig),
+            Ok(true)
+        );
+        assert_eq!(
+            HybridCrypto.verify(&w.public_key, b"massage", &sig),
+            Ok(false)
+        );
+
+        // Swap in the other wallet's ECDSA half: FN-DSA alone must not suffice
+        let foreign = HybridCrypto.sign(&o.secret_key, b"message").unwrap();
+        let mut mixed = foreign[..COMPACT_SIGNATURE_SIZE].to_vec();
+        mixed.extend_from_slice(&sig[COMPACT_SIGNATURE_SIZE..]);
+        assert_eq!(
+            HybridCrypto.verify(&w.public_key, b"message", &mixed),
+            Ok(false)
+        );
+
+        // And the other way round: ECDSA alone must not suffice
+        let mut mixed = sig[..COMPACT_SIGNATURE_SIZE].to_vec();
+        mixed.extend_from_slice(&foreign[COMPACT_SIGNATURE_SIZE..]);
+        assert_eq!(
+            HybridCrypto.verify(&w.public_key, b"message", &mixed),
+            Ok(false)
+        );
+
+        assert_eq!(
+            HybridCrypto.verify(&w.public_key[1..], b"message", &sig),
+            Err(CryptoError::InvalidPublicKey)
+        );
+
+        // A key of the right length is checked half by half
+        assert_eq!(HybridCrypto.validate_public_key(&w.public_key), Ok(()));
+        for range in [0..PUBLIC_KEY_SIZE, PUBLIC_KEY_SIZE..PUBLIC_KEY_LEN] {
+            let mut garbage = w.public_key.clone();
+            garbage[range].fill(0xff);
+            assert_eq!(
+                HybridCrypto.validate_public_key(&garbage),
+                Err(CryptoError::InvalidPublicKey)
+            );
+        }
+    }
+
+    #[test]
+    fn test_address_commits_to_both_keys() {
+        let mut ws = Wallets::new().unwrap();
+        let hybrid = ws.create_wallet(EncryptionType::HYBRID);
+        let mut w = ws.get_wallet(&hybrid).unwrap().clone();
+
+        w.public_key[0..PUBLIC_KEY_SIZE].copy_from_slice(&[2; PUBLIC_KEY_SIZE]);
+        assert_ne!(w.get_address(), hybrid);
+
+        let mut w = ws.get_wallet(&hybrid).unwrap().clone();
+        w.public_key[PUBLIC_KEY_LEN - 1] ^= 0xff;
+        assert_ne!(w.get_address(), hybrid);
+    }
+}
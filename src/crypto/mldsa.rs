@@ -0,0 +1,102 @@
+use super::traits::{CryptoError, CryptoProvider};
+use super::types::EncryptionType;
+use fips204::ml_dsa_44::{PrivateKey, PublicKey, PK_LEN, SIG_LEN, SK_LEN};
+use fips204::traits::{SerDes, Signer, Verifier};
+
+/// Context string bound into every ML-DSA signature (FIPS 204 §5.2)
+const CONTEXT: &[u8] = b"";
+
+/// ML-DSA-44 (FIPS 204) signatures
+pub struct MlDsaCrypto;
+
+impl CryptoProvider for MlDsaCrypto {
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let sk_bytes: [u8; SK_LEN] = private_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPrivateKey)?;
+        let sk =
+            PrivateKey::try_from_bytes(sk_bytes).map_err(|_| CryptoError::InvalidPrivateKey)?;
+        let sig = sk
+            .try_sign(message, CONTEXT)
+            .map_err(|_| CryptoError::SigningFailed)?;
+        Ok(sig.to_vec())
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
+        let pk_bytes: [u8; PK_LEN] = public_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        let pk = PublicKey::try_from_bytes(pk_bytes).map_err(|_| CryptoError::InvalidPublicKey)?;
+        let sig: [u8; SIG_LEN] = signature
+            .try_into()
+            .map_err(|_| CryptoError::InvalidSignature)?;
+        Ok(pk.verify(message, &sig, CONTEXT))
+    }
+
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        let pk_bytes: [u8; PK_LEN] = public_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        PublicKey::try_from_bytes(pk_bytes).map_err(|_| CryptoError::InvalidPublicKey)?;
+        Ok(())
+    }
+
+    fn encryption_type(&self) -> EncryptionType {
+        EncryptionType::MLDSA
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use fips204::ml_dsa_44::KG;
+    use fips204::traits::KeyGen;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(data);
+        hasher.result_str()
+    }
+
+    /// NIST ACVP ML-DSA-keyGen-FIPS204, ML-DSA-44 tgId 1 tcId 1
+    #[test]
+    fn test_keygen_known_answer() {
+        let seed: [u8; 32] = [
+            0x93, 0xef, 0x2e, 0x6e, 0xf1, 0xfb, 0x08, 0x99, 0x9d, 0x14, 0x2a, 0xbe, 0x02, 0x95,
+            0x48, 0x23, 0x70, 0xd3, 0xf4, 0x3b, 0xdb, 0x25, 0x4a, 0x78, 0xe2, 0xb0, 0xd5, 0x16,
+            0x8e, 0xca, 0x06, 0x5f,
+        ];
+        let (pk, sk) = KG::keygen_from_seed(&seed);
+        assert_eq!(
+            sha256_hex(&pk.into_bytes()),
+            "6995b20ecd5cde41719035028a712ccf35b1adf53b913030423d9d6fa188d673"
+        );
+        assert_eq!(
+            sha256_hex(&sk.into_bytes()),
+            "16a35d4b59f932aeada987dc689b075add0df57b4815bb103be7443ee3c1c561"
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (pk, sk) = KG::keygen_from_seed(&[0x42; 32]);
+        let pk = pk.into_bytes();
+        let sk = sk.into_bytes();
+
+        let sig = MlDsaCrypto.sign(&sk, b"message").unwrap();
+        assert_eq!(sig.len(), SIG_LEN);
+        assert_eq!(MlDsaCrypto.verify(&pk, b"message", &sig), Ok(true));
+        assert_eq!(MlDsaCrypto.verify(&pk, b"massage", &sig), Ok(false));
+        assert_eq!(
+            MlDsaCrypto.verify(&pk, b"message", &sig[1..]),
+            Err(CryptoError::InvalidSignature)
+        );
+    }
+}
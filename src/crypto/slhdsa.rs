@@ -0,0 +1,113 @@
+use super::traits::{CryptoError, CryptoProvider};
+use super::types::EncryptionType;
+use fips205::slh_dsa_sha2_128s::{PrivateKey, PublicKey, PK_LEN, SIG_LEN, SK_LEN};
+use fips205::traits::{SerDes, Signer, Verifier};
+
+/// Context string bound into every SLH-DSA signature (FIPS 205 §10.2)
+const CONTEXT: &[u8] = b"";
+
+/// SLH-DSA-SHA2-128s (FIPS 205) stateless hash-based signatures
+///
+/// Signatures are large (7856 bytes) and slow to produce, but security rests
+/// only on the hash function, which makes it suited to long-term cold storage.
+pub struct SlhDsaCrypto;
+
+impl CryptoProvider for SlhDsaCrypto {
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let sk_bytes: [u8; SK_LEN] = private_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPrivateKey)?;
+        let sk =
+            PrivateKey::try_from_bytes(&sk_bytes).map_err(|_| CryptoError::InvalidPrivateKey)?;
+        let sig = sk
+            .try_sign(message, CONTEXT, true)
+            .map_err(|_| CryptoError::SigningFailed)?;
+        Ok(sig.to_vec())
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
+        let pk_bytes: [u8; PK_LEN] = public_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        let pk = PublicKey::try_from_bytes(&pk_bytes).map_err(|_| CryptoError::InvalidPublicKey)?;
+        let sig: [u8; SIG_LEN] = signature
+            .try_into()
+            .map_err(|_| CryptoError::InvalidSignature)?;
+        Ok(pk.verify(message, &sig, CONTEXT))
+    }
+
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        let pk_bytes: [u8; PK_LEN] = public_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        PublicKey::try_from_bytes(&pk_bytes).map_err(|_| CryptoError::InvalidPublicKey)?;
+        Ok(())
+    }
+
+    fn encryption_type(&self) -> EncryptionType {
+        EncryptionType::SLHDSA
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use fips205::slh_dsa_sha2_128s::KG;
+    use fips205::traits::KeyGen;
+
+    /// NIST ACVP SLH-DSA-keyGen-FIPS205, SLH-DSA-SHA2-128s tgId 1 tcId 1
+    #[test]
+    fn test_keygen_known_answer() {
+        let sk_seed = [
+            0x2f, 0x89, 0x6d, 0x61, 0xd9, 0xcd, 0x90, 0x38, 0xca, 0x30, 0x33, 0x94, 0xfa, 0xda,
+            0xa2, 0x2a,
+        ];
+        let sk_prf = [
+            0x24, 0xac, 0x5e, 0xc1, 0xd8, 0x6a, 0x98, 0x9c, 0xa2, 0x19, 0x6c, 0x3c, 0x86, 0x32,
+            0x41, 0x9c,
+        ];
+        let pk_seed = [
+            0x1a, 0x05, 0xa4, 0x2f, 0xe3, 0x00, 0xe8, 0x7b, 0x16, 0xae, 0xe1, 0x16, 0xcb, 0x2e,
+            0x23, 0x63,
+        ];
+        let (pk, sk) = KG::keygen_with_seeds(&sk_seed, &sk_prf, &pk_seed);
+        assert_eq!(
+            pk.into_bytes(),
+            [
+                0x1a, 0x05, 0xa4, 0x2f, 0xe3, 0x00, 0xe8, 0x7b, 0x16, 0xae, 0xe1, 0x16, 0xcb, 0x2e,
+                0x23, 0x63, 0x58, 0xe2, 0xc3, 0xe6, 0x26, 0x32, 0xc9, 0xde, 0x03, 0xd0, 0x8a, 0x53,
+                0x5a, 0x0e, 0xb7, 0xe7,
+            ]
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.input(&sk.into_bytes());
+        assert_eq!(
+            hasher.result_str(),
+            "aade2c5e40baa0e4dbc4633425a0407873fe79250c46722622d4d979ba0a8c45"
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (pk, sk) = KG::keygen_with_seeds(&[1; 16], &[2; 16], &[3; 16]);
+        let pk = pk.into_bytes();
+        let sk = sk.into_bytes();
+
+        let sig = SlhDsaCrypto.sign(&sk, b"message").unwrap();
+        assert_eq!(sig.len(), SIG_LEN);
+        assert_eq!(SlhDsaCrypto.verify(&pk, b"message", &sig), Ok(true));
+        assert_eq!(SlhDsaCrypto.verify(&pk, b"massage", &sig), Ok(false));
+        assert_eq!(
+            SlhDsaCrypto.verify(&pk, b"message", &sig[1..]),
+            Err(CryptoError::InvalidSignature)
+        );
+    }
+}
@@ -1,4 +1,107 @@
+use super::types::EncryptionType;
+use std::fmt;
+
+/// CryptoError describes why a key or signature could not be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    InvalidPrivateKey,
+    InvalidPublicKey,
+    InvalidSignature,
+    SigningFailed,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            CryptoError::InvalidPrivateKey => "invalid private key",
+            CryptoError::InvalidPublicKey => "invalid public key",
+            CryptoError::InvalidSignature => "malformed signature",
+            CryptoError::SigningFailed => "signing failed",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// CryptoProvider signs and verifies messages with one signature scheme
+///
+/// Keys and signatures may come from untrusted peers, so malformed input is
+/// reported as a `CryptoError` rather than a panic. `verify` returns
+/// `Ok(false)` for a well-formed signature that does not match.
 pub trait CryptoProvider {
-    fn sign(&self, private_key: &[u8], message: &[u8]) -> Vec<u8>;
-    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+    fn sign(&self, private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError>;
+    fn verify(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, CryptoError>;
+    /// ValidatePublicKey checks that a public key decodes under this scheme
+    fn validate_public_key(&self, public_key: &[u8]) -> Result<(), CryptoError>;
+    fn encryption_type(&self) -> EncryptionType;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL: [EncryptionType; 5] = [
+        EncryptionType::ECDSA,
+        EncryptionType::FNDSA,
+        EncryptionType::MLDSA,
+        EncryptionType::SLHDSA,
+        EncryptionType::HYBRID,
+    ];
+
+    #[test]
+    fn test_garbage_keys_are_rejected() {
+        for encryption in ALL {
+            let crypto = encryption.crypto_provider();
+            for key in [Vec::new(), vec![0xff; 7], vec![0xff; 4096]] {
+                assert_eq!(
+                    crypto.sign(&key, b"message"),
+                    Err(CryptoError::InvalidPrivateKey),
+                    "{:?}",
+                    encryption
+                );
+                assert_eq!(
+                    crypto.verify(&key, b"message", &[0; 64]),
+                    Err(CryptoError::InvalidPublicKey),
+                    "{:?}",
+                    encryption
+                );
+                assert_eq!(
+                    crypto.validate_public_key(&key),
+                    Err(CryptoError::InvalidPublicKey),
+                    "{:?}",
+                    encryption
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_garbage_signatures_are_rejected() {
+        let mut ws = crate::crypto::wallets::Wallets::new().unwrap();
+        for encryption in ALL {
+            let address = ws.create_wallet(encryption);
+            let w = ws.get_wallet(&address).unwrap();
+            let crypto = encryption.crypto_provider();
+            let size = encryption.signature_size();
+
+            for sig in [Vec::new(), vec![0xff; size - 1], vec![0xff; size + 1]] {
+                assert_eq!(
+                    crypto.verify(&w.public_key, b"message", &sig),
+                    Err(CryptoError::InvalidSignature),
+                    "{:?}",
+                    encryption
+                );
+            }
+
+            // Right length but meaningless: may fail to parse, must never verify
+            let verified = crypto.verify(&w.public_key, b"message", &vec![0xff; size]);
+            assert_ne!(verified, Ok(true), "{:?}", encryption);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use crate::blockchain::utxoset::*;
-use crate::crypto::traits::CryptoProvider;
-
+use crate::crypto::traits::{CryptoError, CryptoProvider};
+use crate::crypto::types::EncryptionType;
 use crate::crypto::wallets::*;
 use crate::Result;
 use bincode::serialize_into;
@@ -8,13 +8,13 @@ use bitcoincash_addr::Address;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use failure::format_err;
-use fn_dsa::{VerifyingKey, VerifyingKeyStandard, DOMAIN_NONE, HASH_ID_RAW};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::vec;
 
-const SUBSIDY: i32 = 10;
+/// Value a coinbase may create on top of the fees of its block
+pub const SUBSIDY: i32 = 10;
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -23,6 +23,8 @@ pub struct TXInput {
     pub vout: i32,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    /// Signature scheme the input was signed with; fixes the expected signature length
+    pub encryption: EncryptionType,
 }
 
 /// TXOutput represents a transaction output
@@ -32,10 +34,10 @@ pub struct TXOutput {
     pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
+// TXOutputs collects the unspent TXOutput of a transaction by output index
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
 }
 
 /// Transaction represents a Bitcoin transaction
@@ -82,6 +84,7 @@ impl Transaction {
                     vout: out,
                     signature: Vec::new(),
                     pub_key: wallet.public_key.clone(),
+                    encryption: crypto.encryption_type(),
                 };
                 vin.push(input);
             }
@@ -106,10 +109,9 @@ impl Transaction {
     /// NewCoinbaseTX creates a new coinbase transaction
     pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
         info!("new coinbase Transaction to: {}", to);
-        let mut key: [u8; 32] = [0; 32];
+        // Random bytes keep two rewards to the same address from sharing an id
+        let key: [u8; 32] = rand::thread_rng().gen();
         if data.is_empty() {
-            let mut rand = rand::thread_rng();
-            key = rand.gen();
             data = format!("Reward to '{}'", to);
         }
         let mut pub_key = Vec::from(data.as_bytes());
@@ -122,6 +124,7 @@ impl Transaction {
                 vout: -1,
                 signature: Vec::new(),
                 pub_key,
+                encryption: EncryptionType::default(),
             }],
             vout: vec![TXOutput::new(SUBSIDY, to)?],
         };
@@ -134,46 +137,73 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
+    /// RollExtraNonce changes a coinbase, and so its id, without changing its value
+    ///
+    /// The last eight bytes of the coinbase input count as a little-endian
+    /// extra nonce, which miners increment once a block's nonces run out.
+    pub fn roll_extra_nonce(&mut self) -> Result<()> {
+        if !self.is_coinbase() {
+            return Err(format_err!("only a coinbase carries an extra nonce"));
+        }
+        let pub_key = &mut self.vin[0].pub_key;
+        if pub_key.len() < 8 {
+            pub_key.resize(8, 0);
+        }
+        let tail = pub_key.len() - 8;
+        let mut extra_nonce = [0u8; 8];
+        extra_nonce.copy_from_slice(&pub_key[tail..]);
+        let extra_nonce = u64::from_le_bytes(extra_nonce).wrapping_add(1);
+        pub_key[tail..].copy_from_slice(&extra_nonce.to_le_bytes());
+        self.id = self.hash()?;
+        Ok(())
+    }
+
     /// Verify verifies signatures of Transaction inputs
     pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        let prev_outs = self
+            .vin
+            .iter()
+            .map(|vin| prev_output(&prev_TXs, vin).cloned())
+            .collect::<Result<Vec<_>>>()?;
+        self.verify_spent(&prev_outs)
+    }
+
+    /// VerifySpent verifies input signatures against the outputs the inputs spend, in input order
+    pub fn verify_spent(&self, prev_outs: &[TXOutput]) -> Result<bool> {
+        if self.is_coinbase() {
+            return Ok(true);
+        }
+        if prev_outs.len() != self.vin.len() {
+            return Err(format_err!(
+                "ERROR: {} inputs but {} spent outputs",
+                self.vin.len(),
+                prev_outs.len()
+            ));
         }
 
         let mut tx_copy = self.trim_copy();
 
-        for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+        for (in_id, prev_out) in prev_outs.iter().enumerate() {
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
-            // if !ed25519::verify(
-            //     &tx_copy.id.as_bytes(), // message
-            //     &self.vin[in_id].pub_key, // public key
-            //     &self.vin[in_id].signature, // signature
-            // ) {
-            //     return Ok(false);
-            // }
-
-            if !VerifyingKeyStandard::decode(&self.vin[in_id].pub_key)
-                .unwrap()
-                .verify(
-                    &self.vin[in_id].signature,
-                    &DOMAIN_NONE,
-                    &HASH_ID_RAW,
-                    tx_copy.id.as_bytes(),
-                )
-            {
+            let encryption = self.vin[in_id].encryption;
+            if self.vin[in_id].signature.len() != encryption.signature_size() {
+                return Err(CryptoError::InvalidSignature.into());
+            }
+
+            let crypto = encryption.crypto_provider();
+            if !crypto.verify(
+                &self.vin[in_id].pub_key,
+                tx_copy.id.as_bytes(),
+                &self.vin[in_id].signature,
+            )? {
                 return Ok(false);
             }
         }
@@ -193,24 +223,26 @@ impl Transaction {
         }
 
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+            prev_output(&prev_TXs, vin)?;
+            if vin.encryption != crypto.encryption_type() {
+                return Err(format_err!(
+                    "ERROR: Input expects {:?} signature but signer is {:?}",
+                    vin.encryption,
+                    crypto.encryption_type()
+                ));
             }
         }
 
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
+            let prev_out = prev_output(&prev_TXs, &self.vin[in_id])?;
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
-            // let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            let signature = crypto.sign(private_key, tx_copy.id.as_bytes());
-            self.vin[in_id].signature = signature.to_vec();
+            let signature = crypto.sign(private_key, tx_copy.id.as_bytes())?;
+            self.vin[in_id].signature = signature;
         }
 
         Ok(())
@@ -227,6 +259,17 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    /// UnsignedHash returns the hash a Transaction's id must equal
+    ///
+    /// Ids are assigned before inputs are signed, so signatures are left out.
+    pub fn unsigned_hash(&self) -> Result<String> {
+        let mut tx = self.clone();
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+        }
+        tx.hash()
+    }
+
     /// TrimmedCopy creates a trimmed copy of Transaction to be used in signing
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::with_capacity(self.vin.len());
@@ -238,6 +281,7 @@ impl Transaction {
                 vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
+                encryption: v.encryption,
             })
         }
 
@@ -256,6 +300,21 @@ impl Transaction {
     }
 }
 
+/// PrevOutput looks up the output an input spends, rejecting dangling references
+fn prev_output<'a>(
+    prev_TXs: &'a HashMap<String, Transaction>,
+    vin: &TXInput,
+) -> Result<&'a TXOutput> {
+    let prev_Tx = match prev_TXs.get(&vin.txid) {
+        Some(tx) if !tx.id.is_empty() => tx,
+        _ => return Err(format_err!("ERROR: Previous transaction is not correct")),
+    };
+    usize::try_from(vin.vout)
+        .ok()
+        .and_then(|idx| prev_Tx.vout.get(idx))
+        .ok_or_else(|| format_err!("ERROR: Output {}:{} does not exist", vin.txid, vin.vout))
+}
+
 impl TXOutput {
     /// IsLockedWithKey checks if the output can be used by the owner of the pubkey
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -263,7 +322,9 @@ impl TXOutput {
     }
     /// Lock signs the output
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = Address::decode(address)
+            .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?
+            .body;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
@@ -281,9 +342,12 @@ impl TXOutput {
 
 #[cfg(test)]
 mod test {
-    use crate::crypto::types::EncryptionType;
-
     use super::*;
+    use crate::crypto::ecdsa::EcdsaCrypto;
+    use crate::crypto::fndsa::FnDsaCrypto;
+    use crate::crypto::hybrid::HybridCrypto;
+    use crate::crypto::mldsa::MlDsaCrypto;
+    use crate::crypto::slhdsa::SlhDsaCrypto;
     use fn_dsa::{
         signature_size, SigningKey, SigningKeyStandard, VerifyingKey, VerifyingKeyStandard,
         DOMAIN_NONE, HASH_ID_RAW,
@@ -320,4 +384,118 @@ mod test {
             tx.id.as_bytes()
         ));
     }
+
+    /// Builds a transaction spending the coinbase output owned by `w`
+    fn spend_coinbase(
+        w: &Wallet,
+        encryption: EncryptionType,
+    ) -> (Transaction, HashMap<String, Transaction>) {
+        let prev = Transaction::new_coinbase(w.get_address(), String::new()).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: w.public_key.clone(),
+                encryption,
+            }],
+            vout: vec![TXOutput::new(SUBSIDY, w.get_address()).unwrap()],
+        };
+        tx.id = tx.hash().unwrap();
+        let mut prev_TXs = HashMap::new();
+        prev_TXs.insert(prev.id.clone(), prev);
+        (tx, prev_TXs)
+    }
+
+    #[test]
+    fn test_verify_dispatches_on_encryption() {
+        let mut ws = Wallets::new().unwrap();
+        let ecdsa = ws.create_wallet(EncryptionType::ECDSA);
+        let fndsa = ws.create_wallet(EncryptionType::FNDSA);
+        let mldsa = ws.create_wallet(EncryptionType::MLDSA);
+        let slhdsa = ws.create_wallet(EncryptionType::SLHDSA);
+        let hybrid = ws.create_wallet(EncryptionType::HYBRID);
+
+        let w = ws.get_wallet(&ecdsa).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::ECDSA);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &EcdsaCrypto)
+            .unwrap();
+        assert!(tx.verify(prev_TXs).unwrap());
+
+        let w = ws.get_wallet(&mldsa).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::MLDSA);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &MlDsaCrypto)
+            .unwrap();
+        assert!(tx.verify(prev_TXs).unwrap());
+
+        let w = ws.get_wallet(&slhdsa).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::SLHDSA);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &SlhDsaCrypto)
+            .unwrap();
+        assert_eq!(
+            tx.vin[0].signature.len(),
+            EncryptionType::SLHDSA.signature_size()
+        );
+        assert!(tx.verify(prev_TXs).unwrap());
+
+        let w = ws.get_wallet(&hybrid).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::HYBRID);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &HybridCrypto)
+            .unwrap();
+        assert!(tx.verify(prev_TXs).unwrap());
+
+        let w = ws.get_wallet(&fndsa).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::FNDSA);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &FnDsaCrypto)
+            .unwrap();
+        assert!(tx.verify(prev_TXs.clone()).unwrap());
+
+        let mut truncated = tx.clone();
+        truncated.vin[0].signature.pop();
+        assert!(truncated.verify(prev_TXs.clone()).is_err());
+
+        tx.vin[0].signature[0] ^= 0xff;
+        assert!(!tx.verify(prev_TXs).unwrap());
+    }
+
+    #[test]
+    fn test_verify_rejects_garbage_inputs() {
+        let mut ws = Wallets::new().unwrap();
+        let ecdsa = ws.create_wallet(EncryptionType::ECDSA);
+        let w = ws.get_wallet(&ecdsa).unwrap();
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::ECDSA);
+        tx.sign(&w.secret_key, prev_TXs.clone(), &EcdsaCrypto)
+            .unwrap();
+
+        let mut bad_key = tx.clone();
+        bad_key.vin[0].pub_key = vec![0xff; 33];
+        assert!(bad_key.verify(prev_TXs.clone()).is_err());
+
+        // A garbage FN-DSA key in an input claiming FN-DSA must not panic
+        let mut bad_scheme = tx.clone();
+        bad_scheme.vin[0].encryption = EncryptionType::FNDSA;
+        bad_scheme.vin[0].signature = vec![0; EncryptionType::FNDSA.signature_size()];
+        assert!(bad_scheme.verify(prev_TXs.clone()).is_err());
+
+        let mut bad_vout = tx.clone();
+        bad_vout.vin[0].vout = 7;
+        assert!(bad_vout.verify(prev_TXs.clone()).is_err());
+
+        let mut bad_txid = tx.clone();
+        bad_txid.vin[0].txid = String::from("missing");
+        assert!(bad_txid.verify(prev_TXs.clone()).is_err());
+
+        assert!(tx.sign(&[0xff; 5], prev_TXs, &EcdsaCrypto).is_err());
+    }
+
+    #[test]
+    fn test_sign_rejects_mismatched_provider() {
+        let mut ws = Wallets::new().unwrap();
+        let ecdsa = ws.create_wallet(EncryptionType::ECDSA);
+        let w = ws.get_wallet(&ecdsa).unwrap();
+
+        let (mut tx, prev_TXs) = spend_coinbase(w, EncryptionType::ECDSA);
+        assert!(tx.sign(&w.secret_key, prev_TXs, &FnDsaCrypto).is_err());
+    }
 }
@@ -1,9 +1,50 @@
+use super::ecdsa::EcdsaCrypto;
+use super::fndsa::FnDsaCrypto;
+use super::hybrid::{self, HybridCrypto};
+use super::mldsa::MlDsaCrypto;
+use super::slhdsa::SlhDsaCrypto;
+use super::traits::CryptoProvider;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum EncryptionType {
     ECDSA,
+    #[default]
     FNDSA,
+    MLDSA,
+    SLHDSA,
+    /// secp256k1 and FN-DSA keys, both of which must sign
+    HYBRID,
+}
+
+impl EncryptionType {
+    /// CryptoProvider returns the provider implementing this signature scheme
+    pub fn crypto_provider(&self) -> Box<dyn CryptoProvider> {
+        match self {
+            EncryptionType::ECDSA => Box::new(EcdsaCrypto),
+            EncryptionType::FNDSA => Box::new(FnDsaCrypto),
+            EncryptionType::MLDSA => Box::new(MlDsaCrypto),
+            EncryptionType::SLHDSA => Box::new(SlhDsaCrypto),
+            EncryptionType::HYBRID => Box::new(HybridCrypto),
+        }
+    }
+
+    /// SignatureSize returns the encoded signature length produced by this scheme
+    pub fn signature_size(&self) -> usize {
+        match self {
+            EncryptionType::ECDSA => secp256k1::constants::COMPACT_SIGNATURE_SIZE,
+            EncryptionType::FNDSA => fn_dsa::signature_size(fn_dsa::FN_DSA_LOGN_512),
+            EncryptionType::MLDSA => fips204::ml_dsa_44::SIG_LEN,
+            EncryptionType::SLHDSA => fips205::slh_dsa_sha2_128s::SIG_LEN,
+            EncryptionType::HYBRID => hybrid::SIGNATURE_LEN,
+        }
+    }
 }
 
 pub enum DecryptionType {
     ECDSA,
     FNDSA,
+    MLDSA,
+    SLHDSA,
+    HYBRID,
 }
@@ -0,0 +1,312 @@
+//! Portable wallet file format
+//!
+//! A wallet file is a UTF-8 JSON object. Byte strings are lowercase hex.
+//!
+//! ```json
+//! {
+//!   "format": "polytorus-wallet",
+//!   "version": 1,
+//!   "address": "<base58 address>",
+//!   "encryption": "ECDSA" | "FNDSA" | "MLDSA" | "SLHDSA" | "HYBRID",
+//!   "public_key": "<hex>",
+//!   "secret_key": "<hex>",
+//!   "sealed": {
+//!     "kdf": "argon2id",
+//!     "m_cost": 19456,
+//!     "t_cost": 2,
+//!     "p_cost": 1,
+//!     "salt": "<hex>",
+//!     "cipher": "xchacha20poly1305",
+//!     "nonce": "<hex>",
+//!     "ciphertext": "<hex>"
+//!   }
+//! }
+//! ```
+//!
+//! At most one of `secret_key` (plaintext) and `sealed` (passphrase
+//! protected) is present; a file with neither imports as watch-only. Sealed
+//! keys use an Argon2id (v0x13, 32-byte output) key over the passphrase and
+//! `salt`, and authenticate the bincode encoding of `(public_key, encryption)`
+//! as associated data. The `address` must be the address of `public_key`.
+//!
+//! Readers reject any other `format` and any `version` they do not know.
+
+use super::types::EncryptionType;
+use super::wallets::{address_of, SealedKey, Wallet};
+use crate::Result;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+/// Value of the `format` field
+pub const FORMAT: &str = "polytorus-wallet";
+/// Newest `version` this build reads and the one it writes
+pub const VERSION: u32 = 1;
+
+const KDF: &str = "argon2id";
+const CIPHER: &str = "xchacha20poly1305";
+
+/// Largest Argon2 memory cost accepted from a file (in KiB), 1 GiB
+const MAX_M_COST: u32 = 1024 * 1024;
+/// Largest Argon2 time cost accepted from a file
+const MAX_T_COST: u32 = 10;
+/// Largest Argon2 parallelism accepted from a file
+const MAX_P_COST: u32 = 16;
+/// Shortest salt accepted from a file (in bytes)
+const MIN_SALT_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletFile {
+    pub format: String,
+    pub version: u32,
+    pub address: String,
+    pub encryption: EncryptionType,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "hex_opt")]
+    pub secret_key: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedKeyFile>,
+}
+
+/// SealedKeyFile is the portable form of a passphrase-protected secret key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SealedKeyFile {
+    pub kdf: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    pub cipher: String,
+    #[serde(with = "hex")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "hex")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl From<&SealedKey> for SealedKeyFile {
+    fn from(sealed: &SealedKey) -> Self {
+        SealedKeyFile {
+            kdf: KDF.to_string(),
+            m_cost: sealed.m_cost,
+            t_cost: sealed.t_cost,
+            p_cost: sealed.p_cost,
+            salt: sealed.salt.clone(),
+            cipher: CIPHER.to_string(),
+            nonce: sealed.nonce.clone(),
+            ciphertext: sealed.ciphertext.clone(),
+        }
+    }
+}
+
+impl TryFrom<&SealedKeyFile> for SealedKey {
+    type Error = failure::Error;
+
+    fn try_from(file: &SealedKeyFile) -> Result<Self> {
+        if file.kdf != KDF || file.cipher != CIPHER {
+            return Err(format_err!(
+                "unsupported key protection: {} with {}",
+                file.kdf,
+                file.cipher
+            ));
+        }
+        // Unlocking runs the key derivation as the file describes it, so
+        // costs that would exhaust memory or never finish are refused here
+        if file.m_cost > MAX_M_COST || file.t_cost > MAX_T_COST || file.p_cost > MAX_P_COST {
+            return Err(format_err!(
+                "key derivation costs out of range: m={}, t={}, p={}",
+                file.m_cost,
+                file.t_cost,
+                file.p_cost
+            ));
+        }
+        if file.salt.len() < MIN_SALT_LEN {
+            return Err(format_err!("salt shorter than {} bytes", MIN_SALT_LEN));
+        }
+        Ok(SealedKey {
+            salt: file.salt.clone(),
+            m_cost: file.m_cost,
+            t_cost: file.t_cost,
+            p_cost: file.p_cost,
+            nonce: file.nonce.clone(),
+            ciphertext: file.ciphertext.clone(),
+        })
+    }
+}
+
+impl WalletFile {
+    /// Export describes a wallet; `watch_only` leaves the secret key out
+    ///
+    /// Encrypted wallets are exported sealed, never in plaintext.
+    pub fn export(wallet: &Wallet, watch_only: bool) -> WalletFile {
+        let (secret_key, sealed) = match &wallet.sealed {
+            _ if watch_only => (None, None),
+            Some(sealed) => (None, Some(sealed.into())),
+            None => (Some(wallet.secret_key.clone()), None),
+        };
+        WalletFile {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            address: wallet.get_address(),
+            encryption: wallet.encryption,
+            public_key: wallet.public_key.clone(),
+            secret_key,
+            sealed,
+        }
+    }
+
+    /// ToJson encodes the file
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// FromJson decodes and checks a file
+    pub fn from_json(json: &str) -> Result<WalletFile> {
+        let file: WalletFile = serde_json::from_str(json)?;
+        if file.format != FORMAT {
+            return Err(format_err!("not a wallet file: {}", file.format));
+        }
+        if file.version == 0 || file.version > VERSION {
+            return Err(format_err!(
+                "unsupported wallet file version: {}",
+                file.version
+            ));
+        }
+        if address_of(&file.public_key) != file.address {
+            return Err(format_err!("address does not match the public key"));
+        }
+        Ok(file)
+    }
+
+    /// IsWatchOnly reports whether the file carries no secret key
+    pub fn is_watch_only(&self) -> bool {
+        self.secret_key.is_none() && self.sealed.is_none()
+    }
+
+    /// IntoWallet returns the wallet the file describes
+    ///
+    /// A plaintext secret key must sign for the public key; a sealed one is
+    /// bound to it by the cipher and checked when the wallet is unlocked.
+    pub fn into_wallet(self) -> Result<Wallet> {
+        let mut wallet = Wallet {
+            secret_key: Vec::new(),
+            public_key: self.public_key,
+            encryption: self.encryption,
+            sealed: None,
+        };
+        match (self.secret_key, &self.sealed) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!("wallet file has both a plain and a sealed key"))
+            }
+            (Some(secret_key), None) => {
+                let crypto = wallet.crypto_provider();
+                let signature = crypto.sign(&secret_key, wallet.public_key.as_slice())?;
+                if !crypto.verify(&wallet.public_key, &wallet.public_key, &signature)? {
+                    return Err(format_err!("secret key does not match the public key"));
+                }
+                wallet.secret_key = secret_key;
+            }
+            (None, Some(sealed)) => wallet.sealed = Some(sealed.try_into()?),
+            (None, None) => return Err(format_err!("wallet file holds no secret key")),
+        }
+        Ok(wallet)
+    }
+}
+
+/// Hex encoding for optional byte strings
+mod hex_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => s.serialize_str(&hex::encode(bytes)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| hex::decode(s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::wallets::Wallets;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let mut ws = Wallets::new().unwrap();
+        let address = ws.create_wallet(EncryptionType::ECDSA);
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+
+        let json = WalletFile::export(&wallet, false).to_json().unwrap();
+        let file = WalletFile::from_json(&json).unwrap();
+        assert_eq!(file.address, address);
+        assert_eq!(file.into_wallet().unwrap(), wallet);
+
+        let json = WalletFile::export(&wallet, true).to_json().unwrap();
+        assert!(!json.contains("secret_key"));
+        let file = WalletFile::from_json(&json).unwrap();
+        assert!(file.is_watch_only());
+        assert!(file.into_wallet().is_err());
+    }
+
+    #[test]
+    fn test_export_keeps_encrypted_keys_sealed() {
+        let mut wallet = Wallet::default();
+        let secret_key = wallet.secret_key.clone();
+        wallet.encrypt("passphrase").unwrap();
+
+        let json = WalletFile::export(&wallet, false).to_json().unwrap();
+        assert!(!json.contains(&hex::encode(&secret_key)));
+
+        let mut imported = WalletFile::from_json(&json).unwrap().into_wallet().unwrap();
+        assert!(imported.is_locked());
+        assert!(imported.unlock("wrong").is_err());
+        imported.unlock("passphrase").unwrap();
+        assert_eq!(imported.secret_key, secret_key);
+    }
+
+    #[test]
+    fn test_import_rejects_bad_files() {
+        let wallet = Wallet::default();
+        let other = Wallet::default();
+        let file = WalletFile::export(&wallet, false);
+
+        let mut bad = file.clone();
+        bad.version = VERSION + 1;
+        assert!(WalletFile::from_json(&bad.to_json().unwrap()).is_err());
+
+        let mut bad = file.clone();
+        bad.format = String::from("something-else");
+        assert!(WalletFile::from_json(&bad.to_json().unwrap()).is_err());
+
+        let mut bad = file.clone();
+        bad.address = other.get_address();
+        assert!(WalletFile::from_json(&bad.to_json().unwrap()).is_err());
+
+        let mut bad = file.clone();
+        bad.secret_key = Some(other.secret_key.clone());
+        assert!(bad.into_wallet().is_err());
+
+        assert!(WalletFile::from_json("{\"format\": \"polytorus-wallet\"}").is_err());
+
+        // Key derivation costs come from the file and must stay bounded
+        let mut sealed = other.clone();
+        sealed.encrypt("passphrase").unwrap();
+        let file = WalletFile::export(&sealed, false);
+        assert!(file.clone().into_wallet().is_ok());
+        let mut bad = file.clone();
+        bad.sealed.as_mut().unwrap().m_cost = u32::MAX;
+        assert!(bad.into_wallet().is_err());
+        let mut bad = file.clone();
+        bad.sealed.as_mut().unwrap().t_cost = u32::MAX;
+        assert!(bad.into_wallet().is_err());
+        let mut bad = file;
+        bad.sealed.as_mut().unwrap().salt.truncate(8);
+        assert!(bad.into_wallet().is_err());
+    }
+}
@@ -1,63 +1,315 @@
+use super::hd::{self, CHILD_SEED_LEN};
+use super::traits::CryptoProvider;
 use super::types::*;
+use super::walletfile::WalletFile;
 use crate::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
 use bincode::{deserialize, serialize};
 use bitcoincash_addr::*;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng as AeadRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use crypto::digest::Digest;
 use crypto::ripemd160::Ripemd160;
 use crypto::sha2::Sha256;
+use failure::format_err;
+use fips204::ml_dsa_44;
+use fips204::traits::{KeyGen as _, SerDes as _};
+use fips205::slh_dsa_sha2_128s;
+use fips205::traits::{KeyGen as _, SerDes as _};
 use fn_dsa::{
     sign_key_size, vrfy_key_size, KeyPairGenerator, KeyPairGeneratorStandard, FN_DSA_LOGN_512,
 };
-use secp256k1::rand::rngs::OsRng;
-use secp256k1::Secp256k1;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sled;
 use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Secret keys of encrypted wallets unlocked in this process, by address;
+/// wiped from memory when removed
+static KEYRING: LazyLock<Mutex<HashMap<String, Zeroizing<Vec<u8>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
+    /// Empty while the wallet is encrypted and locked
     pub secret_key: Vec<u8>,
     pub public_key: Vec<u8>,
+    pub encryption: EncryptionType,
+    /// Passphrase-encrypted secret key; the only copy persisted when set
+    pub sealed: Option<SealedKey>,
+}
+
+impl Drop for Wallet {
+    /// Drop wipes the secret key, so no copy outlives its wallet in memory
+    fn drop(&mut self) {
+        self.secret_key.zeroize();
+    }
+}
+
+/// SealedKey is a secret key encrypted under a passphrase
+///
+/// The key encryption key is derived with Argon2id and the secret sealed with
+/// XChaCha20-Poly1305. The public key and scheme are authenticated alongside,
+/// so a sealed key copied into another record fails to open.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SealedKey {
+    pub(super) salt: Vec<u8>,
+    pub(super) m_cost: u32,
+    pub(super) t_cost: u32,
+    pub(super) p_cost: u32,
+    pub(super) nonce: Vec<u8>,
+    pub(super) ciphertext: Vec<u8>,
+}
+
+const SALT_LEN: usize = 16;
+
+/// Associated data of the sealed HD mnemonic
+const MNEMONIC_AAD: &[u8] = b"hd mnemonic";
+
+impl SealedKey {
+    fn seal(secret_key: &[u8], passphrase: &str, aad: &[u8]) -> Result<SealedKey> {
+        let params = Params::default();
+        let mut salt = [0u8; SALT_LEN];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut salt);
+        let mut sealed = SealedKey {
+            salt: salt.to_vec(),
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            nonce: Vec::new(),
+            ciphertext: Vec::new(),
+        };
+
+        let cipher = sealed.cipher(passphrase)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut AeadRng);
+        sealed.ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret_key,
+                    aad,
+                },
+            )
+            .map_err(|_| format_err!("failed to encrypt wallet"))?;
+        sealed.nonce = nonce.to_vec();
+        Ok(sealed)
+    }
+
+    fn open(&self, passphrase: &str, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        if self.nonce.len() != XNonce::default().len() {
+            return Err(format_err!("corrupted wallet record"));
+        }
+        self.cipher(passphrase)?
+            .decrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| format_err!("wrong passphrase or corrupted wallet"))
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<XChaCha20Poly1305> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| format_err!("invalid key derivation parameters: {}", e))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| format_err!("key derivation failed: {}", e))?;
+        Ok(XChaCha20Poly1305::new(key.as_ref().into()))
+    }
+}
+
+/// PlainWallet is the record layout stored before wallets could be encrypted
+#[derive(Deserialize)]
+struct PlainWallet {
+    secret_key: Vec<u8>,
+    public_key: Vec<u8>,
+    encryption: EncryptionType,
+}
+
+impl From<PlainWallet> for Wallet {
+    fn from(plain: PlainWallet) -> Self {
+        Wallet {
+            secret_key: plain.secret_key,
+            public_key: plain.public_key,
+            encryption: plain.encryption,
+            sealed: None,
+        }
+    }
+}
+
+/// LegacyWallet is the record layout stored before the key algorithm was persisted
+#[derive(Deserialize)]
+struct LegacyWallet {
+    secret_key: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl From<LegacyWallet> for Wallet {
+    fn from(legacy: LegacyWallet) -> Self {
+        // secp256k1 secret keys are 32 bytes, FN-DSA signing keys are far larger
+        let encryption = if legacy.secret_key.len() == 32 {
+            EncryptionType::ECDSA
+        } else {
+            EncryptionType::FNDSA
+        };
+        Wallet {
+            secret_key: legacy.secret_key,
+            public_key: legacy.public_key,
+            encryption,
+            sealed: None,
+        }
+    }
 }
 
 impl Wallet {
     /// NewWallet creates and returns a Wallet
     fn new(encryption: EncryptionType) -> Self {
-        match encryption {
+        let mut seed = [0u8; CHILD_SEED_LEN];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut seed);
+        Wallet::from_seed(encryption, &seed).expect("key generation failed")
+    }
+
+    /// FromSeed deterministically generates the keys of a Wallet
+    fn from_seed(encryption: EncryptionType, seed: &[u8; CHILD_SEED_LEN]) -> Result<Self> {
+        let key_seed: [u8; 32] = seed[..32].try_into().unwrap();
+        let (secret_key, public_key) = match encryption {
             EncryptionType::FNDSA => {
                 let mut kg = KeyPairGeneratorStandard::default();
                 let mut sign_key = [0u8; sign_key_size(FN_DSA_LOGN_512)];
                 let mut vrfy_key = [0u8; vrfy_key_size(FN_DSA_LOGN_512)];
-                kg.keygen(FN_DSA_LOGN_512, &mut OsRng, &mut sign_key, &mut vrfy_key);
-
-                Wallet {
-                    secret_key: sign_key.to_vec(),
-                    public_key: vrfy_key.to_vec(),
-                }
+                let mut rng = ChaCha20Rng::from_seed(key_seed);
+                kg.keygen(FN_DSA_LOGN_512, &mut rng, &mut sign_key, &mut vrfy_key);
+                (sign_key.to_vec(), vrfy_key.to_vec())
+            }
+            EncryptionType::MLDSA => {
+                let (public_key, secret_key) = ml_dsa_44::KG::keygen_from_seed(&key_seed);
+                (
+                    secret_key.into_bytes().to_vec(),
+                    public_key.into_bytes().to_vec(),
+                )
+            }
+            EncryptionType::SLHDSA => {
+                const N: usize = slh_dsa_sha2_128s::N;
+                let (public_key, secret_key) = slh_dsa_sha2_128s::KG::keygen_with_seeds::<N>(
+                    seed[0..N].try_into().unwrap(),
+                    seed[N..2 * N].try_into().unwrap(),
+                    seed[2 * N..3 * N].try_into().unwrap(),
+                );
+                (
+                    secret_key.into_bytes().to_vec(),
+                    public_key.into_bytes().to_vec(),
+                )
+            }
+            EncryptionType::HYBRID => {
+                // Each half is keyed from its own 32 bytes of the seed
+                let mut swapped = [0u8; CHILD_SEED_LEN];
+                swapped[..32].copy_from_slice(&seed[32..]);
+                swapped[32..].copy_from_slice(&seed[..32]);
+                let classic = Wallet::from_seed(EncryptionType::ECDSA, seed)?;
+                let post_quantum = Wallet::from_seed(EncryptionType::FNDSA, &swapped)?;
+                (
+                    [&classic.secret_key[..], &post_quantum.secret_key[..]].concat(),
+                    [&classic.public_key[..], &post_quantum.public_key[..]].concat(),
+                )
             }
             EncryptionType::ECDSA => {
-                let secp = Secp256k1::new();
-                let (secret_key, public_key) = secp.generate_keypair(&mut OsRng);
-
-                Wallet {
-                    secret_key: secret_key.secret_bytes().to_vec(),
-                    public_key: public_key.serialize().to_vec(),
-                }
+                let secp = Secp256k1::signing_only();
+                let secret_key = SecretKey::from_byte_array(&key_seed)?;
+                let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+                (
+                    secret_key.secret_bytes().to_vec(),
+                    public_key.serialize().to_vec(),
+                )
             }
-        }
+        };
+
+        Ok(Wallet {
+            secret_key,
+            public_key,
+            encryption,
+            sealed: None,
+        })
     }
 
     /// GetAddress returns wallet address
     pub fn get_address(&self) -> String {
-        let mut pub_hash: Vec<u8> = self.public_key.clone();
-        hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
+        address_of(&self.public_key)
+    }
+
+    /// CryptoProvider returns the provider matching the wallet's key algorithm
+    pub fn crypto_provider(&self) -> Box<dyn CryptoProvider> {
+        self.encryption.crypto_provider()
+    }
+
+    /// IsEncrypted reports whether the secret key is stored under a passphrase
+    pub fn is_encrypted(&self) -> bool {
+        self.sealed.is_some()
+    }
+
+    /// IsLocked reports whether the secret key is unavailable for signing
+    pub fn is_locked(&self) -> bool {
+        self.sealed.is_some() && self.secret_key.is_empty()
+    }
+
+    /// Encrypt seals the secret key under a passphrase, leaving it unlocked
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(format_err!("wallet is already encrypted"));
+        }
+        self.sealed = Some(SealedKey::seal(
+            &self.secret_key,
+            passphrase,
+            &self.associated_data()?,
+        )?);
+        Ok(())
+    }
+
+    /// Unlock decrypts the secret key, failing on a wrong passphrase or tampering
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        if let Some(sealed) = &self.sealed {
+            let mut secret_key = sealed.open(passphrase, &self.associated_data()?)?;
+            self.secret_key = std::mem::take(&mut *secret_key);
+        }
+        Ok(())
+    }
+
+    /// Lock wipes the decrypted secret key of an encrypted wallet
+    pub fn lock(&mut self) {
+        if self.is_encrypted() {
+            self.secret_key.zeroize();
+        }
+    }
+
+    /// ChangePassphrase re-seals the secret key under a new passphrase
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        let sealed = match &self.sealed {
+            Some(sealed) => sealed,
+            None => return Err(format_err!("wallet is not encrypted")),
         };
-        address.encode().unwrap()
+        let aad = self.associated_data()?;
+        let mut secret_key = sealed.open(old, &aad)?;
+        self.sealed = Some(SealedKey::seal(&secret_key, new, &aad)?);
+        self.secret_key = std::mem::take(&mut *secret_key);
+        Ok(())
+    }
+
+    fn associated_data(&self) -> Result<Vec<u8>> {
+        Ok(serialize(&(&self.public_key, self.encryption))?)
+    }
+
+    /// Record returns the wallet as persisted, without a plaintext key if sealed
+    fn record(&self) -> Wallet {
+        let mut record = self.clone();
+        record.lock();
+        record
     }
 }
 
@@ -67,6 +319,29 @@ impl Default for Wallet {
     }
 }
 
+/// AddressOf returns the address of a public key
+pub fn address_of(public_key: &[u8]) -> String {
+    let mut pub_hash: Vec<u8> = public_key.to_vec();
+    hash_pub_key(&mut pub_hash);
+    let address = Address {
+        body: pub_hash,
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    address.encode().unwrap()
+}
+
+/// WatchOnly is an address tracked without any secret key
+///
+/// The public key and scheme are known when imported from a key or a
+/// watch-only wallet file, but not when only the address was given.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchOnly {
+    pub public_key: Option<Vec<u8>>,
+    pub encryption: Option<EncryptionType>,
+}
+
 /// HashPubKey hashes public key
 pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
@@ -81,22 +356,75 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    /// Recovery phrase HD wallets are derived from, sealed under a passphrase
+    mnemonic: Option<SealedKey>,
+    /// Recovery phrase an older version stored in plaintext; sealed at its
+    /// first unlock
+    #[serde(skip)]
+    plain_mnemonic: Option<Zeroizing<String>>,
+    /// BIP-39 seed of the mnemonic, while unlocked
+    #[serde(skip)]
+    hd_seed: Option<Zeroizing<[u8; 64]>>,
+    /// Addresses tracked without keys
+    watch_only: HashMap<String, WatchOnly>,
 }
 
 impl Wallets {
     /// NewWallets creates Wallets and fills it from a file if it exists
+    ///
+    /// Records written in an older layout are migrated in place. Encrypted
+    /// wallets are locked unless they were unlocked earlier in this process,
+    /// and the HD mnemonic is always locked.
     pub fn new() -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            mnemonic: None,
+            plain_mnemonic: None,
+            hd_seed: None,
+            watch_only: HashMap::new(),
         };
         let db = sled::open("data/wallets")?;
+        let hd = db.open_tree("hd")?;
+        if let Some(sealed) = hd.get("sealed")? {
+            wlt.mnemonic = Some(deserialize(&sealed)?);
+        } else if let Some(mnemonic) = hd.get("mnemonic")? {
+            warn!("the HD mnemonic is stored in plaintext; unlock it to seal it");
+            wlt.plain_mnemonic = Some(Zeroizing::new(String::from_utf8(mnemonic.to_vec())?));
+        }
+        for item in db.open_tree("watch")?.iter() {
+            let (address, entry) = item?;
+            wlt.watch_only
+                .insert(String::from_utf8(address.to_vec())?, deserialize(&entry)?);
+        }
 
-        for item in db.into_iter() {
+        let mut migrated = Vec::new();
+        for item in db.iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = deserialize(&i.1)?;
+            let mut wallet = match deserialize::<Wallet>(&i.1) {
+                Ok(wallet) => wallet,
+                Err(_) => {
+                    let wallet: Wallet = match deserialize::<PlainWallet>(&i.1) {
+                        Ok(plain) => plain.into(),
+                        Err(_) => deserialize::<LegacyWallet>(&i.1)?.into(),
+                    };
+                    migrated.push(address.clone());
+                    wallet
+                }
+            };
+            if let Some(secret_key) = KEYRING.lock().unwrap().get(&address) {
+                wallet.secret_key = secret_key.to_vec();
+            }
             wlt.wallets.insert(address, wallet);
         }
+
+        for address in &migrated {
+            info!("migrate wallet record: {}", address);
+            db.insert(address, serialize(&wlt.wallets[address].record())?)?;
+        }
+        if !migrated.is_empty() {
+            db.flush()?;
+        }
         drop(db);
         Ok(wlt)
     }
@@ -110,6 +438,129 @@ impl Wallets {
         address
     }
 
+    /// HasMnemonic reports whether HD wallets are derived from a recovery phrase
+    pub fn has_mnemonic(&self) -> bool {
+        self.mnemonic.is_some() || self.plain_mnemonic.is_some()
+    }
+
+    /// IsMnemonicSealed reports whether the recovery phrase is kept under a
+    /// passphrase
+    pub fn is_mnemonic_sealed(&self) -> bool {
+        self.mnemonic.is_some()
+    }
+
+    /// InitMnemonic generates the recovery phrase for HD wallets and seals it
+    /// under a passphrase, leaving it unlocked
+    pub fn init_mnemonic(&mut self, passphrase: &str) -> Result<Zeroizing<String>> {
+        if self.has_mnemonic() {
+            return Err(format_err!("a mnemonic already exists"));
+        }
+        let mnemonic = Zeroizing::new(hd::new_mnemonic().to_string());
+        self.seal_mnemonic(&mnemonic, passphrase)?;
+        Ok(mnemonic)
+    }
+
+    /// RestoreMnemonic sets the recovery phrase HD wallets are derived from,
+    /// leaving it unlocked
+    ///
+    /// A new phrase is sealed under the passphrase; an existing one must open
+    /// with it. A different phrase is rejected, since wallets derived from the
+    /// current one could no longer be recovered from a single backup.
+    pub fn restore_mnemonic(&mut self, words: &str, passphrase: &str) -> Result<()> {
+        let mnemonic = Zeroizing::new(hd::parse_mnemonic(words)?.to_string());
+        if !self.has_mnemonic() {
+            return self.seal_mnemonic(&mnemonic, passphrase);
+        }
+        if *self.reveal_mnemonic(passphrase)? != *mnemonic {
+            return Err(format_err!("a different mnemonic already exists"));
+        }
+        Ok(())
+    }
+
+    /// RevealMnemonic opens the recovery phrase, leaving it unlocked
+    ///
+    /// A phrase an older version stored in plaintext is sealed under the
+    /// passphrase.
+    pub fn reveal_mnemonic(&mut self, passphrase: &str) -> Result<Zeroizing<String>> {
+        let mnemonic = match (&self.mnemonic, &self.plain_mnemonic) {
+            (Some(sealed), _) => {
+                let words = sealed.open(passphrase, MNEMONIC_AAD)?;
+                Zeroizing::new(String::from_utf8(words.to_vec())?)
+            }
+            (None, Some(plain)) => plain.clone(),
+            (None, None) => return Err(format_err!("no mnemonic; create or restore one first")),
+        };
+        if self.is_mnemonic_sealed() {
+            self.unlock_seed(&mnemonic)?;
+        } else {
+            self.seal_mnemonic(&mnemonic, passphrase)?;
+        }
+        Ok(mnemonic)
+    }
+
+    /// UnlockMnemonic opens the recovery phrase so HD wallets can be derived
+    pub fn unlock_mnemonic(&mut self, passphrase: &str) -> Result<()> {
+        self.reveal_mnemonic(passphrase).map(drop)
+    }
+
+    fn seal_mnemonic(&mut self, mnemonic: &str, passphrase: &str) -> Result<()> {
+        self.unlock_seed(mnemonic)?;
+        self.mnemonic = Some(SealedKey::seal(
+            mnemonic.as_bytes(),
+            passphrase,
+            MNEMONIC_AAD,
+        )?);
+        self.plain_mnemonic = None;
+        Ok(())
+    }
+
+    /// Derives the BIP-39 seed once, rather than for every wallet
+    fn unlock_seed(&mut self, mnemonic: &str) -> Result<()> {
+        let seed = hd::parse_mnemonic(mnemonic)?.to_seed("");
+        self.hd_seed = Some(Zeroizing::new(seed));
+        Ok(())
+    }
+
+    /// DeriveWallet adds the HD wallet at an account and index
+    pub fn derive_wallet(
+        &mut self,
+        encryption: EncryptionType,
+        account: u32,
+        index: u32,
+    ) -> Result<String> {
+        let wallet = self.hd_wallet(encryption, account, index)?;
+        let address = wallet.get_address();
+        // Keep an existing record, which may have been encrypted since
+        self.wallets.entry(address.clone()).or_insert(wallet);
+        Ok(address)
+    }
+
+    /// CreateHdWallet adds the first HD wallet of an account not yet in Wallets
+    pub fn create_hd_wallet(&mut self, encryption: EncryptionType, account: u32) -> Result<String> {
+        for index in 0.. {
+            let wallet = self.hd_wallet(encryption, account, index)?;
+            let address = wallet.get_address();
+            if !self.wallets.contains_key(&address) {
+                self.wallets.insert(address.clone(), wallet);
+                info!("create wallet: {} ({}/{})", address, account, index);
+                return Ok(address);
+            }
+        }
+        Err(format_err!("account {} has no free index", account))
+    }
+
+    fn hd_wallet(&self, encryption: EncryptionType, account: u32, index: u32) -> Result<Wallet> {
+        let seed = match &self.hd_seed {
+            Some(seed) => seed,
+            None if self.has_mnemonic() => {
+                return Err(format_err!("the mnemonic is locked; unlock it first"))
+            }
+            None => return Err(format_err!("no mnemonic; create or restore one first")),
+        };
+        let seed = Zeroizing::new(hd::child_seed(&seed[..], encryption, account, index));
+        Wallet::from_seed(encryption, &seed)
+    }
+
     /// GetAddresses returns an array of addresses stored in the wallet file
     pub fn get_all_addresses(&self) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
@@ -124,14 +575,190 @@ impl Wallets {
         self.wallets.get(address)
     }
 
-    /// SaveToFile saves wallets to a file
+    /// GetSigningWallet returns a Wallet whose secret key is available
+    pub fn get_signing_wallet(&self, address: &str) -> Result<&Wallet> {
+        match self.wallets.get(address) {
+            Some(wallet) if wallet.is_locked() => Err(format_err!("Wallet is locked: {}", address)),
+            Some(wallet) => Ok(wallet),
+            None => Err(format_err!("Wallet not found: {}", address)),
+        }
+    }
+
+    /// EncryptWallet seals a wallet under a passphrase and saves it
+    pub fn encrypt_wallet(&mut self, address: &str, passphrase: &str) -> Result<()> {
+        self.wallet_mut(address)?.encrypt(passphrase)?;
+        self.save_all()
+    }
+
+    /// UnlockWallet decrypts a wallet and keeps it unlocked for this process
+    pub fn unlock_wallet(&mut self, address: &str, passphrase: &str) -> Result<()> {
+        let wallet = self.wallet_mut(address)?;
+        wallet.unlock(passphrase)?;
+        if wallet.is_encrypted() {
+            KEYRING.lock().unwrap().insert(
+                address.to_string(),
+                Zeroizing::new(wallet.secret_key.clone()),
+            );
+        }
+        Ok(())
+    }
+
+    /// LockWallet wipes the decrypted key of a wallet
+    pub fn lock_wallet(&mut self, address: &str) -> Result<()> {
+        self.wallet_mut(address)?.lock();
+        if let Some(mut secret_key) = KEYRING.lock().unwrap().remove(address) {
+            secret_key.zeroize();
+        }
+        Ok(())
+    }
+
+    /// ChangePassphrase re-encrypts a wallet under a new passphrase and saves it
+    pub fn change_passphrase(&mut self, address: &str, old: &str, new: &str) -> Result<()> {
+        self.wallet_mut(address)?.change_passphrase(old, new)?;
+        self.save_all()
+    }
+
+    fn wallet_mut(&mut self, address: &str) -> Result<&mut Wallet> {
+        self.wallets
+            .get_mut(address)
+            .ok_or_else(|| format_err!("Wallet not found: {}", address))
+    }
+
+    /// ExportWallet writes a wallet or watch-only entry to the portable format
+    ///
+    /// A passphrase seals a plaintext key in the exported copy only; an
+    /// encrypted wallet is exported under its own passphrase.
+    pub fn export_wallet(
+        &self,
+        address: &str,
+        watch_only: bool,
+        passphrase: Option<&str>,
+    ) -> Result<WalletFile> {
+        if let Some(wallet) = self.wallets.get(address) {
+            let mut wallet = wallet.record();
+            if let Some(passphrase) = passphrase {
+                if !wallet.is_encrypted() {
+                    wallet.encrypt(passphrase)?;
+                }
+            }
+            return Ok(WalletFile::export(&wallet, watch_only));
+        }
+        match self.watch_only.get(address) {
+            Some(WatchOnly {
+                public_key: Some(public_key),
+                encryption: Some(encryption),
+            }) => Ok(WalletFile::export(
+                &Wallet {
+                    secret_key: Vec::new(),
+                    public_key: public_key.clone(),
+                    encryption: *encryption,
+                    sealed: None,
+                },
+                true,
+            )),
+            Some(_) => Err(format_err!("no public key is known for {}", address)),
+            None => Err(format_err!("Wallet not found: {}", address)),
+        }
+    }
+
+    /// ImportWallet adds the wallet or watch-only entry a file describes
+    pub fn import_wallet(&mut self, file: WalletFile) -> Result<String> {
+        let address = file.address.clone();
+        if self.wallets.contains_key(&address) {
+            return Err(format_err!("wallet already exists: {}", address));
+        }
+        if file.is_watch_only() {
+            return self.watch_public_key(file.public_key, file.encryption);
+        }
+        let wallet = file.into_wallet()?;
+        self.watch_only.remove(&address);
+        self.wallets.insert(address.clone(), wallet);
+        info!("import wallet: {}", address);
+        Ok(address)
+    }
+
+    /// WatchAddress tracks an address without knowing its key
+    pub fn watch_address(&mut self, address: &str) -> Result<()> {
+        Address::decode(address).map_err(|e| format_err!("invalid address: {:?}", e))?;
+        if !self.wallets.contains_key(address) && !self.watch_only.contains_key(address) {
+            self.watch_only.insert(
+                address.to_string(),
+                WatchOnly {
+                    public_key: None,
+                    encryption: None,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// WatchPublicKey tracks the address of a public key
+    pub fn watch_public_key(
+        &mut self,
+        public_key: Vec<u8>,
+        encryption: EncryptionType,
+    ) -> Result<String> {
+        if encryption
+            .crypto_provider()
+            .validate_public_key(&public_key)
+            .is_err()
+        {
+            return Err(format_err!("invalid {:?} public key", encryption));
+        }
+        let address = address_of(&public_key);
+        if !self.wallets.contains_key(&address) {
+            self.watch_only.insert(
+                address.clone(),
+                WatchOnly {
+                    public_key: Some(public_key),
+                    encryption: Some(encryption),
+                },
+            );
+        }
+        Ok(address)
+    }
+
+    /// GetWatchOnly returns a watch-only entry by its address
+    pub fn get_watch_only(&self, address: &str) -> Option<&WatchOnly> {
+        self.watch_only.get(address)
+    }
+
+    /// GetWatchOnlyAddresses returns the addresses tracked without keys
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
+    }
+
+    /// GetCryptoProvider returns the provider able to sign for an address
+    pub fn get_crypto_provider(&self, address: &str) -> Option<Box<dyn CryptoProvider>> {
+        self.wallets.get(address).map(Wallet::crypto_provider)
+    }
+
+    /// SaveToFile saves wallets, watch-only entries and the HD recovery phrase
+    ///
+    /// Encrypted wallets are written without their decrypted secret key, and
+    /// the recovery phrase only once sealed
     pub fn save_all(&self) -> Result<()> {
         let db = sled::open("data/wallets")?;
 
         for (address, wallet) in &self.wallets {
-            let data = serialize(wallet)?;
+            let data = serialize(&wallet.record())?;
             db.insert(address, data)?;
         }
+        let watch = db.open_tree("watch")?;
+        for (address, entry) in &self.watch_only {
+            watch.insert(address, serialize(entry)?)?;
+        }
+        for item in watch.iter() {
+            let (address, _) = item?;
+            if self.wallets.contains_key(std::str::from_utf8(&address)?) {
+                watch.remove(address)?;
+            }
+        }
+        if let Some(sealed) = &self.mnemonic {
+            let hd = db.open_tree("hd")?;
+            hd.insert("sealed", serialize(sealed)?)?;
+            hd.remove("mnemonic")?;
+        }
 
         db.flush()?;
         drop(db);
@@ -172,6 +799,317 @@ mod test {
         assert_eq!(&w1, w2);
     }
 
+    #[test]
+    fn test_wallets_keep_encryption() {
+        let mut ws = Wallets::new().unwrap();
+        let ecdsa = ws.create_wallet(EncryptionType::ECDSA);
+        let fndsa = ws.create_wallet(EncryptionType::FNDSA);
+        ws.save_all().unwrap();
+
+        let ws2 = Wallets::new().unwrap();
+        assert_eq!(
+            ws2.get_wallet(&ecdsa).unwrap().encryption,
+            EncryptionType::ECDSA
+        );
+        assert_eq!(
+            ws2.get_crypto_provider(&fndsa).unwrap().encryption_type(),
+            EncryptionType::FNDSA
+        );
+        assert!(ws2.get_crypto_provider("unknown").is_none());
+    }
+
+    #[test]
+    fn test_wallets_migrate_legacy_records() {
+        #[derive(Serialize)]
+        struct Record {
+            secret_key: Vec<u8>,
+            public_key: Vec<u8>,
+        }
+
+        let w = Wallet::new(EncryptionType::ECDSA);
+        let address = w.get_address();
+        let legacy = serialize(&Record {
+            secret_key: w.secret_key.clone(),
+            public_key: w.public_key.clone(),
+        })
+        .unwrap();
+        {
+            let db = sled::open("data/wallets").unwrap();
+            db.insert(&address, legacy).unwrap();
+            db.flush().unwrap();
+        }
+
+        let ws = Wallets::new().unwrap();
+        assert_eq!(ws.get_wallet(&address).unwrap(), &w);
+
+        let db = sled::open("data/wallets").unwrap();
+        let stored: Wallet = deserialize(&db.get(&address).unwrap().unwrap()).unwrap();
+        assert_eq!(stored, w);
+    }
+
+    #[test]
+    fn test_wallet_encryption() {
+        let mut w = Wallet::new(EncryptionType::ECDSA);
+        let secret_key = w.secret_key.clone();
+        w.encrypt("passphrase").unwrap();
+        assert!(w.encrypt("passphrase").is_err());
+
+        w.lock();
+        assert!(w.is_locked());
+        assert!(w.unlock("wrong").is_err());
+        assert!(w.is_locked());
+        w.unlock("passphrase").unwrap();
+        assert_eq!(w.secret_key, secret_key);
+
+        w.change_passphrase("passphrase", "new passphrase").unwrap();
+        w.lock();
+        assert!(w.unlock("passphrase").is_err());
+        w.unlock("new passphrase").unwrap();
+        assert_eq!(w.secret_key, secret_key);
+    }
+
+    #[test]
+    fn test_wallet_encryption_detects_tampering() {
+        let mut w = Wallet::new(EncryptionType::ECDSA);
+        w.encrypt("passphrase").unwrap();
+        let w = w.record();
+
+        let mut tampered = w.clone();
+        tampered.sealed.as_mut().unwrap().ciphertext[0] ^= 1;
+        assert!(tampered.unlock("passphrase").is_err());
+
+        let mut tampered = w.clone();
+        tampered.sealed.as_mut().unwrap().salt[0] ^= 1;
+        assert!(tampered.unlock("passphrase").is_err());
+
+        // A sealed key moved onto another public key must not open
+        let mut tampered = Wallet::new(EncryptionType::ECDSA).record();
+        tampered.secret_key.clear();
+        tampered.sealed = w.sealed.clone();
+        assert!(tampered.unlock("passphrase").is_err());
+
+        let mut tampered = w.clone();
+        tampered.encryption = EncryptionType::FNDSA;
+        assert!(tampered.unlock("passphrase").is_err());
+    }
+
+    #[test]
+    fn test_wallets_never_persist_decrypted_keys() {
+        let mut ws = Wallets::new().unwrap();
+        let address = ws.create_wallet(EncryptionType::MLDSA);
+        let secret_key = ws.get_wallet(&address).unwrap().secret_key.clone();
+        ws.encrypt_wallet(&address, "passphrase").unwrap();
+        assert!(ws.get_signing_wallet(&address).is_ok());
+        let file = ws.export_wallet(&address, false, Some("other")).unwrap();
+        assert!(file.secret_key.is_none() && file.sealed.is_some());
+
+        let db = sled::open("data/wallets").unwrap();
+        let stored: Wallet = deserialize(&db.get(&address).unwrap().unwrap()).unwrap();
+        drop(db);
+        assert!(stored.secret_key.is_empty());
+        assert!(stored.is_encrypted());
+
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.get_signing_wallet(&address).is_err());
+        assert!(ws.unlock_wallet(&address, "wrong").is_err());
+        ws.unlock_wallet(&address, "passphrase").unwrap();
+        assert_eq!(
+            ws.get_signing_wallet(&address).unwrap().secret_key,
+            secret_key
+        );
+        ws.save_all().unwrap();
+
+        // Unlocked for this process, but still sealed on disk
+        let db = sled::open("data/wallets").unwrap();
+        let stored: Wallet = deserialize(&db.get(&address).unwrap().unwrap()).unwrap();
+        drop(db);
+        assert!(stored.secret_key.is_empty());
+        assert!(Wallets::new().unwrap().get_signing_wallet(&address).is_ok());
+
+        ws.lock_wallet(&address).unwrap();
+        assert!(Wallets::new()
+            .unwrap()
+            .get_signing_wallet(&address)
+            .is_err());
+    }
+
+    #[test]
+    fn test_wallets_migrate_plain_records() {
+        #[derive(Serialize)]
+        struct Record {
+            secret_key: Vec<u8>,
+            public_key: Vec<u8>,
+            encryption: EncryptionType,
+        }
+
+        let w = Wallet::new(EncryptionType::MLDSA);
+        let address = w.get_address();
+        let plain = serialize(&Record {
+            secret_key: w.secret_key.clone(),
+            public_key: w.public_key.clone(),
+            encryption: w.encryption,
+        })
+        .unwrap();
+        {
+            let db = sled::open("data/wallets").unwrap();
+            db.insert(&address, plain).unwrap();
+            db.flush().unwrap();
+        }
+
+        let ws = Wallets::new().unwrap();
+        assert_eq!(ws.get_wallet(&address).unwrap(), &w);
+    }
+
+    const MNEMONIC: &str = "legal winner thank year wave sausage worth useful \
+                            legal winner thank yellow";
+
+    const ALL: [EncryptionType; 5] = [
+        EncryptionType::ECDSA,
+        EncryptionType::FNDSA,
+        EncryptionType::MLDSA,
+        EncryptionType::SLHDSA,
+        EncryptionType::HYBRID,
+    ];
+
+    /// Wallets holding no records, unaffected by the shared wallet file
+    fn empty_wallets() -> Wallets {
+        Wallets {
+            wallets: HashMap::new(),
+            mnemonic: None,
+            plain_mnemonic: None,
+            hd_seed: None,
+            watch_only: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_hd_wallets_are_deterministic() {
+        let mut ws = empty_wallets();
+        ws.restore_mnemonic(MNEMONIC, "passphrase").unwrap();
+        for encryption in ALL {
+            let w = ws.hd_wallet(encryption, 0, 0).unwrap();
+            assert_eq!(w, ws.hd_wallet(encryption, 0, 0).unwrap());
+            assert_ne!(w, ws.hd_wallet(encryption, 0, 1).unwrap());
+            assert_ne!(w, ws.hd_wallet(encryption, 1, 0).unwrap());
+
+            let crypto = w.crypto_provider();
+            let sig = crypto.sign(&w.secret_key, b"message").unwrap();
+            assert_eq!(
+                crypto.verify(&w.public_key, b"message", &sig),
+                Ok(true),
+                "{:?}",
+                encryption
+            );
+        }
+    }
+
+    #[test]
+    fn test_restore_regenerates_addresses() {
+        let mut ws = empty_wallets();
+        assert!(ws.create_hd_wallet(EncryptionType::FNDSA, 0).is_err());
+        let mnemonic = ws.init_mnemonic("passphrase").unwrap();
+        assert!(ws.init_mnemonic("passphrase").is_err());
+
+        let fndsa0 = ws.create_hd_wallet(EncryptionType::FNDSA, 0).unwrap();
+        let fndsa1 = ws.create_hd_wallet(EncryptionType::FNDSA, 0).unwrap();
+        let ecdsa0 = ws.create_hd_wallet(EncryptionType::ECDSA, 0).unwrap();
+        assert_ne!(fndsa0, fndsa1);
+
+        let mut restored = empty_wallets();
+        restored.restore_mnemonic(&mnemonic, "other").unwrap();
+        assert_eq!(
+            restored.derive_wallet(EncryptionType::FNDSA, 0, 0).unwrap(),
+            fndsa0
+        );
+        assert_eq!(
+            restored.derive_wallet(EncryptionType::FNDSA, 0, 1).unwrap(),
+            fndsa1
+        );
+        assert_eq!(
+            restored.derive_wallet(EncryptionType::ECDSA, 0, 0).unwrap(),
+            ecdsa0
+        );
+        assert_eq!(
+            restored.get_wallet(&fndsa1),
+            ws.get_wallet(&fndsa1),
+            "restored keys must match the originals"
+        );
+
+        assert!(restored.restore_mnemonic(MNEMONIC, "other").is_err());
+        assert!(restored.restore_mnemonic(&mnemonic, "wrong").is_err());
+        restored.restore_mnemonic(&mnemonic, "other").unwrap();
+    }
+
+    #[test]
+    fn test_mnemonic_is_sealed() {
+        let mut ws = empty_wallets();
+        let mnemonic = ws.init_mnemonic("passphrase").unwrap();
+        let address = ws.create_hd_wallet(EncryptionType::ECDSA, 0).unwrap();
+
+        // Only the sealed phrase is kept, so a copy of the records is locked
+        let record = serialize(&ws).unwrap();
+        let words = mnemonic.split(' ').next().unwrap();
+        assert!(!record.windows(words.len()).any(|w| w == words.as_bytes()));
+        let mut locked: Wallets = deserialize(&record).unwrap();
+        assert!(locked.create_hd_wallet(EncryptionType::ECDSA, 0).is_err());
+        assert!(locked.unlock_mnemonic("wrong").is_err());
+        assert_eq!(*locked.reveal_mnemonic("passphrase").unwrap(), *mnemonic);
+        assert_eq!(
+            locked.derive_wallet(EncryptionType::ECDSA, 0, 0).unwrap(),
+            address
+        );
+
+        // A phrase an older version kept in plaintext is sealed on unlock
+        let mut legacy = empty_wallets();
+        legacy.plain_mnemonic = Some(Zeroizing::new(MNEMONIC.to_string()));
+        assert!(legacy.has_mnemonic() && !legacy.is_mnemonic_sealed());
+        legacy.unlock_mnemonic("passphrase").unwrap();
+        assert!(legacy.is_mnemonic_sealed());
+        assert!(legacy.plain_mnemonic.is_none());
+    }
+
+    #[test]
+    fn test_watch_only_entries() {
+        let mut ws = Wallets::new().unwrap();
+        let owned = ws.create_wallet(EncryptionType::ECDSA);
+        let watched = Wallet::new(EncryptionType::MLDSA);
+        let by_address = Wallet::default().get_address();
+
+        let address = ws
+            .watch_public_key(watched.public_key.clone(), EncryptionType::MLDSA)
+            .unwrap();
+        assert_eq!(address, watched.get_address());
+        ws.watch_address(&by_address).unwrap();
+        assert!(ws.watch_address("not an address").is_err());
+        assert!(ws
+            .watch_public_key(vec![0xff; 7], EncryptionType::ECDSA)
+            .is_err());
+        ws.save_all().unwrap();
+
+        let mut ws = Wallets::new().unwrap();
+        assert_eq!(
+            ws.get_watch_only(&address).unwrap().public_key.as_ref(),
+            Some(&watched.public_key)
+        );
+        assert_eq!(ws.get_watch_only(&by_address).unwrap().public_key, None);
+        assert!(ws.get_wallet(&address).is_none());
+        assert!(ws.get_signing_wallet(&address).is_err());
+        assert!(ws.get_watch_only(&owned).is_none());
+
+        let file = ws.export_wallet(&address, false, None).unwrap();
+        assert!(file.is_watch_only());
+        assert!(ws.export_wallet(&by_address, false, None).is_err());
+
+        // Importing the full wallet replaces the watch-only entry
+        let full = WalletFile::export(&watched, false);
+        assert_eq!(ws.import_wallet(full.clone()).unwrap(), address);
+        assert!(ws.import_wallet(full).is_err());
+        ws.save_all().unwrap();
+        let ws = Wallets::new().unwrap();
+        assert!(ws.get_watch_only(&address).is_none());
+        assert_eq!(ws.get_signing_wallet(&address).unwrap(), &watched);
+    }
+
     #[test]
     #[should_panic]
     fn test_wallets_not_exist() {
@@ -185,7 +1123,13 @@ mod test {
         let w = Wallet::default();
         let mut sk = SigningKeyStandard::decode(&w.secret_key).unwrap();
         let mut sig = vec![0u8; signature_size(sk.get_logn())];
-        sk.sign(&mut OsRng, &DOMAIN_NONE, &HASH_ID_RAW, b"message", &mut sig);
+        sk.sign(
+            &mut rand::rngs::OsRng,
+            &DOMAIN_NONE,
+            &HASH_ID_RAW,
+            b"message",
+            &mut sig,
+        );
 
         match VerifyingKeyStandard::decode(&w.public_key) {
             Some(vk) => {
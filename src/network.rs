@@ -1,2 +1,5 @@
+pub mod frame;
+pub mod peers;
 pub mod server;
+#[allow(clippy::module_inception)]
 pub mod tests;
@@ -0,0 +1,235 @@
+//! Wire framing of P2P messages
+//!
+//! Every message is one frame: a fixed 24-byte header followed by the
+//! payload.
+//!
+//! | bytes | field                                                         |
+//! |-------|---------------------------------------------------------------|
+//! | 4     | network magic, `MAGIC`                                        |
+//! | 12    | command, ASCII, padded with zeros                             |
+//! | 4     | payload length, little-endian u32, at most `MAX_PAYLOAD_SIZE` |
+//! | 4     | first four bytes of the double SHA-256 of the payload         |
+//!
+//! The length lets a reader take exactly one message off a stream, however
+//! the sender's writes were split into TCP segments, and refuse an oversized
+//! one before reading it.
+
+use crate::Result;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Marks the start of every frame of this network
+pub const MAGIC: [u8; 4] = [0x70, 0x6f, 0x6c, 0x79];
+/// Size of the command field
+pub const CMD_LEN: usize = 12;
+/// Size of a frame header
+pub const HEADER_LEN: usize = 4 + CMD_LEN + 4 + 4;
+/// Largest payload a frame may carry
+///
+/// Large enough for blocks full of SLH-DSA signed inputs (7856-byte signatures).
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+/// FrameHeader is the decoded header of a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub command: String,
+    pub length: usize,
+    pub checksum: [u8; 4],
+}
+
+impl FrameHeader {
+    /// Parse decodes a header, rejecting foreign magic and oversized payloads
+    pub fn parse(bytes: &[u8; HEADER_LEN]) -> Result<FrameHeader> {
+        if bytes[..4] != MAGIC {
+            return Err(format_err!(
+                "bad network magic {}",
+                hex::encode(&bytes[..4])
+            ));
+        }
+        let command = decode_command(&bytes[4..4 + CMD_LEN])?;
+        let mut length = [0; 4];
+        length.copy_from_slice(&bytes[4 + CMD_LEN..8 + CMD_LEN]);
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(format_err!(
+                "'{}' payload of {} bytes exceeds the maximum of {}",
+                command,
+                length,
+                MAX_PAYLOAD_SIZE
+            ));
+        }
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&bytes[8 + CMD_LEN..]);
+        Ok(FrameHeader {
+            command,
+            length,
+            checksum,
+        })
+    }
+
+    /// Verify checks that `payload` is the one this header describes
+    pub fn verify(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() != self.length {
+            return Err(format_err!(
+                "'{}' payload is {} bytes, header says {}",
+                self.command,
+                payload.len(),
+                self.length
+            ));
+        }
+        if checksum(payload) != self.checksum {
+            return Err(format_err!("'{}' payload checksum mismatch", self.command));
+        }
+        Ok(())
+    }
+}
+
+/// Encode builds the frame carrying `payload` under `command`
+pub fn encode(command: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(format_err!(
+            "'{}' payload of {} bytes exceeds the maximum of {}",
+            command,
+            payload.len(),
+            MAX_PAYLOAD_SIZE
+        ));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&cmd_to_bytes(command));
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// WriteFrame writes one frame and flushes it
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    command: &str,
+    payload: &[u8],
+) -> Result<()> {
+    writer.write_all(&encode(command, payload)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// ReadFrame reads exactly one frame and returns its command and payload
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(String, Vec<u8>)> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let header = FrameHeader::parse(&header)?;
+
+    let mut payload = vec![0; header.length];
+    reader.read_exact(&mut payload).await?;
+    header.verify(&payload)?;
+    Ok((header.command, payload))
+}
+
+/// Checksum is the first four bytes of the double SHA-256 of `payload`
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    let mut digest = [0; 32];
+    hasher.result(&mut digest);
+    hasher.reset();
+    hasher.input(&digest);
+    hasher.result(&mut digest);
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&digest[..4]);
+    checksum
+}
+
+/// Converts a command string to a fixed-size byte array
+fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+    let mut bytes = [0; CMD_LEN];
+    for (i, b) in cmd.bytes().take(CMD_LEN).enumerate() {
+        bytes[i] = b;
+    }
+    bytes
+}
+
+/// Decodes a command, which must be ASCII followed only by zero padding
+fn decode_command(bytes: &[u8]) -> Result<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    if bytes[end..].iter().any(|&b| b != 0) || !bytes[..end].is_ascii() {
+        return Err(format_err!("malformed command {}", hex::encode(bytes)));
+    }
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_cmd_conversion() {
+        let cmd = "version";
+        let bytes = cmd_to_bytes(cmd);
+        let decoded = decode_command(&bytes).unwrap();
+        assert_eq!(cmd, decoded);
+
+        let cmd = "a_very_long_command_that_exceeds_length";
+        let bytes = cmd_to_bytes(cmd);
+        let decoded = decode_command(&bytes).unwrap();
+        assert_eq!(&cmd[..CMD_LEN], decoded);
+
+        assert!(decode_command(b"ver\0sion\0\0\0\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frames_split_across_segments() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A few SLH-DSA signed inputs already span many TCP segments, and a
+        // second frame on the same stream must not bleed into the first
+        let large = vec![0xab; 64 * 1024];
+        let frames = [encode("tx", &large).unwrap(), encode("ping", b"").unwrap()];
+        let writer = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            for frame in frames.concat().chunks(1000) {
+                stream.write_all(frame).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            read_frame(&mut stream).await.unwrap(),
+            (String::from("tx"), large)
+        );
+        assert_eq!(
+            read_frame(&mut stream).await.unwrap(),
+            (String::from("ping"), Vec::new())
+        );
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bad_frames_are_rejected() {
+        let frame = encode("block", b"payload").unwrap();
+
+        let mut bad = frame.clone();
+        bad[0] ^= 1;
+        let err = read_frame(&mut bad.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("magic"), "{}", err);
+
+        let mut bad = frame.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        let err = read_frame(&mut bad.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        // Oversized payloads are refused from the header alone
+        let mut bad = frame.clone();
+        bad[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_frame(&mut bad.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+
+        // A truncated frame is an error, not a short message
+        assert!(read_frame(&mut &frame[..frame.len() - 1]).await.is_err());
+    }
+}
@@ -0,0 +1,438 @@
+//! Peer manager: the addresses a node knows and how each has behaved
+//!
+//! Every known address carries when we were last connected to it and how
+//! many attempts to connect to it failed in a row. Failed addresses are
+//! retried with exponential backoff and forgotten after `MAX_FAILURES`
+//! failures in a row.
+//!
+//! Misbehaviour is scored by IP, since a peer picks its own port: an IP
+//! whose score reaches `BAN_THRESHOLD` is banned on every port.
+//!
+//! Once attached to sled trees, every change to addresses and bans is
+//! written through, so a restarted node can reconnect without a bootstrap
+//! node and keeps its bans.
+
+use crate::Result;
+use bincode::{deserialize, serialize};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses remembered; the least recently seen make room for new ones
+pub const MAX_KNOWN_PEERS: usize = 1000;
+/// Failed connection attempts in a row after which an address is forgotten
+pub const MAX_FAILURES: u32 = 8;
+/// How long a misbehaving peer stays banned (in seconds)
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// Misbehaviour score at which a peer's IP is banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Most IPs whose misbehaviour is remembered; the least recently scored
+/// make room for new ones
+pub const MAX_SCORED_IPS: usize = 1000;
+/// How long misbehaviour counts against an IP (in seconds)
+const SCORE_LIFETIME: u64 = 24 * 60 * 60;
+/// Wait before retrying an address after its first failure (in seconds)
+const BASE_BACKOFF: u64 = 30;
+/// Longest wait between attempts (in seconds)
+const MAX_BACKOFF: u64 = 60 * 60;
+
+/// Now returns the current time in seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// IpOf returns the IP of an `ip:port` address
+pub fn ip_of(addr: &str) -> Option<IpAddr> {
+    addr.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// PeerStatus is the state of our connection to a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// Not connected
+    #[default]
+    Disconnected,
+    /// Handshake completed, connection open
+    Connected,
+}
+
+/// PeerInfo is what we know about one address
+///
+/// Times are in seconds since the epoch.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    /// Last time we were connected to the peer, 0 if never
+    pub last_seen: u64,
+    /// Connection attempts that failed since the last one that succeeded
+    pub failures: u32,
+    /// Earliest time to try connecting again
+    pub retry_at: u64,
+    /// Connection status, which is not saved
+    #[serde(skip)]
+    pub status: PeerStatus,
+}
+
+/// PeerManager keeps the known peers of a node
+#[derive(Default)]
+pub struct PeerManager {
+    peers: HashMap<String, PeerInfo>,
+    /// Misbehaviour since the last ban and when it was last scored, by IP,
+    /// which is not saved
+    scores: HashMap<IpAddr, (u32, u64)>,
+    /// End of each ban, by IP
+    bans: HashMap<IpAddr, u64>,
+    /// Where changes to addresses are saved, once attached
+    tree: Option<sled::Tree>,
+    /// Where bans are saved, once attached
+    ban_tree: Option<sled::Tree>,
+}
+
+impl PeerManager {
+    /// Attach merges the peers saved in `tree` and the bans saved in
+    /// `ban_tree` into ours and saves every later change there
+    ///
+    /// Bans of older records, which were kept by address, are dropped.
+    pub fn attach(&mut self, tree: sled::Tree, ban_tree: sled::Tree) -> Result<()> {
+        for item in tree.iter() {
+            let (addr, info) = item?;
+            let addr = String::from_utf8(addr.to_vec())?;
+            // Records of an older format only tell us the address
+            let info: PeerInfo = deserialize(&info).unwrap_or_default();
+            self.peers.entry(addr).or_insert(info);
+        }
+        for item in ban_tree.iter() {
+            let (ip, until) = item?;
+            let ip: IpAddr = String::from_utf8(ip.to_vec())?.parse()?;
+            let until: u64 = deserialize(&until)?;
+            let ban = self.bans.entry(ip).or_insert(until);
+            *ban = until.max(*ban);
+        }
+        self.tree = Some(tree);
+        self.ban_tree = Some(ban_tree);
+        let addrs: Vec<String> = self.peers.keys().cloned().collect();
+        for addr in addrs {
+            self.save(&addr);
+        }
+        let ips: Vec<IpAddr> = self.bans.keys().copied().collect();
+        for ip in ips {
+            self.save_ban(ip);
+        }
+        Ok(())
+    }
+
+    /// Len returns the number of known peers
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// IsEmpty reports whether no peer is known
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Get returns what we know about `addr`
+    pub fn get(&self, addr: &str) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    /// IsKnown reports whether `addr` is a known peer
+    pub fn is_known(&self, addr: &str) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    /// IsBanned reports whether `ip` is banned at `now`
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.bans.get(&ip).is_some_and(|until| now < *until)
+    }
+
+    /// Add remembers a new address, returning whether it was new
+    ///
+    /// When `MAX_KNOWN_PEERS` are known, the least recently seen address
+    /// that is not connected is forgotten to make room; if there is none,
+    /// the new address is not added.
+    pub fn add(&mut self, addr: &str) -> bool {
+        if self.peers.contains_key(addr) {
+            return false;
+        }
+        if self.peers.len() >= MAX_KNOWN_PEERS {
+            let evictable = self
+                .peers
+                .iter()
+                .filter(|(_, info)| info.status != PeerStatus::Connected)
+                .min_by_key(|(_, info)| (info.last_seen, u32::MAX - info.failures))
+                .map(|(addr, _)| addr.clone());
+            match evictable {
+                Some(old) => self.remove(&old),
+                None => return false,
+            }
+        }
+        self.peers.insert(addr.to_string(), PeerInfo::default());
+        self.save(addr);
+        true
+    }
+
+    /// Connected records a completed handshake with `addr`, adding it if new
+    /// as `add` does
+    pub fn connected(&mut self, addr: &str, now: u64) {
+        if !self.peers.contains_key(addr) && !self.add(addr) {
+            return;
+        }
+        let info = self.peers.entry(addr.to_string()).or_default();
+        info.status = PeerStatus::Connected;
+        info.last_seen = now;
+        info.failures = 0;
+        info.retry_at = 0;
+        self.save(addr);
+    }
+
+    /// Disconnected records that our connection to `addr` closed
+    pub fn disconnected(&mut self, addr: &str, now: u64) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.status = PeerStatus::Disconnected;
+            info.last_seen = now;
+            self.save(addr);
+        }
+    }
+
+    /// Failed records a failed connection attempt
+    ///
+    /// The wait before the next attempt doubles with every failure in a row,
+    /// up to `MAX_BACKOFF`; after `MAX_FAILURES` the address is forgotten.
+    pub fn failed(&mut self, addr: &str, now: u64) {
+        let Some(info) = self.peers.get_mut(addr) else {
+            return;
+        };
+        info.status = PeerStatus::Disconnected;
+        info.failures += 1;
+        if info.failures >= MAX_FAILURES {
+            self.remove(addr);
+            return;
+        }
+        let backoff = BASE_BACKOFF
+            .saturating_mul(1 << (info.failures - 1).min(32))
+            .min(MAX_BACKOFF);
+        info.retry_at = now + backoff;
+        self.save(addr);
+    }
+
+    /// Misbehaved adds `score` to the misbehaviour of `ip`, banning it once
+    /// the total reaches `BAN_THRESHOLD`; returns whether it was banned
+    ///
+    /// Scores are forgotten `SCORE_LIFETIME` after an IP last misbehaved,
+    /// and at most `MAX_SCORED_IPS` are kept.
+    pub fn misbehaved(&mut self, ip: IpAddr, score: u32, now: u64) -> bool {
+        self.scores
+            .retain(|_, (_, scored)| now < scored.saturating_add(SCORE_LIFETIME));
+        if !self.scores.contains_key(&ip) && self.scores.len() >= MAX_SCORED_IPS {
+            let oldest = self
+                .scores
+                .iter()
+                .min_by_key(|(_, (_, scored))| *scored)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.scores.remove(&oldest);
+            }
+        }
+        let (total, scored) = self.scores.entry(ip).or_default();
+        *total = total.saturating_add(score);
+        *scored = now;
+        if *total < BAN_THRESHOLD {
+            return false;
+        }
+        self.ban(ip, now);
+        true
+    }
+
+    /// Ban keeps `ip` from connecting or being dialed for `BAN_DURATION`
+    pub fn ban(&mut self, ip: IpAddr, now: u64) {
+        self.scores.remove(&ip);
+        self.bans.insert(ip, now + BAN_DURATION);
+        for (addr, info) in self.peers.iter_mut() {
+            if ip_of(addr) == Some(ip) {
+                info.status = PeerStatus::Disconnected;
+            }
+        }
+        self.save_ban(ip);
+    }
+
+    /// ConnectedPeers returns the peers we have a connection to
+    pub fn connected_peers(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .filter(|(_, info)| info.status == PeerStatus::Connected)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Dialable returns the addresses due for a connection attempt at `now`,
+    /// those that failed least first
+    pub fn dialable(&self, now: u64) -> Vec<String> {
+        let mut due: Vec<(&String, &PeerInfo)> = self
+            .peers
+            .iter()
+            .filter(|(addr, info)| {
+                info.status == PeerStatus::Disconnected
+                    && info.retry_at <= now
+                    && !ip_of(addr).is_some_and(|ip| self.is_banned(ip, now))
+            })
+            .collect();
+        due.sort_by_key(|(addr, info)| (info.failures, *addr));
+        due.into_iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    fn remove(&mut self, addr: &str) {
+        self.peers.remove(addr);
+        if let Some(tree) = &self.tree {
+            if let Err(e) = tree.remove(addr) {
+                warn!("Failed to forget peer {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn save(&self, addr: &str) {
+        let (Some(tree), Some(info)) = (&self.tree, self.peers.get(addr)) else {
+            return;
+        };
+        let saved = serialize(info)
+            .map_err(failure::Error::from)
+            .and_then(|info| Ok(tree.insert(addr, info)?));
+        if let Err(e) = saved {
+            warn!("Failed to save peer {}: {}", addr, e);
+        }
+    }
+
+    fn save_ban(&self, ip: IpAddr) {
+        let (Some(tree), Some(until)) = (&self.ban_tree, self.bans.get(&ip)) else {
+            return;
+        };
+        let saved = serialize(until)
+            .map_err(failure::Error::from)
+            .and_then(|until| Ok(tree.insert(ip.to_string(), until)?));
+        if let Err(e) = saved {
+            warn!("Failed to save ban of {}: {}", ip, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_failures_back_off_then_forget() {
+        let mut peers = PeerManager::default();
+        assert!(peers.add("10.0.0.1:7000"));
+        assert!(!peers.add("10.0.0.1:7000"));
+        assert_eq!(peers.dialable(100), vec![String::from("10.0.0.1:7000")]);
+
+        peers.failed("10.0.0.1:7000", 100);
+        assert!(peers.dialable(100).is_empty());
+        assert_eq!(peers.dialable(100 + BASE_BACKOFF).len(), 1);
+        peers.failed("10.0.0.1:7000", 200);
+        assert_eq!(
+            peers.get("10.0.0.1:7000").unwrap().retry_at,
+            200 + 2 * BASE_BACKOFF
+        );
+
+        // A success resets the count
+        peers.connected("10.0.0.1:7000", 300);
+        assert_eq!(peers.connected_peers(), vec![String::from("10.0.0.1:7000")]);
+        assert!(peers.dialable(300).is_empty());
+        peers.disconnected("10.0.0.1:7000", 400);
+        assert_eq!(peers.get("10.0.0.1:7000").unwrap().failures, 0);
+
+        for _ in 0..MAX_FAILURES {
+            peers.failed("10.0.0.1:7000", 500);
+        }
+        assert!(!peers.is_known("10.0.0.1:7000"));
+    }
+
+    #[test]
+    fn test_bans_expire() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut peers = PeerManager::default();
+        peers.connected("10.0.0.1:7000", 100);
+        peers.add("10.0.0.1:7001");
+        peers.ban(ip, 100);
+        assert!(peers.is_banned(ip, 100));
+        assert!(peers.connected_peers().is_empty());
+        assert!(peers.dialable(100).is_empty());
+
+        let end = 100 + BAN_DURATION;
+        assert!(!peers.is_banned(ip, end));
+        assert_eq!(peers.dialable(end).len(), 2);
+    }
+
+    #[test]
+    fn test_misbehaviour_adds_up_to_a_ban() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut peers = PeerManager::default();
+        assert!(!peers.misbehaved(ip, BAN_THRESHOLD - 1, 100));
+        assert!(!peers.is_banned(ip, 100));
+        assert!(!peers.is_banned("10.0.0.2".parse().unwrap(), 100));
+
+        // Any port of the IP counts against it
+        assert!(peers.misbehaved(ip, 1, 100));
+        assert!(peers.is_banned(ip, 100));
+
+        // The score starts over once the ban is over
+        assert!(!peers.misbehaved(ip, 1, 100 + BAN_DURATION));
+
+        // Old scores are forgotten, and only so many IPs are scored
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(!peers.misbehaved(other, BAN_THRESHOLD - 1, 200));
+        assert!(!peers.misbehaved(other, 1, 200 + SCORE_LIFETIME));
+        for i in 0..2 * MAX_SCORED_IPS {
+            let ip = IpAddr::from([10, 1, (i / 256) as u8, (i % 256) as u8]);
+            peers.misbehaved(ip, 1, 300 + i as u64);
+        }
+        assert_eq!(peers.scores.len(), MAX_SCORED_IPS);
+    }
+
+    #[test]
+    fn test_full_manager_forgets_least_recently_seen() {
+        let mut peers = PeerManager::default();
+        for i in 0..MAX_KNOWN_PEERS {
+            let addr = format!("10.0.{}.{}:7000", i / 256, i % 256);
+            peers.connected(&addr, 1000 + i as u64);
+            if i > 0 {
+                peers.disconnected(&addr, 1000 + i as u64);
+            }
+        }
+
+        // The first is connected, so the second goes
+        assert!(peers.add("10.1.0.0:7000"));
+        assert_eq!(peers.len(), MAX_KNOWN_PEERS);
+        assert!(peers.is_known("10.0.0.0:7000"));
+        assert!(!peers.is_known("10.0.0.1:7000"));
+
+        // A new connection makes room the same way, for the address never seen
+        peers.connected("10.1.0.1:7000", 6000);
+        assert_eq!(peers.len(), MAX_KNOWN_PEERS);
+        assert!(peers.is_known("10.1.0.1:7000"));
+        assert!(!peers.is_known("10.1.0.0:7000"));
+    }
+
+    #[test]
+    fn test_peers_are_saved() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("peers").unwrap();
+        let ban_tree = db.open_tree("bans").unwrap();
+        let mut peers = PeerManager::default();
+        peers.add("10.0.0.1:7000");
+        peers.attach(tree.clone(), ban_tree.clone()).unwrap();
+        peers.add("10.0.0.2:7000");
+        peers.add("10.0.0.3:7000");
+        peers.ban("10.0.0.3".parse().unwrap(), 100);
+
+        let mut restored = PeerManager::default();
+        restored.attach(tree, ban_tree).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert!(restored.is_banned("10.0.0.3".parse().unwrap(), 100));
+        assert_eq!(restored.dialable(100).len(), 2);
+    }
+}
@@ -3,54 +3,110 @@
 //! This module implements a peer-to-peer server for blockchain nodes to communicate.
 //! It handles node discovery, block synchronization, transaction propagation,
 //! and remote wallet operations through a standard binary protocol.
+//!
+//! Each peer is reached over long-lived connections. Both ends open one
+//! with a version/verack handshake and accept nothing else until it is
+//! done; afterwards a reader task handles the peer's messages and a writer
+//! task sends the frames queued for it. Connections we dial are known by the
+//! address dialed, and those peers dial to us by their socket address: the
+//! address a peer says it listens on is only worth trying until we have
+//! reached it ourselves.
+//!
+//! Known addresses are kept by a [`PeerManager`], which limits how often
+//! failing addresses are dialed and bans peers that send invalid blocks or
+//! transactions.
 
-use crate::blockchain::block::Block;
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::blockchain::TipChange;
+use crate::blockchain::miner::Miner;
 use crate::blockchain::utxoset::UTXOSet;
-use crate::crypto::fndsa::FnDsaCrypto;
-use crate::crypto::traits::CryptoProvider;
+use crate::blockchain::validation::{check_transaction, UtxoView, ValidationError};
 use crate::crypto::transaction::Transaction;
-use crate::crypto::wallets::Wallets;
+use crate::crypto::wallets::{Wallet, Wallets};
+use crate::network::frame::{encode, read_frame, write_frame};
+use crate::network::peers::{self, PeerManager};
 use crate::Result;
 
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::{Duration, Instant};
 
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+
 use bincode::{deserialize, serialize};
 use failure::format_err;
 use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-/// Size of command field in protocol messages
-const CMD_LEN: usize = 12;
 /// Protocol version
 const VERSION: i32 = 1;
 /// Default timeout for network operations (in seconds)
 const NETWORK_TIMEOUT: u64 = 30;
 /// Interval for node discovery (in seconds)
 const DISCOVERY_INTERVAL: u64 = 300; // 5 minutes
+/// Interval between mining attempts on the mempool (in seconds)
+const MEMPOOL_INTERVAL: u64 = 10;
 /// Maximum number of peers to connect to
-/// This constant is currently unused but reserved for future implementation.
-const _MAX_PEERS: usize = 25;
-/// Buffer size for reading from sockets
-const READ_BUFFER_SIZE: usize = 8192;
+const MAX_PEERS: usize = 25;
+/// How many of `MAX_PEERS` we dial; the rest are left to peers dialing us
+const MAX_OUTBOUND_PEERS: usize = 8;
+/// Most headers sent in one `headers` message
+const MAX_HEADERS: usize = 2000;
+/// Most block hashes sent in answer to one `getblocks` message
+const MAX_INV_BLOCKS: usize = 500;
+/// Most blocks requested at once across all peers
+const MAX_BLOCKS_IN_TRANSIT: usize = 16;
+/// Time after which a block request is given to another peer (in seconds)
+const BLOCK_DOWNLOAD_TIMEOUT: u64 = 60;
+/// Most blocks kept while waiting for their parent to arrive
+const MAX_PENDING_BLOCKS: usize = 64;
+/// Most frames queued for a peer before it is dropped as too slow
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+/// Tree of the blockchain database holding the mempool across restarts
+const MEMPOOL_TREE: &str = "mempool";
+/// Tree of the blockchain database holding known peer addresses
+const PEERS_TREE: &str = "peers";
+/// Tree of the blockchain database holding banned IPs
+const BANS_TREE: &str = "bans";
+/// Misbehaviour score for a message that cannot be decoded
+const MALFORMED_MESSAGE_SCORE: u32 = 20;
+/// Misbehaviour score for a handshake message after the handshake
+const REPEATED_HANDSHAKE_SCORE: u32 = 10;
+
+/// Future of a method that cannot name its own type
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 /// Protocol message types
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
     /// Node addresses
     Addr(AddrMessage),
     /// Version information
     Version(VersionMessage),
+    /// Acknowledges a peer's version, completing our side of the handshake
+    Verack,
     /// Transaction
     Tx(TxMessage),
     /// Request for data
     GetData(GetDataMessage),
     /// Request for blocks
     GetBlocks(GetBlocksMessage),
+    /// Request for headers
+    GetHeaders(GetHeadersMessage),
+    /// Headers of the sender's best chain
+    Headers(HeadersMessage),
     /// Inventory announcement
     Inv(InvMessage),
     /// Block
@@ -79,12 +135,32 @@ struct BlockMessage {
 struct GetBlocksMessage {
     /// Sender node address
     addr_from: String,
-    /// Optional hash to start from
+    /// Hash the sender already knows of, beyond its locator
     start_hash: Option<String>,
-    /// Maximum number of blocks to return
+    /// Hashes of the sender's chain, newest first with doubling gaps
+    locator: Vec<String>,
+    /// Maximum number of blocks to return, at most `MAX_INV_BLOCKS`
     limit: Option<u32>,
 }
 
+/// Message to request the headers of a peer's best chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Hashes of the sender's best header chain, newest first with doubling gaps
+    locator: Vec<String>,
+}
+
+/// Headers of the best chain, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HeadersMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Up to `MAX_HEADERS` consecutive headers
+    headers: Vec<BlockHeader>,
+}
+
 /// Message to request specific data
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDataMessage {
@@ -119,7 +195,7 @@ struct TxMessage {
 /// Version message for handshake
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct VersionMessage {
-    /// Sender node address
+    /// Address the sender listens on; port 0 if it does not accept connections
     addr_from: String,
     /// Protocol version
     version: i32,
@@ -164,6 +240,46 @@ struct SignResponseMessage {
     error_message: String,
 }
 
+/// Message asking a node for a block to mine
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetTemplateMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Address the template's coinbase pays
+    mining_address: String,
+}
+
+/// Response to a template request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TemplateMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Unmined block on the node's tip, if one could be built
+    template: Option<Block>,
+    /// Error message if no template could be built
+    error_message: String,
+}
+
+/// Message handing a node a block mined from one of its templates
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubmitBlockMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Mined block
+    block: Block,
+}
+
+/// Response to a submitted block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubmitResponseMessage {
+    /// Sender node address
+    addr_from: String,
+    /// Whether the block was valid and stored
+    accepted: bool,
+    /// Error message if the block was rejected
+    error_message: String,
+}
+
 /// Ping message to check node connectivity
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PingMessage {
@@ -186,42 +302,54 @@ struct PongMessage {
     timestamp: u64,
 }
 
-/// Node information
-#[derive(Clone, Debug)]
-struct PeerInfo {
-    /// Network address
-    _address: String,
-    /// Last seen timestamp
-    last_seen: Instant,
-    /// Latest known blockchain height
-    best_height: i32,
-    /// Connection status
-    status: PeerStatus,
-}
-
-/// Peer connection status
-#[derive(Clone, Debug, PartialEq)]
-enum PeerStatus {
-    /// New peer, not yet contacted
-    New,
-    /// Successfully connected
-    Connected,
-    /// Failed to connect or disconnected
-    Failed,
+/// Open connection to a peer that completed the handshake
+struct PeerConnection {
+    /// Tells this connection apart from earlier ones to the same peer
+    id: u64,
+    /// Whether we dialed the peer
+    outbound: bool,
+    /// IP of the peer's socket, which misbehaviour is held against
+    ip: IpAddr,
+    /// Frames for the writer task to send
+    queue: mpsc::Sender<Vec<u8>>,
+    /// Writer task, which exits once `queue` is dropped and drained
+    writer: JoinHandle<()>,
+    /// Dropping it stops the reader task
+    _close: oneshot::Sender<()>,
+    /// Requests sent on this connection waiting for their reply, by the
+    /// reply's command, oldest first; dropped with the connection
+    requests: HashMap<String, VecDeque<oneshot::Sender<Vec<u8>>>>,
 }
 
 /// Internal server state
 struct ServerInner {
     /// Known peers
-    peers: HashMap<String, PeerInfo>,
+    peers: PeerManager,
+    /// Open connections, by the address we dialed or, for peers that
+    /// dialed us, their socket address
+    connections: HashMap<String, PeerConnection>,
+    /// Id of the next connection
+    next_connection_id: u64,
+    /// Blocks being downloaded, with the peer asked and when
+    blocks_in_transit: HashMap<String, (String, Instant)>,
+    /// Downloaded blocks waiting for their parent, by parent hash
+    pending_blocks: HashMap<String, Vec<Block>>,
+    /// Rotates block requests between peers
+    download_cursor: usize,
+    /// Latest seen ping times (ms)
+    ping_times: HashMap<String, u64>,
+}
+
+/// Chain state: the blockchain, its UTXO set and the transactions waiting
+/// for a block
+///
+/// It is only locked on the blocking pool, through `Server::with_chain`, so
+/// its disk reads and signature checks never hold up the async workers.
+struct ChainState {
     /// UTXO set and blockchain
     utxo: UTXOSet,
-    /// Blocks being downloaded
-    blocks_in_transit: Vec<String>,
     /// Unconfirmed transactions
     mempool: HashMap<String, Transaction>,
-    /// Latest seen ping times (ms)
-    ping_times: HashMap<String, u64>,
 }
 
 /// P2P blockchain server
@@ -232,8 +360,54 @@ pub struct Server {
     mining_address: String,
     /// Shared server state
     inner: Arc<Mutex<ServerInner>>,
-    /// Server is running flag
-    running: Arc<Mutex<bool>>,
+    /// Blockchain, UTXO set and mempool
+    chain: Arc<Mutex<ChainState>>,
+    /// Proof-of-work search, cancelled whenever a peer moves the tip
+    miner: Miner,
+    /// Tasks spawned by the server, waited for before it stops
+    tasks: Arc<Mutex<JoinSet<()>>>,
+    /// Set to true to stop the server's tasks
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Set to true once `start_server` has returned
+    stopped: Arc<watch::Sender<bool>>,
+}
+
+/// ShutdownHandle stops a server from outside it
+///
+/// Handles stay valid after the server stops and can be cloned freely.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    stopped: watch::Receiver<bool>,
+    miner: Miner,
+}
+
+impl ShutdownHandle {
+    /// Shutdown asks the server to stop and cancels any mining
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.miner.cancel();
+    }
+
+    /// Stopped waits until the server has stopped and saved its state
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+
+    /// StopOnSignal shuts the server down on SIGINT or SIGTERM
+    pub fn stop_on_signal(&self) {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            match shutdown_signal().await {
+                Ok(signal) => {
+                    info!("Received {}, shutting down", signal);
+                    handle.shutdown();
+                }
+                Err(e) => error!("Cannot listen for shutdown signals: {}", e),
+            }
+        });
+    }
 }
 
 impl Server {
@@ -257,19 +431,11 @@ impl Server {
         bootstrap: Option<&str>,
         utxo: UTXOSet,
     ) -> Result<Server> {
-        let mut peers = HashMap::new();
+        let mut peers = PeerManager::default();
 
         // Add bootstrap node if provided
         if let Some(bn) = bootstrap {
-            peers.insert(
-                bn.to_string(),
-                PeerInfo {
-                    _address: bn.to_string(),
-                    last_seen: Instant::now(),
-                    best_height: -1,
-                    status: PeerStatus::New,
-                },
-            );
+            peers.add(bn);
         }
 
         Ok(Server {
@@ -277,113 +443,153 @@ impl Server {
             mining_address: miner_address.to_string(),
             inner: Arc::new(Mutex::new(ServerInner {
                 peers,
+                connections: HashMap::new(),
+                next_connection_id: 0,
+                blocks_in_transit: HashMap::new(),
+                pending_blocks: HashMap::new(),
+                download_cursor: 0,
+                ping_times: HashMap::new(),
+            })),
+            chain: Arc::new(Mutex::new(ChainState {
                 utxo,
-                blocks_in_transit: Vec::new(),
                 mempool: HashMap::new(),
-                ping_times: HashMap::new(),
             })),
-            running: Arc::new(Mutex::new(false)),
+            miner: Miner::default(),
+            tasks: Arc::new(Mutex::new(JoinSet::new())),
+            shutdown: Arc::new(watch::channel(false).0),
+            stopped: Arc::new(watch::channel(false).0),
         })
     }
 
     /// Starts the server and begins listening for connections
     ///
-    /// This method starts the main server loop and several background tasks:
-    /// - Node discovery thread
-    /// - Mempool management thread
-    /// - Block synchronization thread
+    /// This method runs the main server loop alongside several background tasks:
+    /// - Node discovery
+    /// - Mempool management
+    /// - Initial block synchronization
+    ///
+    /// It returns once the server is asked to stop and every task has
+    /// finished, with connections flushed and the peers and mempool saved.
     ///
     /// # Returns
     ///
     /// Result indicating success or failure
-    pub fn start_server(&self) -> Result<()> {
+    pub async fn start_server(&self) -> Result<()> {
+        let result = self.run().await;
+        self.stopped.send_replace(true);
+        result
+    }
+
+    /// Runs the server until it is asked to stop
+    async fn run(&self) -> Result<()> {
         info!(
             "Starting server at {}, mining address: {}",
             &self.node_address, &self.mining_address
         );
 
-        // Mark server as running
-        {
-            let mut running = self.running.lock().unwrap();
-            *running = true;
+        let listener = match TcpListener::bind(&self.node_address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind to {}: {}", self.node_address, e);
+                return Err(format_err!(
+                    "Failed to bind to {}: {}",
+                    self.node_address,
+                    e
+                ));
+            }
+        };
+
+        if let Err(e) = self.load_state().await {
+            warn!("Failed to restore saved peers and mempool: {}", e);
         }
 
-        // Clone references for background threads
+        // Clone references for background tasks
         let server_discovery = self.clone();
         let server_mempool = self.clone();
         let server_sync = self.clone();
 
-        // Start node discovery thread
-        thread::spawn(move || {
-            info!("Starting node discovery thread");
-            while *server_discovery.running.lock().unwrap() {
-                if let Err(e) = server_discovery.discover_nodes() {
+        // Start node discovery task
+        self.spawn(async move {
+            info!("Starting node discovery task");
+            let mut interval = time::interval(Duration::from_secs(DISCOVERY_INTERVAL));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = server_discovery.stopping() => break,
+                }
+                if let Err(e) = server_discovery.discover_nodes().await {
                     error!("Node discovery error: {}", e);
                 }
-                thread::sleep(Duration::from_secs(DISCOVERY_INTERVAL));
             }
         });
 
-        // Start mempool management thread
-        thread::spawn(move || {
-            info!("Starting mempool management thread");
-            while *server_mempool.running.lock().unwrap() {
+        // Start mempool management task
+        self.spawn(async move {
+            info!("Starting mempool management task");
+            let mut interval = time::interval(Duration::from_secs(MEMPOOL_INTERVAL));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = server_mempool.stopping() => break,
+                }
                 if !server_mempool.mining_address.is_empty() {
-                    if let Err(e) = server_mempool.process_mempool() {
+                    if let Err(e) = server_mempool.process_mempool().await {
                         error!("Mempool processing error: {}", e);
                     }
                 }
-                thread::sleep(Duration::from_secs(10));
             }
         });
 
-        // Start block synchronization thread if we're a new node
-        thread::spawn(move || {
+        // Connect to known peers; the handshake tells each side whether
+        // the other is ahead
+        self.spawn(async move {
             info!("Starting initial block synchronization");
-            thread::sleep(Duration::from_secs(5)); // Give time for server to start
+            tokio::select! {
+                // Give time for server to start
+                _ = time::sleep(Duration::from_secs(5)) => {}
+                _ = server_sync.stopping() => return,
+            }
 
-            if let Ok(height) = server_sync.get_best_height() {
-                if height == -1 {
-                    if let Err(e) = server_sync.synchronize_blockchain() {
-                        error!("Initial blockchain sync error: {}", e);
-                    }
-                } else {
-                    // We already have blocks, just announce our version
-                    let peers = server_sync.get_peers();
-                    for peer in peers {
-                        if let Err(e) = server_sync.send_version(&peer) {
-                            error!("Failed to send version to {}: {}", peer, e);
-                        }
-                    }
+            server_sync.connect_peers().await;
+            if let Ok(-1) = server_sync.get_best_height().await {
+                if let Err(e) = server_sync.synchronize_blockchain().await {
+                    error!("Initial blockchain sync error: {}", e);
                 }
             }
         });
 
-        // Start main server loop
-        let listener = match TcpListener::bind(&self.node_address) {
-            Ok(listener) => listener,
-            Err(e) => {
-                error!("Failed to bind to {}: {}", self.node_address, e);
-                return Err(format_err!(
-                    "Failed to bind to {}: {}",
-                    self.node_address,
-                    e
-                ));
-            }
-        };
-
         info!("Server listening for connections");
+        self.serve(listener).await;
 
-        for stream in listener.incoming() {
-            if !*self.running.lock().unwrap() {
-                break;
-            }
+        // Let every task finish, then flush and close connections
+        self.join_tasks().await;
+        self.close_connections().await;
+        self.save_state().await?;
+        info!("Server stopped");
+
+        Ok(())
+    }
+
+    /// Accepts connections until the server stops, handshaking with each
+    /// in its own task
+    async fn serve(&self, listener: TcpListener) {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.stopping() => break,
+            };
 
             match stream {
-                Ok(stream) => {
+                Ok((_, addr)) if self.is_banned(addr.ip()) => {
+                    debug!("Refusing banned peer {}", addr);
+                }
+                Ok((_, addr)) if self.connection_count(false) >= MAX_PEERS - MAX_OUTBOUND_PEERS => {
+                    debug!("Inbound peer limit reached, refusing {}", addr);
+                }
+                Ok((stream, _)) => {
                     let server_conn = self.clone();
-                    thread::spawn(move || {
-                        if let Err(e) = server_conn.handle_connection(stream) {
+                    self.spawn(async move {
+                        if let Err(e) = server_conn.handle_connection(stream).await {
                             error!("Connection error: {}", e);
                         }
                     });
@@ -393,24 +599,68 @@ impl Server {
                 }
             }
         }
-
-        // Mark server as stopped
-        {
-            let mut running = self.running.lock().unwrap();
-            *running = false;
-        }
-
-        Ok(())
     }
 
     /// Stops the server gracefully
+    ///
+    /// Cancels any mining and makes `start_server` return once its tasks
+    /// have finished.
     pub fn stop_server(&self) -> Result<()> {
         info!("Stopping server");
-        let mut running = self.running.lock().unwrap();
-        *running = false;
+        self.shutdown_handle().shutdown();
         Ok(())
     }
 
+    /// Returns a handle that can stop the server from another task or thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: Arc::clone(&self.shutdown),
+            stopped: self.stopped.subscribe(),
+            miner: self.miner.clone(),
+        }
+    }
+
+    /// Resolves once the server has been asked to stop
+    async fn stopping(&self) {
+        let mut stop = self.shutdown.subscribe();
+        let _ = stop.wait_for(|stop| *stop).await;
+    }
+
+    /// Checks if the server has been asked to stop
+    fn is_stopping(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Spawns a task that the server waits for before it stops
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+        // Reap finished tasks so the set holds only running ones
+        while let Some(result) = tasks.try_join_next() {
+            if let Err(e) = result {
+                error!("Task failed: {}", e);
+            }
+        }
+        tasks.spawn(task);
+    }
+
+    /// Waits for every task spawned so far, and for those they spawn
+    async fn join_tasks(&self) {
+        loop {
+            let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                return;
+            }
+            while let Some(result) = tasks.join_next().await {
+                if let Err(e) = result {
+                    error!("Task failed: {}", e);
+                }
+            }
+        }
+    }
+
     /// Sends a transaction to the network
     ///
     /// # Arguments
@@ -422,14 +672,20 @@ impl Server {
     /// # Returns
     ///
     /// Result indicating success or failure
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet, target_addr: &str) -> Result<()> {
+    pub async fn send_transaction(
+        tx: &Transaction,
+        utxoset: UTXOSet,
+        target_addr: &str,
+    ) -> Result<()> {
         let server = Server::new("0.0.0.0", "0", "", None, utxoset)?;
-        server.send_tx(target_addr, tx)?;
+        server.send_tx(target_addr, tx).await?;
+        server.close_connections().await;
         Ok(())
     }
 
     /// Discovers and connects to new nodes
-    fn discover_nodes(&self) -> Result<()> {
+    async fn discover_nodes(&self) -> Result<()> {
+        self.connect_peers().await;
         let peers = self.get_peers();
 
         if peers.is_empty() {
@@ -439,7 +695,7 @@ impl Server {
 
         // Ask each connected peer for their known addresses
         for peer in peers {
-            if let Err(e) = self.send_get_addr(&peer) {
+            if let Err(e) = self.send_get_addr(&peer).await {
                 warn!("Failed to request addresses from {}: {}", peer, e);
                 continue;
             }
@@ -449,60 +705,85 @@ impl Server {
     }
 
     /// Processes transactions in the mempool
-    fn process_mempool(&self) -> Result<()> {
-        let mempool = self.get_mempool();
-
-        if mempool.is_empty() {
+    async fn process_mempool(&self) -> Result<()> {
+        // Taken before the template, so a tip that moves while it is built
+        // cancels the search
+        let epoch = self.miner.epoch();
+        if self.is_stopping() {
             return Ok(());
         }
+        let mining_address = self.mining_address.clone();
+        let template = self
+            .with_chain(move |chain| {
+                if chain.mempool.is_empty() {
+                    return Ok(None);
+                }
+                debug!(
+                    "Processing mempool with {} transactions",
+                    chain.mempool.len()
+                );
+
+                let txs = chain.select_transactions()?;
+                if txs.is_empty() {
+                    return Ok(None);
+                }
+                info!("Mining new block with {} transactions", txs.len());
+                chain.create_template(&mining_address, txs).map(Some)
+            })
+            .await?;
+        let template = match template {
+            Some(template) => template,
+            None => return Ok(()),
+        };
 
-        debug!("Processing mempool with {} transactions", mempool.len());
-
-        // Collect valid transactions
-        let mut txs = Vec::new();
-
-        for tx in mempool.values() {
-            if self.verify_tx(tx)? {
-                txs.push(tx.clone());
-            } else {
-                warn!("Invalid transaction in mempool: {}", tx.id);
-            }
-        }
+        // Mine without holding the chain state, so peers' blocks can
+        // arrive and cancel the search
+        let miner = self.miner.clone();
+        let mined = tokio::task::spawn_blocking(move || miner.mine(template, epoch)).await?;
+        let new_block = match mined? {
+            Some(block) => block,
+            None => return Ok(()),
+        };
 
-        if txs.is_empty() {
+        if self.accept_block(new_block.clone()).await?.is_empty() {
+            info!("Mined block {} is stale", new_block.get_hash());
             return Ok(());
         }
+        self.announce_block(&new_block).await?;
 
-        info!("Mining new block with {} transactions", txs.len());
-
-        // Create coinbase transaction
-        let cbtx = Transaction::new_coinbase(self.mining_address.clone(), String::from("reward!"))?;
-        txs.push(cbtx);
+        info!("New block mined: {}", new_block.get_hash());
 
-        // Mine block
-        let new_block = self.mine_block(txs)?;
+        Ok(())
+    }
 
-        // Update UTXO set
-        self.update_utxo(&new_block)?;
+    /// Adds a block and, if it moved our tip, follows it
+    ///
+    /// Any search on the old tip is cancelled. Returns how the best chain
+    /// changed.
+    async fn accept_block(&self, block: Block) -> Result<TipChange> {
+        let change = self
+            .with_chain(move |chain| chain.accept_block(block))
+            .await?;
+        if !change.is_empty() {
+            // Our own template no longer extends the tip
+            self.miner.cancel();
+        }
+        Ok(change)
+    }
 
-        // Announce block to peers
-        let peers = self.get_peers();
-        for peer in peers {
+    /// Announces a block we mined or were handed to every peer
+    async fn announce_block(&self, block: &Block) -> Result<()> {
+        for peer in self.get_peers() {
             if peer != self.node_address {
-                self.send_inv(&peer, "block", vec![new_block.get_hash()])?;
+                self.send_inv(&peer, "block", vec![block.get_hash()])
+                    .await?;
             }
         }
-
-        // Clear processed transactions from mempool
-        self.clear_mempool();
-
-        info!("New block mined: {}", new_block.get_hash());
-
         Ok(())
     }
 
     /// Synchronizes blockchain with peers
-    fn synchronize_blockchain(&self) -> Result<()> {
+    async fn synchronize_blockchain(&self) -> Result<()> {
         info!("Synchronizing blockchain with peers");
 
         let peers = self.get_peers();
@@ -511,10 +792,10 @@ impl Server {
             return Ok(());
         }
 
-        // Request blocks from all peers
+        // Headers first; blocks are requested once their headers check out
         for peer in peers {
-            if let Err(e) = self.send_get_blocks(&peer) {
-                warn!("Failed to request blocks from {}: {}", peer, e);
+            if let Err(e) = self.send_get_headers(&peer).await {
+                warn!("Failed to request headers from {}: {}", peer, e);
             }
         }
 
@@ -522,7 +803,7 @@ impl Server {
     }
 
     /// Sends a request for peer addresses
-    fn send_get_addr(&self, addr: &str) -> Result<()> {
+    async fn send_get_addr(&self, addr: &str) -> Result<()> {
         info!("Requesting peer addresses from {}", addr);
 
         let now = std::time::SystemTime::now()
@@ -535,14 +816,15 @@ impl Server {
             timestamp: now,
         };
 
-        self.send_message(addr, "addr", &msg)
+        self.send_message(addr, "addr", &msg).await
     }
 
     /// Sends our known peer addresses
-    fn send_addr(&self, addr: &str) -> Result<()> {
+    async fn send_addr(&self, addr: &str) -> Result<()> {
         info!("Sending peer addresses to {}", addr);
 
-        let peers = self.get_peers();
+        // Only addresses we reached ourselves; others are just claims
+        let peers = self.inner.lock().unwrap().peers.connected_peers();
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
@@ -553,29 +835,25 @@ impl Server {
             timestamp: now,
         };
 
-        self.send_message(addr, "addr", &msg)
+        self.send_message(addr, "addr", &msg).await
     }
 
-    /// Sends version information to a peer
-    fn send_version(&self, addr: &str) -> Result<()> {
-        info!("Sending version info to {}", addr);
-
+    /// Builds the version we open the handshake with
+    async fn version_message(&self) -> Result<VersionMessage> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
 
-        let msg = VersionMessage {
+        Ok(VersionMessage {
             addr_from: self.node_address.clone(),
             version: VERSION,
-            best_height: self.get_best_height()?,
+            best_height: self.get_best_height().await?,
             timestamp: now,
-        };
-
-        self.send_message(addr, "version", &msg)
+        })
     }
 
     /// Sends block inventory to a peer
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+    async fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
         if items.is_empty() {
             return Ok(());
         }
@@ -593,24 +871,38 @@ impl Server {
             items,
         };
 
-        self.send_message(addr, "inv", &msg)
+        self.send_message(addr, "inv", &msg).await
     }
 
-    /// Sends a request for blocks
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
+    /// Sends a request for the blocks after our tip, or after `start_hash`
+    /// if the peer already announced blocks up to it
+    async fn send_get_blocks(&self, addr: &str, start_hash: Option<String>) -> Result<()> {
         info!("Requesting blocks from {}", addr);
 
         let msg = GetBlocksMessage {
             addr_from: self.node_address.clone(),
-            start_hash: None,
-            limit: None,
+            start_hash,
+            locator: self.block_locator().await?,
+            limit: Some(MAX_INV_BLOCKS as u32),
+        };
+
+        self.send_message(addr, "getblocks", &msg).await
+    }
+
+    /// Sends a request for the headers after our best header
+    async fn send_get_headers(&self, addr: &str) -> Result<()> {
+        info!("Requesting headers from {}", addr);
+
+        let msg = GetHeadersMessage {
+            addr_from: self.node_address.clone(),
+            locator: self.header_locator().await?,
         };
 
-        self.send_message(addr, "getblocks", &msg)
+        self.send_message(addr, "getheaders", &msg).await
     }
 
     /// Sends a request for specific data
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+    async fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
         info!("Requesting {} data from {}: {}", kind, addr, id);
 
         let msg = GetDataMessage {
@@ -619,11 +911,11 @@ impl Server {
             id: id.to_string(),
         };
 
-        self.send_message(addr, "getdata", &msg)
+        self.send_message(addr, "getdata", &msg).await
     }
 
     /// Sends a block to a peer
-    fn send_block(&self, addr: &str, block: &Block) -> Result<()> {
+    async fn send_block(&self, addr: &str, block: &Block) -> Result<()> {
         info!("Sending block to {}: {}", addr, block.get_hash());
 
         let msg = BlockMessage {
@@ -631,11 +923,11 @@ impl Server {
             block: block.clone(),
         };
 
-        self.send_message(addr, "block", &msg)
+        self.send_message(addr, "block", &msg).await
     }
 
     /// Sends a transaction to a peer
-    pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
+    pub async fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
         info!("Sending transaction to {}: {}", addr, tx.id);
 
         let msg = TxMessage {
@@ -643,11 +935,11 @@ impl Server {
             transaction: tx.clone(),
         };
 
-        self.send_message(addr, "tx", &msg)
+        self.send_message(addr, "tx", &msg).await
     }
 
     /// Sends a ping to check connectivity
-    fn _send_ping(&self, addr: &str) -> Result<()> {
+    async fn _send_ping(&self, addr: &str) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
@@ -661,11 +953,11 @@ impl Server {
             timestamp: now,
         };
 
-        self.send_message(addr, "ping", &msg)
+        self.send_message(addr, "ping", &msg).await
     }
 
     /// Responds to a ping with a pong
-    fn send_pong(&self, addr: &str, nonce: u64) -> Result<()> {
+    async fn send_pong(&self, addr: &str, nonce: u64) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
@@ -676,61 +968,100 @@ impl Server {
             timestamp: now,
         };
 
-        self.send_message(addr, "pong", &msg)
+        self.send_message(addr, "pong", &msg).await
     }
 
     /// Generic method to send a message to a peer
-    fn send_message<T: Serialize>(&self, addr: &str, cmd: &str, payload: &T) -> Result<()> {
+    ///
+    /// The message is queued on our connection to `addr`, which is opened
+    /// first if there is none.
+    async fn send_message<T: Serialize>(&self, addr: &str, cmd: &str, payload: &T) -> Result<()> {
         if addr == self.node_address {
             return Ok(());
         }
 
-        let cmd_bytes = cmd_to_bytes(cmd);
-        let payload_bytes = serialize(payload)?;
-        let mut message = Vec::with_capacity(CMD_LEN + payload_bytes.len());
-
-        message.extend_from_slice(&cmd_bytes);
-        message.extend_from_slice(&payload_bytes);
-
-        self.send_data(addr, &message)
-    }
+        let frame = encode(cmd, &serialize(payload)?)?;
+        self.connect(addr).await?;
 
-    /// Sends raw data to a peer
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == self.node_address {
-            return Ok(());
+        let queued = {
+            let inner = self.inner.lock().unwrap();
+            match inner.connections.get(addr) {
+                Some(conn) => conn.queue.try_send(frame).map_err(|e| (conn.id, e)),
+                None => return Err(format_err!("Connection to {} closed", addr)),
+            }
+        };
+        match queued {
+            Ok(()) => Ok(()),
+            Err((id, TrySendError::Full(_))) => {
+                warn!("Outbound queue to {} is full, disconnecting", addr);
+                self.disconnect(addr, id);
+                Err(format_err!("Peer {} is not keeping up", addr))
+            }
+            Err((_, TrySendError::Closed(_))) => Err(format_err!("Connection to {} closed", addr)),
         }
+    }
 
-        let mut stream = match TcpStream::connect_timeout(
-            &addr.parse::<SocketAddr>()?,
-            Duration::from_secs(NETWORK_TIMEOUT),
-        ) {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("Failed to connect to {}: {}", addr, e);
-                self.mark_peer_failed(addr);
-                return Err(format_err!("Connection failed: {}", e));
+    /// Opens a connection to `addr` unless we already have one
+    ///
+    /// Boxed because opening a connection spawns the task that handles its
+    /// messages, and handling messages can open connections; the boxed
+    /// future ends that cycle with a type known to be `Send`.
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if self.is_connected(addr) {
+                return Ok(());
             }
-        };
+            if self.is_stopping() {
+                return Err(format_err!("Server is stopping"));
+            }
+            if addr == self.node_address {
+                return Err(format_err!("Refusing to connect to ourselves"));
+            }
+            if peers::ip_of(addr).is_some_and(|ip| self.is_banned(ip)) {
+                return Err(format_err!("Peer {} is banned", addr));
+            }
+            if self.connection_count(true) >= MAX_OUTBOUND_PEERS {
+                return Err(format_err!("Outbound peer limit reached"));
+            }
+
+            let stream = match dial(addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", addr, e);
+                    self.mark_peer_failed(addr);
+                    return Err(format_err!("Connection failed: {}", e));
+                }
+            };
 
-        stream.set_write_timeout(Some(Duration::from_secs(NETWORK_TIMEOUT)))?;
+            self.open_connection(stream, Some(addr))
+                .await
+                .inspect_err(|_| self.mark_peer_failed(addr))
+        })
+    }
 
-        match stream.write_all(data) {
-            Ok(_) => {
-                stream.flush()?;
-                self.update_peer_status(addr, PeerStatus::Connected);
-                Ok(())
-            }
-            Err(e) => {
-                warn!("Failed to send data to {}: {}", addr, e);
-                self.mark_peer_failed(addr);
-                Err(format_err!("Send failed: {}", e))
+    /// Connects to known peers that are due for an attempt, as far as
+    /// outbound slots allow
+    async fn connect_peers(&self) {
+        let addrs: Vec<String> = {
+            let inner = self.inner.lock().unwrap();
+            let free = MAX_OUTBOUND_PEERS.saturating_sub(outbound_count(&inner));
+            inner
+                .peers
+                .dialable(peers::now())
+                .into_iter()
+                .filter(|addr| !inner.connections.contains_key(addr))
+                .take(free)
+                .collect()
+        };
+        for addr in addrs {
+            if let Err(e) = self.connect(&addr).await {
+                warn!("Failed to connect to {}: {}", addr, e);
             }
         }
     }
 
     /// Handles incoming connections
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
         let peer_addr = match stream.peer_addr() {
             Ok(addr) => addr.to_string(),
             Err(_) => "unknown".to_string(),
@@ -738,203 +1069,429 @@ impl Server {
 
         info!("Handling connection from {}", peer_addr);
 
-        stream.set_read_timeout(Some(Duration::from_secs(NETWORK_TIMEOUT)))?;
+        self.open_connection(stream, None)
+            .await
+            .map_err(|e| format_err!("Connection from {} failed: {}", peer_addr, e))
+    }
 
-        let mut buffer = vec![0; READ_BUFFER_SIZE];
+    /// Handshakes on a new connection and starts its reader and writer tasks
+    ///
+    /// `dialed` is the address we connected to, or `None` for a peer that
+    /// connected to us; such a peer is known by its socket address, and the
+    /// address it says it listens on is remembered for us to dial later.
+    async fn open_connection(&self, mut stream: TcpStream, dialed: Option<&str>) -> Result<()> {
+        let version = tokio::select! {
+            version = self.handshake(&mut stream) => version?,
+            _ = self.stopping() => return Err(format_err!("Server is stopping")),
+        };
+        let socket = stream.peer_addr()?;
+        let key = match dialed {
+            Some(addr) => addr.to_string(),
+            None => socket.to_string(),
+        };
+        if key == self.node_address {
+            return Err(format_err!("Connected to ourselves"));
+        }
+        if self.is_banned(socket.ip()) {
+            return Err(format_err!("Peer {} is banned", key));
+        }
 
-        let bytes_read = match stream.read(&mut buffer) {
-            Ok(count) => {
-                if count == 0 {
-                    return Err(format_err!("Empty message from {}", peer_addr));
+        // Idle peers stay connected; only writes time out
+        let (reader, mut writer_half) = stream.into_split();
+        let (queue, mut frames) = mpsc::channel::<Vec<u8>>(OUTBOUND_QUEUE_SIZE);
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = frames.recv().await {
+                let timeout = Duration::from_secs(NETWORK_TIMEOUT);
+                match time::timeout(timeout, writer_half.write_all(&frame)).await {
+                    Ok(Ok(())) => {}
+                    _ => break,
                 }
-                count
             }
-            Err(e) => {
-                return Err(format_err!("Read error from {}: {}", peer_addr, e));
+            let _ = writer_half.shutdown().await;
+        });
+        let (close, closed) = oneshot::channel();
+
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            if self.is_stopping() {
+                return Err(format_err!("Server is stopping"));
+            }
+            let id = inner.next_connection_id;
+            inner.next_connection_id += 1;
+            let conn = PeerConnection {
+                id,
+                outbound: dialed.is_some(),
+                ip: socket.ip(),
+                queue,
+                writer,
+                _close: close,
+                requests: HashMap::new(),
+            };
+            if !register_connection(&mut inner, &key, conn) {
+                debug!("Not keeping connection to {}", key);
+                return Ok(());
+            }
+            if dialed.is_some() {
+                inner.peers.connected(&key, peers::now());
+            } else if let Some(addr) = listen_address(&version.addr_from, socket) {
+                if addr != self.node_address {
+                    inner.peers.add(&addr);
+                }
+            }
+            id
+        };
+        info!("Connected to {} ({})", key, socket);
+
+        let server = self.clone();
+        self.spawn(async move {
+            server
+                .read_messages(reader, closed, &key, id, version)
+                .await
+        });
+        Ok(())
+    }
+
+    /// Exchanges version and verack with a new connection
+    ///
+    /// Both sides send their version first and acknowledge the other's;
+    /// anything else before the handshake completes, or taking longer than
+    /// `NETWORK_TIMEOUT`, ends the connection.
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<VersionMessage> {
+        let exchange = async {
+            write_frame(
+                stream,
+                "version",
+                &serialize(&self.version_message().await?)?,
+            )
+            .await?;
+
+            let mut version: Option<VersionMessage> = None;
+            let mut acked = false;
+            while version.is_none() || !acked {
+                let (cmd, payload) = read_frame(stream).await?;
+                match cmd.as_str() {
+                    "version" if version.is_none() => {
+                        let msg: VersionMessage = deserialize(&payload)?;
+                        if msg.version != VERSION {
+                            return Err(format_err!(
+                                "Unsupported protocol version {}",
+                                msg.version
+                            ));
+                        }
+                        write_frame(stream, "verack", &[]).await?;
+                        version = Some(msg);
+                    }
+                    "verack" if !acked => acked = true,
+                    _ => return Err(format_err!("Unexpected '{}' during handshake", cmd)),
+                }
             }
+            Ok(version.unwrap())
         };
+        time::timeout(Duration::from_secs(NETWORK_TIMEOUT), exchange)
+            .await
+            .map_err(|_| format_err!("Handshake timed out"))?
+    }
 
-        buffer.truncate(bytes_read);
+    /// Handles messages from a connected peer until the connection closes
+    /// or is dropped, which resolves `closed`
+    ///
+    /// When the server stops, the connection is left for
+    /// `close_connections` to flush.
+    async fn read_messages(
+        &self,
+        mut reader: OwnedReadHalf,
+        mut closed: oneshot::Receiver<()>,
+        peer: &str,
+        id: u64,
+        version: VersionMessage,
+    ) {
+        if let Err(e) = self.handle_version(version, peer).await {
+            warn!("Error handling version from {}: {}", peer, e);
+        }
 
-        if buffer.len() < CMD_LEN {
-            return Err(format_err!("Message too short from {}", peer_addr));
+        loop {
+            let frame = tokio::select! {
+                frame = read_frame(&mut reader) => frame,
+                _ = &mut closed => break,
+                _ = self.stopping() => return,
+            };
+            let (cmd, payload) = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!("Connection to {} closed: {}", peer, e);
+                    break;
+                }
+            };
+            debug!("Received command '{}' from {}", cmd, peer);
+            if let Err(e) = self.process_message(&cmd, &payload, peer).await {
+                warn!("Error handling '{}' from {}: {}", cmd, peer, e);
+            }
         }
 
-        let cmd_bytes = &buffer[..CMD_LEN];
-        let cmd = decode_command(cmd_bytes)?;
-        let payload = &buffer[CMD_LEN..];
+        self.disconnect(peer, id);
+    }
 
-        debug!("Received command '{}' from {}", cmd, peer_addr);
+    /// Closes the connection `id` to `peer`, if it is still the open one
+    fn disconnect(&self, peer: &str, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .connections
+            .get(peer)
+            .is_some_and(|conn| conn.id == id)
+        {
+            inner.connections.remove(peer);
+            inner.peers.disconnected(peer, peers::now());
+            info!("Disconnected from {}", peer);
+        }
+    }
 
-        self.process_message(&cmd, payload, &peer_addr, &mut stream)
+    /// Closes every connection once its queued frames are written
+    pub async fn close_connections(&self) {
+        let connections: Vec<PeerConnection> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.connections.drain().map(|(_, conn)| conn).collect()
+        };
+        for conn in connections {
+            // Dropping the queue lets the writer finish and close the socket
+            drop(conn.queue);
+            if let Err(e) = conn.writer.await {
+                error!("Writer task failed: {}", e);
+            }
+        }
     }
 
-    /// Processes a received message
-    fn process_message(
-        &self,
-        cmd: &str,
-        payload: &[u8],
-        peer_addr: &str,
-        stream: &mut TcpStream,
-    ) -> Result<()> {
+    /// Processes a message from a connected peer
+    async fn process_message(&self, cmd: &str, payload: &[u8], peer: &str) -> Result<()> {
         match cmd {
-            "version" => {
-                let msg: VersionMessage = deserialize(payload)?;
-                self.handle_version(msg, peer_addr)?;
+            "version" | "verack" => {
+                let reason = format!("repeated '{}'", cmd);
+                self.misbehaving(peer, REPEATED_HANDSHAKE_SCORE, &reason);
             }
             "addr" => {
-                let msg: AddrMessage = deserialize(payload)?;
-                self.handle_addr(msg)?;
+                let msg: AddrMessage = self.decode(payload, peer)?;
+                self.handle_addr(msg).await?;
             }
             "block" => {
-                let msg: BlockMessage = deserialize(payload)?;
-                self.handle_block(msg)?;
+                let msg: BlockMessage = self.decode(payload, peer)?;
+                self.handle_block(msg, peer).await?;
             }
             "inv" => {
-                let msg: InvMessage = deserialize(payload)?;
-                self.handle_inv(msg)?;
+                let msg: InvMessage = self.decode(payload, peer)?;
+                self.handle_inv(msg, peer).await?;
             }
             "getblocks" => {
-                let msg: GetBlocksMessage = deserialize(payload)?;
-                self.handle_get_blocks(msg)?;
+                let msg: GetBlocksMessage = self.decode(payload, peer)?;
+                self.handle_get_blocks(msg, peer).await?;
             }
             "getdata" => {
-                let msg: GetDataMessage = deserialize(payload)?;
-                self.handle_get_data(msg)?;
+                let msg: GetDataMessage = self.decode(payload, peer)?;
+                self.handle_get_data(msg, peer).await?;
+            }
+            "getheaders" => {
+                let msg: GetHeadersMessage = self.decode(payload, peer)?;
+                self.handle_get_headers(msg, peer).await?;
+            }
+            "headers" => {
+                let msg: HeadersMessage = self.decode(payload, peer)?;
+                self.handle_headers(msg, peer).await?;
             }
             "tx" => {
-                let msg: TxMessage = deserialize(payload)?;
-                self.handle_tx(msg)?;
+                let msg: TxMessage = self.decode(payload, peer)?;
+                self.handle_tx(msg, peer).await?;
             }
             "ping" => {
-                let msg: PingMessage = deserialize(payload)?;
-                self.handle_ping(msg)?;
+                let msg: PingMessage = self.decode(payload, peer)?;
+                self.handle_ping(msg, peer).await?;
             }
             "pong" => {
-                let msg: PongMessage = deserialize(payload)?;
-                self.handle_pong(msg)?;
+                let msg: PongMessage = self.decode(payload, peer)?;
+                self.handle_pong(msg, peer).await?;
             }
             "signreq" => {
-                let msg: SignRequestMessage = deserialize(payload)?;
-                let response = self.handle_sign_request(msg)?;
-
-                // Direct response needed
-                let cmd_bytes = cmd_to_bytes("signres");
-                let response_bytes = serialize(&response)?;
-                let mut message = Vec::with_capacity(CMD_LEN + response_bytes.len());
-
-                message.extend_from_slice(&cmd_bytes);
-                message.extend_from_slice(&response_bytes);
-
-                stream.write_all(&message)?;
-                stream.flush()?;
+                let msg: SignRequestMessage = self.decode(payload, peer)?;
+                let response = self.handle_sign_request(msg).await?;
+                self.send_message(peer, "signres", &response).await?;
+            }
+            "gettemplate" => {
+                let msg: GetTemplateMessage = self.decode(payload, peer)?;
+                let response = self.handle_get_template(msg).await;
+                self.send_message(peer, "template", &response).await?;
+            }
+            "submitblock" => {
+                let msg: SubmitBlockMessage = self.decode(payload, peer)?;
+                let response = self.handle_submit_block(msg).await;
+                self.send_message(peer, "submitres", &response).await?;
+            }
+            "signres" | "template" | "submitres" => {
+                self.deliver_response(cmd, payload, peer);
             }
             _ => {
-                warn!("Unknown command '{}' from {}", cmd, peer_addr);
+                warn!("Unknown command '{}' from {}", cmd, peer);
             }
         }
 
         Ok(())
     }
 
-    /// Handles version messages
-    fn handle_version(&self, msg: VersionMessage, peer_addr: &str) -> Result<()> {
+    /// Handles the version a peer sent in the handshake
+    async fn handle_version(&self, msg: VersionMessage, peer: &str) -> Result<()> {
         info!(
             "Received version from {}: v{}, height {}",
-            msg.addr_from, msg.version, msg.best_height
+            peer, msg.version, msg.best_height
         );
 
-        // Update peer info
-        self.update_peer(&msg.addr_from, msg.best_height, PeerStatus::Connected);
-
-        // Compare blockchain heights
-        let my_height = self.get_best_height()?;
+        // Compare blockchain heights; a peer behind us learns of it from
+        // the version we sent
+        let my_height = self.get_best_height().await?;
 
         if my_height < msg.best_height {
             // Our chain is shorter, request blocks
             info!(
                 "Our blockchain ({}) is behind {} ({})",
-                my_height, msg.addr_from, msg.best_height
-            );
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_height > msg.best_height {
-            // Our chain is longer, send our version
-            info!(
-                "Our blockchain ({}) is ahead of {} ({})",
-                my_height, msg.addr_from, msg.best_height
+                my_height, peer, msg.best_height
             );
-            self.send_version(&msg.addr_from)?;
-        }
-
-        // Send address list if peer is new
-        if msg.addr_from != peer_addr {
-            // This means the peer is using a different address for its node_address
-            // than the socket address we're communicating with
-            self.update_peer(peer_addr, msg.best_height, PeerStatus::Connected);
+            self.send_get_blocks(peer, None).await?;
         }
 
         // Share our known addresses
-        self.send_addr(&msg.addr_from)?;
+        self.send_addr(peer).await?;
 
         Ok(())
     }
 
     /// Handles address messages
-    fn handle_addr(&self, msg: AddrMessage) -> Result<()> {
+    async fn handle_addr(&self, msg: AddrMessage) -> Result<()> {
         info!(
             "Received {} addresses from {}",
             msg.addresses.len(),
             msg.addr_from
         );
 
+        let mut discovered = false;
         for addr in msg.addresses {
-            if addr != self.node_address && !self.is_peer_known(&addr) {
+            if addr != self.node_address && self.add_peer(&addr) {
                 info!("Discovered new peer: {}", addr);
-                self.add_peer(&addr);
-
-                // Send version to new peer
-                if let Err(e) = self.send_version(&addr) {
-                    warn!("Failed to send version to new peer {}: {}", addr, e);
-                }
+                discovered = true;
             }
         }
 
+        if discovered {
+            // Dial without holding up the connection this came on
+            let server = self.clone();
+            self.spawn(async move { server.connect_peers().await });
+        }
+
         Ok(())
     }
 
     /// Handles block messages
-    fn handle_block(&self, msg: BlockMessage) -> Result<()> {
-        info!(
-            "Received block from {}: {}",
-            msg.addr_from,
-            msg.block.get_hash()
-        );
-
-        // Add block to our chain
-        self.add_block(msg.block.clone())?;
+    async fn handle_block(&self, msg: BlockMessage, peer: &str) -> Result<()> {
+        let hash = msg.block.get_hash();
+        info!("Received block from {}: {}", peer, hash);
 
-        // Process any blocks in transit
-        let mut in_transit = self.get_blocks_in_transit();
-        if !in_transit.is_empty() {
-            let block_hash = in_transit.remove(0);
-            self.set_blocks_in_transit(in_transit);
+        let prev_hash = msg.block.get_prev_hash();
+        self.finish_download(&hash);
 
-            // Request next block
-            self.send_get_data(&msg.addr_from, "block", &block_hash)?;
-        } else {
-            // No more blocks in transit, reindex UTXO
-            info!("Blockchain sync complete, reindexing UTXO set");
-            self.reindex_utxo()?;
-        }
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash).await? {
+            if self.has_header(&hash).await? {
+                // Bodies download in parallel; hold on until the parent arrives
+                self.add_pending_block(msg.block);
+            } else {
+                // A block we cannot place; learn its chain's headers first
+                self.send_get_headers(peer).await?;
+            }
+            return Ok(());
+        }
+
+        // Add block to our chain; it may switch us to another branch
+        if let Err(e) = self.accept_block(msg.block).await {
+            if let Some(rule) = invalid_block(&e) {
+                self.ban_peer(peer, rule);
+            }
+            return Err(e);
+        }
+
+        // Blocks that were waiting for this one can follow it now
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.take_pending_blocks(&parent) {
+                let child_hash = child.get_hash();
+                match self.accept_block(child).await {
+                    Ok(_) => parents.push(child_hash),
+                    Err(e) => warn!("Dropping block {}: {}", child_hash, e),
+                }
+            }
+        }
+
+        if !self.request_blocks(peer).await? {
+            // The UTXO set followed every block
+            info!("Blockchain sync complete");
+        }
+
+        Ok(())
+    }
+
+    /// Handles getheaders messages
+    async fn handle_get_headers(&self, msg: GetHeadersMessage, peer: &str) -> Result<()> {
+        info!("Received get headers request from {}", peer);
+
+        let headers = self
+            .with_chain(move |chain| {
+                Ok(chain
+                    .utxo
+                    .blockchain
+                    .headers_after(&msg.locator, MAX_HEADERS))
+            })
+            .await?;
+        if headers.is_empty() {
+            return Ok(());
+        }
+
+        let response = HeadersMessage {
+            addr_from: self.node_address.clone(),
+            headers,
+        };
+        self.send_message(peer, "headers", &response).await
+    }
+
+    /// Handles headers messages
+    ///
+    /// Headers are checked and stored as far as they follow the rules; the
+    /// blocks of the best header chain are then requested.
+    async fn handle_headers(&self, msg: HeadersMessage, peer: &str) -> Result<()> {
+        info!("Received {} headers from {}", msg.headers.len(), peer);
+
+        let count = msg.headers.len();
+        let result = self
+            .with_chain(move |chain| {
+                for header in &msg.headers {
+                    chain.utxo.blockchain.add_header(header)?;
+                }
+                Ok(())
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Invalid headers from {}: {}", peer, e);
+            if let Some(rule) = invalid_block(&e) {
+                self.ban_peer(peer, rule);
+            }
+            return Ok(());
+        }
+
+        // A full batch means the peer has more
+        if count == MAX_HEADERS {
+            self.send_get_headers(peer).await?;
+        }
 
+        self.request_blocks(peer).await?;
         Ok(())
     }
 
     /// Handles inventory messages
-    fn handle_inv(&self, msg: InvMessage) -> Result<()> {
+    async fn handle_inv(&self, msg: InvMessage, peer: &str) -> Result<()> {
         info!(
             "Received inventory from {}: {} {} items",
-            msg.addr_from,
+            peer,
             msg.items.len(),
             msg.kind
         );
@@ -945,29 +1502,25 @@ impl Server {
 
         match msg.kind.as_str() {
             "block" => {
-                // Save block hashes for later processing
-                let mut blocks_in_transit = self.get_blocks_in_transit();
-
-                // Add the first block to request immediately
-                if !msg.items.is_empty() {
-                    let block_hash = &msg.items[0];
-                    self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-                    // Add remaining blocks to the in-transit queue
-                    for hash in msg.items.iter().skip(1) {
-                        if !blocks_in_transit.contains(hash) {
-                            blocks_in_transit.push(hash.clone());
-                        }
-                    }
+                // A full batch answers our getblocks and the peer has more
+                if msg.items.len() == MAX_INV_BLOCKS {
+                    self.send_get_blocks(peer, msg.items.last().cloned())
+                        .await?;
+                }
 
-                    self.set_blocks_in_transit(blocks_in_transit);
+                // Unknown blocks are fetched headers first
+                for hash in &msg.items {
+                    if !self.has_header(hash).await? {
+                        return self.send_get_headers(peer).await;
+                    }
                 }
+                self.request_blocks(peer).await?;
             }
             "tx" => {
                 // Request unknown transactions
                 for tx_id in &msg.items {
-                    if !self.has_transaction(tx_id) {
-                        self.send_get_data(&msg.addr_from, "tx", tx_id)?;
+                    if !self.has_transaction(tx_id).await? {
+                        self.send_get_data(peer, "tx", tx_id).await?;
                     }
                 }
             }
@@ -980,38 +1533,46 @@ impl Server {
     }
 
     /// Handles getblocks messages
-    fn handle_get_blocks(&self, msg: GetBlocksMessage) -> Result<()> {
-        info!("Received get blocks request from {}", msg.addr_from);
+    async fn handle_get_blocks(&self, msg: GetBlocksMessage, peer: &str) -> Result<()> {
+        info!("Received get blocks request from {}", peer);
 
-        // Get our block hashes
-        let block_hashes = self.get_block_hashes();
+        // Hashes announced to the sender beyond its own chain come first
+        let mut locator = msg.locator;
+        if let Some(start_hash) = msg.start_hash {
+            locator.insert(0, start_hash);
+        }
+        let limit = msg
+            .limit
+            .map_or(MAX_INV_BLOCKS, |limit| (limit as usize).min(MAX_INV_BLOCKS));
 
-        // Send inventory of our blocks
-        self.send_inv(&msg.addr_from, "block", block_hashes)?;
+        let block_hashes = self
+            .with_chain(move |chain| Ok(chain.utxo.blockchain.hashes_after(&locator, limit)))
+            .await?;
 
-        Ok(())
+        // Send the hashes the sender is missing, oldest first
+        self.send_inv(peer, "block", block_hashes).await
     }
 
     /// Handles getdata messages
-    fn handle_get_data(&self, msg: GetDataMessage) -> Result<()> {
+    async fn handle_get_data(&self, msg: GetDataMessage, peer: &str) -> Result<()> {
         info!(
             "Received get data request from {} for {} {}",
-            msg.addr_from, msg.kind, msg.id
+            peer, msg.kind, msg.id
         );
 
         match msg.kind.as_str() {
             "block" => {
                 // Send requested block
-                if let Ok(block) = self.get_block(&msg.id) {
-                    self.send_block(&msg.addr_from, &block)?;
+                if let Ok(block) = self.get_block(&msg.id).await {
+                    self.send_block(peer, &block).await?;
                 } else {
                     warn!("Block not found: {}", msg.id);
                 }
             }
             "tx" => {
                 // Send requested transaction
-                if let Some(tx) = self.get_mempool_tx(&msg.id) {
-                    self.send_tx(&msg.addr_from, &tx)?;
+                if let Some(tx) = self.get_mempool_tx(&msg.id).await? {
+                    self.send_tx(peer, &tx).await?;
                 } else {
                     warn!("Transaction not found in mempool: {}", msg.id);
                 }
@@ -1025,57 +1586,72 @@ impl Server {
     }
 
     /// Handles transaction messages
-    fn handle_tx(&self, msg: TxMessage) -> Result<()> {
+    async fn handle_tx(&self, msg: TxMessage, peer: &str) -> Result<()> {
         let tx_id = &msg.transaction.id;
-        info!("Received transaction from {}: {}", msg.addr_from, tx_id);
+        info!("Received transaction from {}: {}", peer, tx_id);
 
-        // Add to mempool
-        self.add_to_mempool(msg.transaction.clone());
+        if self.has_transaction(tx_id).await? {
+            return Ok(());
+        }
+
+        // Add to mempool, dropping transactions that could never be mined
+        if let Err(e) = self.accept_to_mempool(msg.transaction.clone()).await {
+            warn!("Rejected transaction {}: {}", tx_id, e);
+            if let Some(rule) = invalid_transaction(&e) {
+                self.ban_peer(peer, rule);
+            }
+            return Ok(());
+        }
 
         // Relay to other peers
-        let peers = self.get_peers();
-        for peer in peers {
-            if peer != self.node_address && peer != msg.addr_from {
-                self.send_inv(&peer, "tx", vec![tx_id.clone()])?;
+        for other in self.get_peers() {
+            if other != self.node_address && other != peer {
+                self.send_inv(&other, "tx", vec![tx_id.clone()]).await?;
             }
         }
 
-        // If we're a mining node, process mempool
+        // If we're a mining node, process mempool without holding up
+        // this peer's messages
         if !self.mining_address.is_empty() {
-            self.process_mempool()?;
+            let server = self.clone();
+            self.spawn(async move {
+                if let Err(e) = server.process_mempool().await {
+                    error!("Mempool processing error: {}", e);
+                }
+            });
         }
 
         Ok(())
     }
 
     /// Handles ping messages
-    fn handle_ping(&self, msg: PingMessage) -> Result<()> {
-        debug!("Received ping from {}, nonce {}", msg.addr_from, msg.nonce);
+    async fn handle_ping(&self, msg: PingMessage, peer: &str) -> Result<()> {
+        debug!("Received ping from {}, nonce {}", peer, msg.nonce);
 
         // Send pong response
-        self.send_pong(&msg.addr_from, msg.nonce)?;
+        self.send_pong(peer, msg.nonce).await?;
 
         Ok(())
     }
 
     /// Handles pong messages
-    fn handle_pong(&self, msg: PongMessage) -> Result<()> {
+    async fn handle_pong(&self, msg: PongMessage, peer: &str) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
 
         let rtt = now.saturating_sub(msg.timestamp);
 
-        debug!("Received pong from {}, RTT: {}ms", msg.addr_from, rtt);
+        debug!("Received pong from {}, RTT: {}ms", peer, rtt);
 
         // Update ping time
-        self.update_ping_time(&msg.addr_from, rtt);
+        self.update_ping_time(peer, rtt);
 
         Ok(())
     }
 
     /// Handles transaction signing requests
-    fn handle_sign_request(&self, msg: SignRequestMessage) -> Result<SignResponseMessage> {
+    async fn handle_sign_request(&self, msg: SignRequestMessage) -> Result<SignResponseMessage> {
         info!(
             "Received sign request from {} for wallet {}",
             msg.addr_from, msg.address
@@ -1094,25 +1670,29 @@ impl Server {
             }
         };
 
-        // Find requested wallet
-        let wallet = match wallets.get_wallet(&msg.address) {
-            Some(wallet) => wallet,
-            None => {
+        // Find requested wallet; encrypted wallets must have been unlocked
+        let wallet = match wallets.get_signing_wallet(&msg.address) {
+            Ok(wallet) => wallet.clone(),
+            Err(e) => {
                 return Ok(SignResponseMessage {
                     addr_from: self.node_address.clone(),
                     transaction: msg.transaction.clone(),
                     success: false,
-                    error_message: format!("Wallet not found: {}", msg.address),
+                    error_message: e.to_string(),
                 });
             }
         };
 
-        // Sign the transaction
+        // Bind every input to this wallet's key and scheme before signing
         let mut tx = msg.transaction.clone();
-        let crypto = FnDsaCrypto;
+        for vin in &mut tx.vin {
+            vin.pub_key = wallet.public_key.clone();
+            vin.encryption = wallet.encryption;
+        }
+        tx.id = tx.hash()?;
 
-        match self.sign_transaction(&mut tx, &wallet.secret_key, &crypto) {
-            Ok(_) => {
+        match self.sign_transaction(tx, wallet).await {
+            Ok(tx) => {
                 info!("Successfully signed transaction for {}", msg.address);
                 Ok(SignResponseMessage {
                     addr_from: self.node_address.clone(),
@@ -1134,7 +1714,7 @@ impl Server {
     }
 
     /// Sends a transaction signing request to a remote node
-    pub fn send_sign_request(
+    pub async fn send_sign_request(
         &self,
         addr: &str,
         wallet_addr: &str,
@@ -1150,144 +1730,253 @@ impl Server {
             address: wallet_addr.to_string(),
             transaction: tx.clone(),
         };
+        let payload = self.send_request(addr, "signreq", &msg, "signres").await?;
+        let response: SignResponseMessage = deserialize(&payload)?;
 
-        let cmd_bytes = cmd_to_bytes("signreq");
-        let msg_bytes = serialize(&msg)?;
-        let mut message = Vec::with_capacity(CMD_LEN + msg_bytes.len());
+        if response.success {
+            Ok(response.transaction)
+        } else {
+            Err(format_err!("Signing failed: {}", response.error_message))
+        }
+    }
 
-        message.extend_from_slice(&cmd_bytes);
-        message.extend_from_slice(&msg_bytes);
+    /// Handles requests for a block to mine
+    async fn handle_get_template(&self, msg: GetTemplateMessage) -> TemplateMessage {
+        info!(
+            "Received template request from {} paying {}",
+            msg.addr_from, msg.mining_address
+        );
 
-        // Connect to remote node
-        let mut stream = match TcpStream::connect_timeout(
-            &addr.parse::<SocketAddr>()?,
-            Duration::from_secs(NETWORK_TIMEOUT),
-        ) {
-            Ok(stream) => stream,
+        let mining_address = msg.mining_address.clone();
+        let template = self
+            .with_chain(move |chain| {
+                let txs = chain.select_transactions()?;
+                chain.create_template(&mining_address, txs)
+            })
+            .await;
+        match template {
+            Ok(template) => TemplateMessage {
+                addr_from: self.node_address.clone(),
+                template: Some(template),
+                error_message: String::new(),
+            },
             Err(e) => {
-                error!("Failed to connect to {}: {}", addr, e);
-                self.mark_peer_failed(addr);
-                return Err(format_err!("Connection failed: {}", e));
+                warn!("Failed to build a template for {}: {}", msg.addr_from, e);
+                TemplateMessage {
+                    addr_from: self.node_address.clone(),
+                    template: None,
+                    error_message: e.to_string(),
+                }
             }
-        };
-
-        // Set timeouts
-        stream.set_write_timeout(Some(Duration::from_secs(NETWORK_TIMEOUT)))?;
-        stream.set_read_timeout(Some(Duration::from_secs(NETWORK_TIMEOUT)))?;
-
-        // Send request
-        stream.write_all(&message)?;
-        stream.flush()?;
-
-        // Read response
-        let mut buffer = vec![0; READ_BUFFER_SIZE];
-        let count = stream.read(&mut buffer)?;
-
-        if count == 0 {
-            return Err(format_err!("Empty response from {}", addr));
         }
+    }
 
-        buffer.truncate(count);
-
-        if buffer.len() < CMD_LEN {
-            return Err(format_err!("Response too short from {}", addr));
+    /// Handles blocks mined by an external miner
+    ///
+    /// A valid block is stored, and announced to peers if it became our tip.
+    async fn handle_submit_block(&self, msg: SubmitBlockMessage) -> SubmitResponseMessage {
+        let hash = msg.block.get_hash();
+        info!("Received mined block {} from {}", hash, msg.addr_from);
+
+        let result = match self.accept_block(msg.block.clone()).await {
+            Ok(change) if change.is_empty() => {
+                info!("Submitted block {} is not on the best chain", hash);
+                Ok(())
+            }
+            Ok(_) => self.announce_block(&msg.block).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => SubmitResponseMessage {
+                addr_from: self.node_address.clone(),
+                accepted: true,
+                error_message: String::new(),
+            },
+            Err(e) => {
+                warn!("Rejected block {} from {}: {}", hash, msg.addr_from, e);
+                SubmitResponseMessage {
+                    addr_from: self.node_address.clone(),
+                    accepted: false,
+                    error_message: e.to_string(),
+                }
+            }
         }
+    }
 
-        let cmd_bytes = &buffer[..CMD_LEN];
-        let cmd = decode_command(cmd_bytes)?;
+    /// Asks a node for a block to mine, paying `mining_address`
+    pub async fn get_block_template(&self, addr: &str, mining_address: &str) -> Result<Block> {
+        let msg = GetTemplateMessage {
+            addr_from: self.node_address.clone(),
+            mining_address: mining_address.to_string(),
+        };
+        let payload = self
+            .send_request(addr, "gettemplate", &msg, "template")
+            .await?;
+        let response: TemplateMessage = deserialize(&payload)?;
+
+        response
+            .template
+            .ok_or_else(|| format_err!("No template: {}", response.error_message))
+    }
+
+    /// Hands a node a block mined from one of its templates
+    pub async fn submit_block(&self, addr: &str, block: &Block) -> Result<()> {
+        let msg = SubmitBlockMessage {
+            addr_from: self.node_address.clone(),
+            block: block.clone(),
+        };
+        let payload = self
+            .send_request(addr, "submitblock", &msg, "submitres")
+            .await?;
+        let response: SubmitResponseMessage = deserialize(&payload)?;
 
-        if cmd != "signres" {
-            return Err(format_err!("Unexpected response command: {}", cmd));
+        if response.accepted {
+            Ok(())
+        } else {
+            Err(format_err!("Block rejected: {}", response.error_message))
         }
+    }
 
-        let payload = &buffer[CMD_LEN..];
-        let response: SignResponseMessage = deserialize(payload)?;
+    /// Sends a request and returns the payload of the `response_cmd` reply
+    ///
+    /// The request goes over our connection to `addr` like any message, and
+    /// the reader hands the reply back. Peers answer in order, so a request
+    /// left unanswered for `NETWORK_TIMEOUT` ends the connection; its late
+    /// reply would otherwise be taken for the next request's.
+    async fn send_request<T: Serialize>(
+        &self,
+        addr: &str,
+        cmd: &str,
+        payload: &T,
+        response_cmd: &str,
+    ) -> Result<Vec<u8>> {
+        self.connect(addr).await?;
+        let (reply, response) = oneshot::channel();
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let conn = inner
+                .connections
+                .get_mut(addr)
+                .ok_or_else(|| format_err!("Connection to {} closed", addr))?;
+            conn.requests
+                .entry(response_cmd.to_string())
+                .or_default()
+                .push_back(reply);
+            conn.id
+        };
+        self.send_message(addr, cmd, payload).await?;
+
+        match time::timeout(Duration::from_secs(NETWORK_TIMEOUT), response).await {
+            Ok(Ok(payload)) => Ok(payload),
+            Ok(Err(_)) => Err(format_err!(
+                "Connection to {} closed before its '{}'",
+                addr,
+                response_cmd
+            )),
+            Err(_) => {
+                self.disconnect(addr, id);
+                Err(format_err!("No '{}' from {}", response_cmd, addr))
+            }
+        }
+    }
 
-        if response.success {
-            Ok(response.transaction)
-        } else {
-            Err(format_err!("Signing failed: {}", response.error_message))
+    /// Hands a reply to the oldest request awaiting it on the connection
+    /// to `peer`
+    fn deliver_response(&self, cmd: &str, payload: &[u8], peer: &str) {
+        let reply = {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .connections
+                .get_mut(peer)
+                .and_then(|conn| conn.requests.get_mut(cmd)?.pop_front())
+        };
+        match reply {
+            // The request may have given up; its reply is dropped
+            Some(reply) => {
+                let _ = reply.send(payload.to_vec());
+            }
+            None => debug!("Ignoring unrequested '{}' from {}", cmd, peer),
         }
     }
 
     // Helper methods for peer management
 
-    /// Adds a new peer
-    fn add_peer(&self, addr: &str) {
+    /// Adds a new peer, returning whether it was new
+    fn add_peer(&self, addr: &str) -> bool {
         let mut inner = self.inner.lock().unwrap();
-
-        if !inner.peers.contains_key(addr) && addr != &self.node_address {
-            inner.peers.insert(
-                addr.to_string(),
-                PeerInfo {
-                    _address: addr.to_string(),
-                    last_seen: Instant::now(),
-                    best_height: -1,
-                    status: PeerStatus::New,
-                },
-            );
-        }
+        addr != self.node_address && inner.peers.add(addr)
     }
 
-    /// Updates peer information
-    fn update_peer(&self, addr: &str, height: i32, status: PeerStatus) {
+    /// Records a failed attempt to connect to a peer
+    fn mark_peer_failed(&self, addr: &str) {
         let mut inner = self.inner.lock().unwrap();
+        inner.peers.failed(addr, peers::now());
+    }
 
-        if let Some(peer) = inner.peers.get_mut(addr) {
-            peer.last_seen = Instant::now();
-            peer.best_height = height;
-            peer.status = status;
-        } else if addr != self.node_address {
-            inner.peers.insert(
-                addr.to_string(),
-                PeerInfo {
-                    _address: addr.to_string(),
-                    last_seen: Instant::now(),
-                    best_height: height,
-                    status,
-                },
-            );
-        }
+    /// Bans a peer that broke a consensus rule and drops our connections to it
+    fn ban_peer(&self, peer: &str, reason: &ValidationError) {
+        self.misbehaving(peer, peers::BAN_THRESHOLD, reason);
     }
 
-    /// Updates peer status
-    fn update_peer_status(&self, addr: &str, status: PeerStatus) {
+    /// Scores misbehaviour by a peer against its IP
+    ///
+    /// Once the IP's score reaches `BAN_THRESHOLD` it is banned and every
+    /// connection from it dropped.
+    fn misbehaving(&self, peer: &str, score: u32, reason: &dyn fmt::Display) {
+        warn!("Peer {} misbehaved: {}", peer, reason);
         let mut inner = self.inner.lock().unwrap();
-
-        if let Some(peer) = inner.peers.get_mut(addr) {
-            peer.last_seen = Instant::now();
-            peer.status = status;
+        let ip = match inner.connections.get(peer) {
+            Some(conn) => conn.ip,
+            None => match peers::ip_of(peer) {
+                Some(ip) => ip,
+                None => return,
+            },
+        };
+        if inner.peers.misbehaved(ip, score, peers::now()) {
+            warn!("Banning {}", ip);
+            // Dropping a connection stops its reader and writer
+            inner.connections.retain(|_, conn| conn.ip != ip);
         }
     }
 
-    /// Marks a peer as failed
-    fn mark_peer_failed(&self, addr: &str) {
-        let mut inner = self.inner.lock().unwrap();
+    /// Checks if an IP is banned
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.is_banned(ip, peers::now())
+    }
 
-        if let Some(peer) = inner.peers.get_mut(addr) {
-            peer.status = PeerStatus::Failed;
-        }
+    /// Decodes the payload of a message from a peer, scoring it as
+    /// misbehaviour if it cannot be decoded
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8], peer: &str) -> Result<T> {
+        deserialize(payload).map_err(|e| {
+            self.misbehaving(peer, MALFORMED_MESSAGE_SCORE, &e);
+            e.into()
+        })
     }
 
-    /// Checks if a peer is known
-    fn is_peer_known(&self, addr: &str) -> bool {
+    /// Checks if we have a connection to a peer
+    fn is_connected(&self, addr: &str) -> bool {
         let inner = self.inner.lock().unwrap();
-        inner.peers.contains_key(addr)
+        inner.connections.contains_key(addr)
     }
 
-    /// Gets all known peers
-    fn get_peers(&self) -> Vec<String> {
+    /// Counts the connections we dialed, or those dialed by peers
+    fn connection_count(&self, outbound: bool) -> usize {
         let inner = self.inner.lock().unwrap();
+        let dialed = outbound_count(&inner);
+        if outbound {
+            dialed
+        } else {
+            inner.connections.len() - dialed
+        }
+    }
 
-        inner
-            .peers
-            .iter()
-            .filter(|(addr, info)| {
-                // Only include connected peers and not ourselves
-                info.status == PeerStatus::Connected && **addr != self.node_address
-            })
-            .map(|(addr, _)| addr.clone())
-            .collect()
+    /// Gets the peers we are connected to, by connection
+    fn get_peers(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers: Vec<String> = inner.connections.keys().cloned().collect();
+        peers.sort();
+        peers
     }
 
     /// Updates ping time for a peer
@@ -1298,112 +1987,333 @@ impl Server {
 
     // Helper methods for blockchain operations
 
-    /// Gets the height of our blockchain
-    fn get_best_height(&self) -> Result<i32> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.get_best_height()
+    /// Runs `f` on the chain state on the blocking pool, holding the state
+    /// throughout
+    async fn with_chain<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ChainState) -> Result<T> + Send + 'static,
+    {
+        let chain = Arc::clone(&self.chain);
+        tokio::task::spawn_blocking(move || f(&mut chain.lock().unwrap())).await?
     }
 
-    /// Gets all block hashes in our blockchain
-    fn get_block_hashes(&self) -> Vec<String> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.get_block_hashs()
+    /// Gets the height of our blockchain
+    async fn get_best_height(&self) -> Result<i32> {
+        self.with_chain(|chain| chain.utxo.blockchain.get_best_height())
+            .await
     }
 
     /// Gets a block by hash
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.get_block(block_hash)
+    async fn get_block(&self, block_hash: &str) -> Result<Block> {
+        let block_hash = block_hash.to_string();
+        self.with_chain(move |chain| chain.utxo.blockchain.get_block(&block_hash))
+            .await
     }
 
-    /// Adds a block to our blockchain
-    fn add_block(&self, block: Block) -> Result<()> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.add_block(block)
+    /// Locator of our block chain
+    async fn block_locator(&self) -> Result<Vec<String>> {
+        self.with_chain(|chain| {
+            let blockchain = &chain.utxo.blockchain;
+            Ok(blockchain.locator(&blockchain.tip))
+        })
+        .await
     }
 
-    /// Mines a new block
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.mine_block(txs)
+    /// Locator of our best header chain
+    async fn header_locator(&self) -> Result<Vec<String>> {
+        self.with_chain(|chain| {
+            let best_header = chain.utxo.blockchain.best_header()?;
+            Ok(chain.utxo.blockchain.locator(&best_header))
+        })
+        .await
     }
 
-    /// Reindexes the UTXO set
-    fn reindex_utxo(&self) -> Result<()> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.reindex()
+    /// Checks whether we have the header `hash`
+    async fn has_header(&self, hash: &str) -> Result<bool> {
+        let hash = hash.to_string();
+        self.with_chain(move |chain| Ok(chain.utxo.blockchain.get_header(&hash)?.is_some()))
+            .await
     }
 
-    /// Updates the UTXO set with a new block
-    fn update_utxo(&self, block: &Block) -> Result<()> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.update(block)
+    /// Checks whether we have the block `hash` itself
+    async fn has_block(&self, hash: &str) -> Result<bool> {
+        let hash = hash.to_string();
+        self.with_chain(move |chain| chain.utxo.blockchain.has_block(&hash))
+            .await
     }
 
-    /// Verifies a transaction
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        let inner = self.inner.lock().unwrap();
-        inner.utxo.blockchain.verify_transacton(tx)
+    /// Blocks of our best header chain we have yet to download
+    async fn missing_blocks(&self, limit: usize) -> Result<Vec<String>> {
+        self.with_chain(move |chain| chain.utxo.blockchain.missing_blocks(limit))
+            .await
     }
 
-    /// Signs a transaction
-    fn sign_transaction(
-        &self,
-        tx: &mut Transaction,
-        private_key: &[u8],
-        crypto: &dyn CryptoProvider,
-    ) -> Result<()> {
-        let inner = self.inner.lock().unwrap();
-        inner
-            .utxo
-            .blockchain
-            .sign_transacton(tx, private_key, crypto)
+    /// Signs a transaction with `wallet`
+    async fn sign_transaction(&self, mut tx: Transaction, wallet: Wallet) -> Result<Transaction> {
+        self.with_chain(move |chain| {
+            let crypto = wallet.crypto_provider();
+            chain
+                .utxo
+                .blockchain
+                .sign_transacton(&mut tx, &wallet.secret_key, crypto.as_ref())?;
+            Ok(tx)
+        })
+        .await
     }
 
     // Helper methods for mempool management
 
-    /// Adds a transaction to the mempool
-    fn add_to_mempool(&self, tx: Transaction) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.mempool.insert(tx.id.clone(), tx);
+    /// Adds a transaction to the mempool if it is valid on our tip and
+    /// spends nothing another mempool transaction already spends
+    async fn accept_to_mempool(&self, tx: Transaction) -> Result<()> {
+        self.with_chain(move |chain| chain.accept_to_mempool(tx))
+            .await
     }
 
-    /// Gets the entire mempool
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        let inner = self.inner.lock().unwrap();
-        inner.mempool.clone()
+    /// Restores the peers and mempool saved when the server last stopped
+    ///
+    /// Saved transactions go through the mempool checks again, as the chain
+    /// may have moved on since.
+    async fn load_state(&self) -> Result<()> {
+        let inner = Arc::clone(&self.inner);
+        self.with_chain(move |chain| {
+            let db = chain.utxo.blockchain.db.clone();
+            let tree = db.open_tree(PEERS_TREE)?;
+            let ban_tree = db.open_tree(BANS_TREE)?;
+            inner.lock().unwrap().peers.attach(tree, ban_tree)?;
+
+            let mut restored = 0;
+            for item in db.open_tree(MEMPOOL_TREE)?.iter() {
+                let (_, tx) = item?;
+                let tx: Transaction = deserialize(&tx)?;
+                let id = tx.id.clone();
+                match chain.accept_to_mempool(tx) {
+                    Ok(()) => restored += 1,
+                    Err(e) => debug!("Dropping saved transaction {}: {}", id, e),
+                }
+            }
+            info!("Restored {} mempool transactions", restored);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Saves the mempool and flushes the blockchain database, which also
+    /// holds the peers the peer manager saved as they changed
+    async fn save_state(&self) -> Result<()> {
+        let db = self
+            .with_chain(|chain| {
+                let db = chain.utxo.blockchain.db.clone();
+                let tree = db.open_tree(MEMPOOL_TREE)?;
+                tree.clear()?;
+                for (id, tx) in &chain.mempool {
+                    tree.insert(id.as_bytes(), serialize(tx)?)?;
+                }
+                Ok(db)
+            })
+            .await?;
+
+        db.flush_async().await?;
+        Ok(())
     }
 
     /// Gets a transaction from the mempool
-    fn get_mempool_tx(&self, tx_id: &str) -> Option<Transaction> {
-        let inner = self.inner.lock().unwrap();
-        inner.mempool.get(tx_id).cloned()
+    async fn get_mempool_tx(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        let tx_id = tx_id.to_string();
+        self.with_chain(move |chain| Ok(chain.mempool.get(&tx_id).cloned()))
+            .await
     }
 
     /// Checks if a transaction is in the mempool
-    fn has_transaction(&self, tx_id: &str) -> bool {
-        let inner = self.inner.lock().unwrap();
-        inner.mempool.contains_key(tx_id)
+    async fn has_transaction(&self, tx_id: &str) -> Result<bool> {
+        let tx_id = tx_id.to_string();
+        self.with_chain(move |chain| Ok(chain.mempool.contains_key(&tx_id)))
+            .await
     }
 
-    /// Clears the mempool
-    fn clear_mempool(&self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.mempool.clear();
+    // Helper methods for block transit management
+
+    /// Requests the missing blocks of our best header chain
+    ///
+    /// Requests are spread over our peers and `preferred`, the peer that
+    /// told us about the blocks. Requests left unanswered for
+    /// `BLOCK_DOWNLOAD_TIMEOUT` go to the next peer. Returns whether any
+    /// blocks are missing.
+    async fn request_blocks(&self, preferred: &str) -> Result<bool> {
+        let missing = self.missing_blocks(MAX_BLOCKS_IN_TRANSIT).await?;
+        let any_missing = !missing.is_empty();
+        let mut peers = self.get_peers();
+        if !peers.contains(&preferred.to_string()) {
+            peers.push(preferred.to_string());
+        }
+
+        let requests: Vec<(String, String)> = {
+            let mut inner = self.inner.lock().unwrap();
+            let timeout = Duration::from_secs(BLOCK_DOWNLOAD_TIMEOUT);
+            inner
+                .blocks_in_transit
+                .retain(|hash, (_, asked)| asked.elapsed() < timeout && missing.contains(hash));
+
+            let mut requests = Vec::new();
+            for hash in missing {
+                if inner.blocks_in_transit.contains_key(&hash)
+                    || inner
+                        .pending_blocks
+                        .values()
+                        .flatten()
+                        .any(|b| b.get_hash() == hash)
+                {
+                    continue;
+                }
+                let peer = peers[inner.download_cursor % peers.len()].clone();
+                inner.download_cursor = inner.download_cursor.wrapping_add(1);
+                inner
+                    .blocks_in_transit
+                    .insert(hash.clone(), (peer.clone(), Instant::now()));
+                requests.push((peer, hash));
+            }
+            requests
+        };
+
+        for (peer, hash) in requests {
+            if let Err(e) = self.send_get_data(&peer, "block", &hash).await {
+                warn!("Failed to request block {} from {}: {}", hash, peer, e);
+                self.finish_download(&hash);
+            }
+        }
+        Ok(any_missing)
     }
 
-    // Helper methods for block transit management
+    /// Forgets the request for a block that arrived or could not be sent
+    fn finish_download(&self, hash: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks_in_transit.remove(hash);
+    }
 
-    /// Gets blocks in transit
-    fn get_blocks_in_transit(&self) -> Vec<String> {
-        let inner = self.inner.lock().unwrap();
-        inner.blocks_in_transit.clone()
+    /// Keeps a block whose parent has not arrived yet
+    fn add_pending_block(&self, block: Block) {
+        let mut inner = self.inner.lock().unwrap();
+        let pending: usize = inner.pending_blocks.values().map(Vec::len).sum();
+        if pending >= MAX_PENDING_BLOCKS {
+            debug!("Too many pending blocks, dropping {}", block.get_hash());
+            return;
+        }
+        inner
+            .pending_blocks
+            .entry(block.get_prev_hash())
+            .or_default()
+            .push(block);
     }
 
-    /// Sets blocks in transit
-    fn set_blocks_in_transit(&self, blocks: Vec<String>) {
+    /// Takes the blocks that were waiting for `parent`
+    fn take_pending_blocks(&self, parent: &str) -> Vec<Block> {
         let mut inner = self.inner.lock().unwrap();
-        inner.blocks_in_transit = blocks;
+        inner.pending_blocks.remove(parent).unwrap_or_default()
+    }
+}
+
+impl ChainState {
+    /// Adds a block and, if it moved our tip, brings the mempool up to date
+    ///
+    /// The UTXO set moves with the block, so the mempool is checked against
+    /// the new tip without anything changing in between.
+    fn accept_block(&mut self, block: Block) -> Result<TipChange> {
+        let change = self.utxo.add_block(block)?;
+        if !change.is_empty() {
+            // Drops the transactions the new blocks confirmed
+            self.update_mempool(&change)?;
+        }
+        Ok(change)
+    }
+
+    /// Builds the unspent outputs as of our tip
+    fn utxo_view(&self) -> Result<UtxoView> {
+        self.utxo.view_at(&self.utxo.blockchain.tip)
+    }
+
+    /// Selects the mempool transactions the next block can hold
+    ///
+    /// Each one spends from the view the previous ones left, so conflicting
+    /// transactions are not mined together.
+    fn select_transactions(&self) -> Result<Vec<Transaction>> {
+        let mut txs = Vec::new();
+        let mut view = self.utxo_view()?;
+        view.fetch(self.mempool.values())?;
+
+        for tx in self.mempool.values() {
+            match check_transaction(tx, &view) {
+                Ok(_) => {
+                    view.apply(tx);
+                    txs.push(tx.clone());
+                }
+                // Invalid transactions from a peer must not stop mining
+                Err(e) => warn!("Invalid transaction in mempool: {}: {}", tx.id, e),
+            }
+        }
+        Ok(txs)
+    }
+
+    /// Builds an unmined block on our tip holding `txs` and a coinbase to `mining_address`
+    fn create_template(&self, mining_address: &str, mut txs: Vec<Transaction>) -> Result<Block> {
+        let cbtx = Transaction::new_coinbase(mining_address.to_string(), String::from("reward!"))?;
+        txs.insert(0, cbtx);
+        self.utxo.block_template(txs)
+    }
+
+    /// Adds a transaction to the mempool if it is valid on our tip and
+    /// spends nothing another mempool transaction already spends
+    fn accept_to_mempool(&mut self, tx: Transaction) -> Result<()> {
+        let mut view = self.utxo_view()?;
+        view.fetch([&tx])?;
+        check_transaction(&tx, &view)?;
+
+        for pending in self.mempool.values() {
+            if let Some(vin) = tx.vin.iter().find(|vin| {
+                pending
+                    .vin
+                    .iter()
+                    .any(|p| p.txid == vin.txid && p.vout == vin.vout)
+            }) {
+                return Err(format_err!(
+                    "output {}:{} is already spent by {} in the mempool",
+                    vin.txid,
+                    vin.vout,
+                    pending.id
+                ));
+            }
+        }
+        self.mempool.insert(tx.id.clone(), tx);
+        Ok(())
+    }
+
+    /// Returns transactions of disconnected blocks to the mempool and drops
+    /// those the new best chain confirmed or made invalid
+    fn update_mempool(&mut self, change: &TipChange) -> Result<()> {
+        let mut view = self.utxo_view()?;
+
+        // Oldest first, so transactions spending each other keep their order
+        let mut pending: Vec<Transaction> = change
+            .disconnected
+            .iter()
+            .rev()
+            .flat_map(|block| block.get_transaction())
+            .filter(|tx| !tx.is_coinbase())
+            .cloned()
+            .collect();
+        pending.extend(self.mempool.drain().map(|(_, tx)| tx));
+        view.fetch(&pending)?;
+
+        for tx in pending {
+            match check_transaction(&tx, &view) {
+                Ok(_) => {
+                    view.apply(&tx);
+                    self.mempool.insert(tx.id.clone(), tx);
+                }
+                Err(e) => debug!("Dropping transaction {} from mempool: {}", tx.id, e),
+            }
+        }
+        Ok(())
     }
 }
 
@@ -1413,55 +2323,128 @@ impl Clone for Server {
             node_address: self.node_address.clone(),
             mining_address: self.mining_address.clone(),
             inner: Arc::clone(&self.inner),
-            running: Arc::clone(&self.running),
+            chain: Arc::clone(&self.chain),
+            miner: self.miner.clone(),
+            tasks: Arc::clone(&self.tasks),
+            shutdown: Arc::clone(&self.shutdown),
+            stopped: Arc::clone(&self.stopped),
         }
     }
 }
 
-/// Converts a command string to a fixed-size byte array
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut bytes = [0; CMD_LEN];
-    for (i, b) in cmd.bytes().enumerate() {
-        if i < CMD_LEN {
-            bytes[i] = b;
-        } else {
-            break;
-        }
+/// Registers a connection under `key`, returning false if it is dropped
+/// because we already have one there or for lack of a free slot
+///
+/// A peer may hold one connection it dialed and one we dialed; only the
+/// address we dial is trusted to be the peer's, so they are not merged.
+fn register_connection(inner: &mut ServerInner, key: &str, conn: PeerConnection) -> bool {
+    if inner.connections.contains_key(key) {
+        return false;
     }
-    bytes
+    let (count, limit) = if conn.outbound {
+        (outbound_count(inner), MAX_OUTBOUND_PEERS)
+    } else {
+        (
+            inner.connections.len() - outbound_count(inner),
+            MAX_PEERS - MAX_OUTBOUND_PEERS,
+        )
+    };
+    if count >= limit {
+        return false;
+    }
+    inner.connections.insert(key.to_string(), conn);
+    true
+}
+
+/// Counts the connections we dialed
+fn outbound_count(inner: &ServerInner) -> usize {
+    inner
+        .connections
+        .values()
+        .filter(|conn| conn.outbound)
+        .count()
+}
+
+/// Returns the consensus rule `err` reports broken, if it is one
+fn broken_rule(err: &failure::Error) -> Option<&ValidationError> {
+    err.iter_chain()
+        .find_map(|cause| cause.downcast_ref::<ValidationError>())
 }
 
-/// Decodes a command from a byte array
-fn decode_command(bytes: &[u8]) -> Result<String> {
-    let mut cmd = String::new();
-    for &b in bytes.iter().take(CMD_LEN) {
-        if b != 0 {
-            cmd.push(b as char);
+/// Returns the rule a block or header from a peer breaks whatever our
+/// chain holds; a missing parent or a clock behind the peer's is no offence
+fn invalid_block(err: &failure::Error) -> Option<&ValidationError> {
+    broken_rule(err).filter(|rule| {
+        !matches!(
+            rule,
+            ValidationError::UnknownParent(_) | ValidationError::TimestampTooNew { .. }
+        )
+    })
+}
+
+/// Returns the rule a transaction from a peer breaks whatever our chain
+/// holds; it is checked against our tip, so the outputs it spends may be
+/// in blocks we do not have yet
+fn invalid_transaction(err: &failure::Error) -> Option<&ValidationError> {
+    broken_rule(err).filter(|rule| {
+        !matches!(
+            rule,
+            ValidationError::MissingOutput { .. } | ValidationError::DuplicateTransaction(_)
+        )
+    })
+}
+
+/// Resolves on the first SIGINT or SIGTERM, with the signal's name
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
         }
     }
-    Ok(cmd)
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.map(|_| "Ctrl-C")
+    }
+}
+
+/// Dial connects to `addr`, giving up after `NETWORK_TIMEOUT`
+async fn dial(addr: &str) -> Result<TcpStream> {
+    let addr: SocketAddr = addr.parse()?;
+    time::timeout(
+        Duration::from_secs(NETWORK_TIMEOUT),
+        TcpStream::connect(addr),
+    )
+    .await
+    .map_err(|_| format_err!("timed out"))?
+    .map_err(Into::into)
+}
+
+/// ListenAddress is where a peer that connected to us accepts connections
+///
+/// `None` for peers that do not listen, which advertise port 0.
+fn listen_address(advertised: &str, socket: SocketAddr) -> Option<String> {
+    let mut addr: SocketAddr = advertised.parse().ok()?;
+    if addr.port() == 0 {
+        return None;
+    }
+    if addr.ip().is_unspecified() {
+        addr.set_ip(socket.ip());
+    }
+    Some(addr.to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::blockchain::blockchain::Blockchain;
+    use crate::crypto::transaction::{TXInput, TXOutput};
     use crate::crypto::types::EncryptionType;
     use crate::crypto::wallets::Wallets;
 
-    #[test]
-    fn test_cmd_conversion() {
-        let cmd = "version";
-        let bytes = cmd_to_bytes(cmd);
-        let decoded = decode_command(&bytes).unwrap();
-        assert_eq!(cmd, decoded);
-
-        let cmd = "a_very_long_command_that_exceeds_length";
-        let bytes = cmd_to_bytes(cmd);
-        let decoded = decode_command(&bytes).unwrap();
-        assert_eq!(&cmd[..CMD_LEN], decoded);
-    }
-
     #[test]
     fn test_server_creation() {
         let mut wallets = Wallets::new().unwrap();
@@ -1471,7 +2454,7 @@ mod tests {
         let bc =
             Blockchain::new().unwrap_or_else(|_| Blockchain::create_blockchain(address).unwrap());
 
-        let utxo_set = UTXOSet { blockchain: bc };
+        let utxo_set = temp_utxo_set(bc);
         let server = Server::new("127.0.0.1", "7000", "", None, utxo_set).unwrap();
 
         assert_eq!(server.node_address, "127.0.0.1:7000");
@@ -1480,4 +2463,412 @@ mod tests {
         let inner = server.inner.lock().unwrap();
         assert!(inner.peers.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_sign_request_requires_unlocked_wallet() {
+        let mut wallets = Wallets::new().unwrap();
+        let address = wallets.create_wallet(EncryptionType::ECDSA);
+        wallets.save_all().unwrap();
+        wallets.encrypt_wallet(&address, "passphrase").unwrap();
+
+        let bc = Blockchain::new()
+            .unwrap_or_else(|_| Blockchain::create_blockchain(address.clone()).unwrap());
+        let utxo_set = temp_utxo_set(bc);
+        let server = Server::new("127.0.0.1", "7000", "", None, utxo_set).unwrap();
+        let request = SignRequestMessage {
+            addr_from: String::from("10.0.0.1:7001"),
+            address: address.clone(),
+            transaction: Transaction {
+                id: String::new(),
+                vin: Vec::new(),
+                vout: Vec::new(),
+            },
+        };
+
+        let response = server.handle_sign_request(request.clone()).await.unwrap();
+        assert!(!response.success);
+        assert!(response.error_message.contains("locked"));
+
+        Wallets::new()
+            .unwrap()
+            .unlock_wallet(&address, "passphrase")
+            .unwrap();
+        let response = server.handle_sign_request(request).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transactions_stay_out_of_mempool() {
+        let mut wallets = Wallets::new().unwrap();
+        let address = wallets.create_wallet(EncryptionType::ECDSA);
+        wallets.save_all().unwrap();
+        let wallet = wallets.get_wallet(&address).unwrap();
+
+        let bc = Blockchain::new()
+            .unwrap_or_else(|_| Blockchain::create_blockchain(address.clone()).unwrap());
+        let utxo_set = temp_utxo_set(bc);
+        let server = Server::new("127.0.0.1", "7000", "", None, utxo_set).unwrap();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::from("missing"),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+                encryption: EncryptionType::ECDSA,
+            }],
+            vout: vec![TXOutput::new(5, address.clone()).unwrap()],
+        };
+        tx.id = tx.hash().unwrap();
+
+        let err = server.accept_to_mempool(tx.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+
+        let msg = TxMessage {
+            addr_from: String::from("10.0.0.1:7001"),
+            transaction: tx.clone(),
+        };
+        server.handle_tx(msg, "10.0.0.1:7001").await.unwrap();
+        assert!(!server.has_transaction(&tx.id).await.unwrap());
+        // The output may be in a block we have not seen yet
+        assert!(!server.is_banned("10.0.0.1".parse().unwrap()));
+
+        // A transaction without inputs is invalid whatever our chain holds
+        let tx = Transaction {
+            id: String::new(),
+            vin: Vec::new(),
+            vout: vec![TXOutput::new(5, address).unwrap()],
+        };
+        let msg = TxMessage {
+            addr_from: String::from("10.0.0.2:7002"),
+            transaction: tx,
+        };
+        server.handle_tx(msg, "10.0.0.2:7002").await.unwrap();
+        assert!(server.is_banned("10.0.0.2".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_external_miner_round_trip() {
+        let mut wallets = Wallets::new().unwrap();
+        let address = wallets.create_wallet(EncryptionType::ECDSA);
+        wallets.save_all().unwrap();
+
+        // A chain of its own, so the template builds on a known genesis
+        let mut bc = Blockchain {
+            tip: String::new(),
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            params: Default::default(),
+        };
+        let cbtx = Transaction::new_coinbase(address.clone(), String::new()).unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx, &bc.params).unwrap())
+            .unwrap();
+        let utxo_set = temp_utxo_set(bc);
+        let server = Server::new("127.0.0.1", "7000", "", None, utxo_set).unwrap();
+
+        let response = server
+            .handle_get_template(GetTemplateMessage {
+                addr_from: String::from("10.0.0.1:7001"),
+                mining_address: String::from("not an address"),
+            })
+            .await;
+        assert!(response.template.is_none());
+        assert!(!response.error_message.is_empty());
+
+        let template = server
+            .handle_get_template(GetTemplateMessage {
+                addr_from: String::from("10.0.0.1:7001"),
+                mining_address: address,
+            })
+            .await
+            .template
+            .unwrap();
+        assert!(template.get_transaction()[0].is_coinbase());
+
+        // An unsolved template is turned away
+        let response = server
+            .handle_submit_block(SubmitBlockMessage {
+                addr_from: String::from("10.0.0.1:7001"),
+                block: template.clone(),
+            })
+            .await;
+        assert!(!response.accepted);
+
+        // A peer relaying it is banned
+        let msg = BlockMessage {
+            addr_from: String::from("10.0.0.2:7002"),
+            block: template.clone(),
+        };
+        assert!(server.handle_block(msg, "10.0.0.2:7002").await.is_err());
+        assert!(server.is_banned("10.0.0.2".parse().unwrap()));
+
+        let miner = Miner::new(2);
+        let block = miner.mine(template, miner.epoch()).unwrap().unwrap();
+        let response = server
+            .handle_submit_block(SubmitBlockMessage {
+                addr_from: String::from("10.0.0.1:7001"),
+                block: block.clone(),
+            })
+            .await;
+        assert!(response.accepted, "{}", response.error_message);
+        assert!(server.get_block(&block.get_hash()).await.is_ok());
+    }
+
+    /// Wraps a chain in a UTXO set kept in a throwaway database
+    fn temp_utxo_set(blockchain: Blockchain) -> UTXOSet {
+        UTXOSet {
+            blockchain,
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+
+    /// Starts a server on a free local port with a chain of its own
+    async fn start_local_server() -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let bc = Blockchain {
+            tip: String::new(),
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            params: Default::default(),
+        };
+        let server = Server::new("127.0.0.1", &port, "", None, temp_utxo_set(bc)).unwrap();
+
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve(listener).await });
+        server
+    }
+
+    /// Polls `check` until it holds or a few seconds pass
+    async fn eventually(check: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_messages_require_handshake() {
+        let server = start_local_server().await;
+        let client = start_local_server().await;
+        let client_addr = client.node_address.clone();
+
+        // Anything but the handshake is turned away
+        let mut stream = TcpStream::connect(&server.node_address).await.unwrap();
+        let ping = PingMessage {
+            addr_from: client_addr.clone(),
+            nonce: 7,
+            timestamp: 0,
+        };
+        write_frame(&mut stream, "ping", &serialize(&ping).unwrap())
+            .await
+            .unwrap();
+        let (cmd, _) = read_frame(&mut stream).await.unwrap();
+        assert_eq!(cmd, "version");
+        assert!(read_frame(&mut stream).await.is_err());
+
+        // After it, the answer comes back on the same connection
+        let mut stream = TcpStream::connect(&server.node_address).await.unwrap();
+        let version = client.handshake(&mut stream).await.unwrap();
+        assert_eq!(version.addr_from, server.node_address);
+        write_frame(&mut stream, "ping", &serialize(&ping).unwrap())
+            .await
+            .unwrap();
+        loop {
+            let (cmd, payload) = read_frame(&mut stream).await.unwrap();
+            if cmd == "pong" {
+                let pong: PongMessage = deserialize(&payload).unwrap();
+                assert_eq!(pong.nonce, 7);
+                break;
+            }
+        }
+        assert!(server.is_connected(&stream.local_addr().unwrap().to_string()));
+        assert!(!server.is_connected(&client_addr));
+    }
+
+    #[tokio::test]
+    async fn test_misbehaving_ips_are_refused_before_the_handshake() {
+        let server = start_local_server().await;
+        let client = start_local_server().await;
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // Malformed messages add up to a ban of the socket's IP
+        let mut stream = TcpStream::connect(&server.node_address).await.unwrap();
+        client.handshake(&mut stream).await.unwrap();
+        for _ in 1..peers::BAN_THRESHOLD / MALFORMED_MESSAGE_SCORE {
+            write_frame(&mut stream, "ping", &[1]).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_banned(ip));
+        write_frame(&mut stream, "ping", &[1]).await.unwrap();
+        assert!(eventually(|| server.is_banned(ip)).await);
+        assert!(eventually(|| server.get_peers().is_empty()).await);
+
+        // Whatever port it comes from, it gets no version
+        let mut stream = TcpStream::connect(&server.node_address).await.unwrap();
+        assert!(read_frame(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_share_the_peer_connection() {
+        let node = start_local_server().await;
+        let client = start_local_server().await;
+
+        // The node has no chain to build on; its refusal still comes back
+        for _ in 0..2 {
+            let err = client
+                .get_block_template(&node.node_address, "not an address")
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("No template"), "{}", err);
+        }
+        assert_eq!(client.get_peers(), vec![node.node_address.clone()]);
+        assert_eq!(node.connection_count(false), 1);
+
+        // Requests on a connection that closes fail without waiting
+        let (reply, response) = oneshot::channel();
+        client
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .get_mut(&node.node_address)
+            .unwrap()
+            .requests
+            .entry(String::from("template"))
+            .or_default()
+            .push_back(reply);
+        node.close_connections().await;
+        assert!(time::timeout(Duration::from_secs(5), response)
+            .await
+            .unwrap()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inbound_peers_are_known_by_socket_address() {
+        let a = start_local_server().await;
+        let b = start_local_server().await;
+
+        // The address b says it listens on is remembered, not trusted
+        b.connect(&a.node_address).await.unwrap();
+        assert!(b.is_connected(&a.node_address));
+        assert!(eventually(|| a.connection_count(false) == 1).await);
+        assert!(!a.is_connected(&b.node_address));
+        {
+            let inner = a.inner.lock().unwrap();
+            assert!(inner.peers.is_known(&b.node_address));
+            assert!(inner.peers.connected_peers().is_empty());
+        }
+
+        // Dialing it checks it, and a connection claiming it afterwards
+        // does not take the place of ours
+        a.connect(&b.node_address).await.unwrap();
+        let dialed = a.inner.lock().unwrap().connections[&b.node_address].id;
+        assert_eq!(
+            a.inner.lock().unwrap().peers.connected_peers(),
+            vec![b.node_address.clone()]
+        );
+        let port = b.node_address.rsplit(':').next().unwrap();
+        let bc = Blockchain {
+            tip: String::new(),
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            params: Default::default(),
+        };
+        let impostor = Server::new("127.0.0.1", port, "", None, temp_utxo_set(bc)).unwrap();
+        let mut stream = TcpStream::connect(&a.node_address).await.unwrap();
+        impostor.handshake(&mut stream).await.unwrap();
+        let socket = stream.local_addr().unwrap().to_string();
+        assert!(eventually(|| a.is_connected(&socket)).await);
+        assert_eq!(
+            a.inner.lock().unwrap().connections[&b.node_address].id,
+            dialed
+        );
+        drop(stream);
+
+        // Messages are handled in order, so once the pong is back neither
+        // side has replies left to send that would dial the other again
+        a._send_ping(&b.node_address).await.unwrap();
+        assert!(
+            eventually(|| a
+                .inner
+                .lock()
+                .unwrap()
+                .ping_times
+                .contains_key(&b.node_address))
+            .await
+        );
+
+        // Closing one end is noticed by the other
+        b.close_connections().await;
+        assert!(eventually(|| a.get_peers().is_empty()).await);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_state() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxo_set = || {
+            temp_utxo_set(Blockchain {
+                tip: String::new(),
+                db: db.clone(),
+                params: Default::default(),
+            })
+        };
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port().to_string()
+        };
+        let server = Server::new("127.0.0.1", &port, "", Some("127.0.0.1:1"), utxo_set()).unwrap();
+        let tx = Transaction {
+            id: String::from("unmineable"),
+            vin: Vec::new(),
+            vout: Vec::new(),
+        };
+        server
+            .chain
+            .lock()
+            .unwrap()
+            .mempool
+            .insert(tx.id.clone(), tx.clone());
+
+        // Stopping unblocks the accept loop and the sleeping background tasks
+        let handle = server.shutdown_handle();
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.start_server().await }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        let peer = start_local_server().await;
+        peer.connect(&server.node_address).await.unwrap();
+        assert!(eventually(|| server.connection_count(false) == 1).await);
+        handle.shutdown();
+        time::timeout(Duration::from_secs(5), handle.stopped())
+            .await
+            .unwrap();
+        running.await.unwrap().unwrap();
+
+        // Connection tasks are waited for, and no connection outlives the server
+        assert!(server.tasks.lock().unwrap().is_empty());
+        assert!(server.get_peers().is_empty());
+        assert!(eventually(|| !peer.is_connected(&server.node_address)).await);
+        assert!(db
+            .open_tree(MEMPOOL_TREE)
+            .unwrap()
+            .contains_key(&tx.id)
+            .unwrap());
+
+        // Peers come back on restart; saved transactions are checked again
+        let restarted = Server::new("127.0.0.1", &port, "", None, utxo_set()).unwrap();
+        restarted.load_state().await.unwrap();
+        assert!(restarted
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .is_known("127.0.0.1:1"));
+        assert!(!restarted.has_transaction(&tx.id).await.unwrap());
+    }
 }
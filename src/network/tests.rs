@@ -9,7 +9,8 @@ mod tests {
     use crate::network::server::Server;
     use crate::Result;
 
-    use std::{env, net::TcpStream, thread, time::Duration};
+    use std::{env, net::TcpStream, time::Duration};
+    use tokio::time::sleep;
 
     /// Skip test if no remote node is configured
     fn require_remote_node() -> Option<String> {
@@ -63,27 +64,35 @@ mod tests {
         Ok((bc, wallets, address))
     }
 
+    /// Wraps a chain in a UTXO set kept in a throwaway database
+    fn temp_utxo_set(blockchain: Blockchain) -> UTXOSet {
+        UTXOSet {
+            blockchain,
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+
     /// Create and start a local server for testing
-    fn start_test_server(
+    async fn start_test_server(
         port: &str,
         mining_address: &str,
         bootstrap: Option<&str>,
     ) -> Result<Server> {
         let (bc, _, _) = setup_test_environment()?;
-        let utxo_set = UTXOSet { blockchain: bc };
+        let utxo_set = temp_utxo_set(bc);
 
         let server = Server::new("0.0.0.0", port, mining_address, bootstrap, utxo_set)?;
 
-        // Start server in background thread
+        // Start server in a background task
         let server_clone = server.clone();
-        thread::spawn(move || {
-            if let Err(e) = server_clone.start_server() {
+        tokio::spawn(async move {
+            if let Err(e) = server_clone.start_server().await {
                 eprintln!("Server error: {}", e);
             }
         });
 
         // Give server time to start
-        thread::sleep(Duration::from_secs(2));
+        sleep(Duration::from_secs(2)).await;
 
         Ok(server)
     }
@@ -108,8 +117,8 @@ mod tests {
     }
 
     /// Test version exchange with a remote node
-    #[test]
-    fn test_version_exchange() {
+    #[tokio::test]
+    async fn test_version_exchange() {
         let remote_addr = match require_remote_node() {
             Some(addr) => addr,
             None => return, // Skip test if no remote node
@@ -117,7 +126,7 @@ mod tests {
 
         // Create a test server with the remote node as bootstrap
         let port = get_local_port();
-        let server = match start_test_server(&port, "", Some(&remote_addr)) {
+        let server = match start_test_server(&port, "", Some(&remote_addr)).await {
             Ok(s) => s,
             Err(e) => {
                 panic!("Failed to create test server: {}", e);
@@ -125,7 +134,7 @@ mod tests {
         };
 
         // Give time for version exchange
-        thread::sleep(Duration::from_secs(5));
+        sleep(Duration::from_secs(5)).await;
 
         // Stop server
         let _ = server.stop_server();
@@ -134,9 +143,8 @@ mod tests {
     }
 
     /// Test sending a transaction to a remote node
-    #[test]
-    fn test_send_transaction_to_remote() {
-        use crate::crypto::fndsa::FnDsaCrypto;
+    #[tokio::test]
+    async fn test_send_transaction_to_remote() {
         use crate::crypto::transaction::Transaction;
         let remote_addr = match require_remote_node() {
             Some(addr) => addr,
@@ -151,7 +159,7 @@ mod tests {
             }
         };
 
-        let utxo_set = UTXOSet { blockchain: bc };
+        let utxo_set = temp_utxo_set(bc);
 
         // Create a new wallet for receiving
         let mut wallets_clone = wallets.clone();
@@ -160,7 +168,7 @@ mod tests {
 
         // Set up test server
         let port = get_local_port();
-        let server = match start_test_server(&port, "", None) {
+        let server = match start_test_server(&port, "", None).await {
             Ok(s) => s,
             Err(e) => {
                 panic!("Failed to start test server: {}", e);
@@ -169,13 +177,13 @@ mod tests {
 
         // Get wallet and crypto provider
         let from_wallet = wallets.get_wallet(&from_address).unwrap();
-        let crypto = FnDsaCrypto;
+        let crypto = from_wallet.crypto_provider();
 
         // Create and send a transaction
-        match Transaction::new_UTXO(from_wallet, &to_address, 1, &utxo_set, &crypto) {
+        match Transaction::new_UTXO(from_wallet, &to_address, 1, &utxo_set, crypto.as_ref()) {
             Ok(tx) => {
                 // Send transaction to remote node
-                let result = server.send_tx(&remote_addr, &tx);
+                let result = server.send_tx(&remote_addr, &tx).await;
 
                 if let Err(e) = result {
                     println!("Transaction send failed: {}. This might be expected.", e);
@@ -194,8 +202,8 @@ mod tests {
         // Stop server
         let _ = server.stop_server();
     }
-    #[test]
-    fn test_blockchain_sync() {
+    #[tokio::test]
+    async fn test_blockchain_sync() {
         let remote_addr = match require_remote_node() {
             Some(addr) => addr,
             None => return, // Skip test if no remote node
@@ -218,7 +226,7 @@ mod tests {
 
         // Set up server with the remote node as bootstrap
         let port = get_local_port();
-        let utxo_set = UTXOSet { blockchain: bc };
+        let utxo_set = temp_utxo_set(bc);
         let server = match Server::new("0.0.0.0", &port, "", Some(&remote_addr), utxo_set) {
             Ok(s) => s,
             Err(e) => {
@@ -228,15 +236,15 @@ mod tests {
 
         // Start server (this should trigger blockchain sync)
         let server_clone = server.clone();
-        thread::spawn(move || {
-            if let Err(e) = server_clone.start_server() {
+        tokio::spawn(async move {
+            if let Err(e) = server_clone.start_server().await {
                 eprintln!("Server error: {}", e);
             }
         });
 
         // Give time for sync (this might need to be longer for larger blockchains)
         println!("Waiting for blockchain sync (30 seconds)...");
-        thread::sleep(Duration::from_secs(30));
+        sleep(Duration::from_secs(30)).await;
 
         // Stop server
         let _ = server.stop_server();
@@ -257,8 +265,8 @@ mod tests {
     }
 
     /// Test remote wallet operations (requires a remote node with wallets)
-    #[test]
-    fn test_remote_wallet_operations() {
+    #[tokio::test]
+    async fn test_remote_wallet_operations() {
         use crate::crypto::transaction::Transaction;
         let remote_addr = match require_remote_node() {
             Some(addr) => addr,
@@ -273,11 +281,11 @@ mod tests {
             }
         };
 
-        let _utxo_set = UTXOSet { blockchain: bc };
+        let _utxo_set = temp_utxo_set(bc);
 
         // Create a test server
         let port = get_local_port();
-        let server = match start_test_server(&port, "", None) {
+        let server = match start_test_server(&port, "", None).await {
             Ok(s) => s,
             Err(e) => {
                 panic!("Failed to start test server: {}", e);
@@ -293,7 +301,9 @@ mod tests {
 
         // Try to request remote signing
         println!("Requesting remote signing from {}", remote_addr);
-        let result = server.send_sign_request(&remote_addr, "test_wallet_address", &tx);
+        let result = server
+            .send_sign_request(&remote_addr, "test_wallet_address", &tx)
+            .await;
 
         // Allow failure as the remote node might not have the requested wallet
         if let Err(e) = result {
@@ -1,7 +1,15 @@
+pub mod changepassphrase;
 pub mod createwallet;
+pub mod encryptwallet;
+pub mod exportwallet;
+pub mod history;
+pub mod importwallet;
 pub mod listaddresses;
 pub mod printchain;
 pub mod reindex;
 pub mod startminer;
 pub mod startnode;
+pub mod stopnode;
+pub mod unlockwallet;
+#[allow(clippy::module_inception)]
 pub mod webserver;
@@ -0,0 +1,21 @@
+use crate::command::cli::cmd_change_passphrase;
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct ChangePassphraseRequest {
+    address: String,
+    old_passphrase: String,
+    new_passphrase: String,
+}
+
+#[post("/change-passphrase")]
+pub async fn change_passphrase(req: web::Json<ChangePassphraseRequest>) -> impl Responder {
+    if req.new_passphrase.is_empty() {
+        return HttpResponse::BadRequest().body("passphrase must not be empty");
+    }
+    match cmd_change_passphrase(&req.address, &req.old_passphrase, &req.new_passphrase) {
+        Ok(()) => HttpResponse::Ok().body(req.address.clone()),
+        Err(err) => HttpResponse::Unauthorized().body(err.to_string()),
+    }
+}
@@ -11,6 +11,9 @@ impl FromStr for EncryptionType {
         match s.to_uppercase().as_str() {
             "ECDSA" => Ok(EncryptionType::ECDSA),
             "FNDSA" => Ok(EncryptionType::FNDSA),
+            "MLDSA" => Ok(EncryptionType::MLDSA),
+            "SLHDSA" => Ok(EncryptionType::SLHDSA),
+            "HYBRID" => Ok(EncryptionType::HYBRID),
             _ => Err(()),
         }
     }
@@ -21,10 +24,20 @@ struct CryptoPath {
     encryption: String,
 }
 
+/// Unlocks the mnemonic, once wallets are derived from one
+#[derive(Deserialize)]
+struct CreateWalletRequest {
+    passphrase: Option<String>,
+}
+
 #[post("/create_wallet/{encryption}")]
-pub async fn create_wallet(path: web::Path<CryptoPath>) -> impl Responder {
+pub async fn create_wallet(
+    path: web::Path<CryptoPath>,
+    req: Option<web::Json<CreateWalletRequest>>,
+) -> impl Responder {
+    let passphrase = req.and_then(|req| req.into_inner().passphrase);
     match path.encryption.parse::<EncryptionType>() {
-        Ok(encryption) => match cmd_create_wallet(encryption) {
+        Ok(encryption) => match cmd_create_wallet(encryption, passphrase.as_deref()) {
             Ok(msg) => HttpResponse::Ok().body(msg),
             Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
         },
@@ -0,0 +1,20 @@
+use crate::command::cli::cmd_encrypt_wallet;
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct EncryptWalletRequest {
+    address: String,
+    passphrase: String,
+}
+
+#[post("/encrypt-wallet")]
+pub async fn encrypt_wallet(req: web::Json<EncryptWalletRequest>) -> impl Responder {
+    if req.passphrase.is_empty() {
+        return HttpResponse::BadRequest().body("passphrase must not be empty");
+    }
+    match cmd_encrypt_wallet(&req.address, &req.passphrase) {
+        Ok(()) => HttpResponse::Ok().body(req.address.clone()),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
@@ -0,0 +1,28 @@
+use crate::command::cli::cmd_export_wallet;
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct ExportWalletRequest {
+    address: String,
+    /// Includes the secret key, which then leaves sealed
+    #[serde(default)]
+    include_secret: bool,
+    /// Seals a plaintext key in the exported file
+    passphrase: Option<String>,
+}
+
+#[post("/export-wallet")]
+pub async fn export_wallet(req: web::Json<ExportWalletRequest>) -> impl Responder {
+    let passphrase = req.passphrase.as_deref().filter(|p| !p.is_empty());
+    if req.include_secret && passphrase.is_none() {
+        return HttpResponse::BadRequest()
+            .body("a passphrase is required to export the secret key");
+    }
+    match cmd_export_wallet(&req.address, !req.include_secret, passphrase) {
+        Ok(json) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
@@ -0,0 +1,17 @@
+use crate::command::cli::cmd_history;
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct HistoryPath {
+    address: String,
+}
+
+/// Lists the outputs an address received and where they were spent, as JSON
+#[post("/history/{address}")]
+pub async fn history(path: web::Path<HistoryPath>) -> impl Responder {
+    match cmd_history(&path.address) {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
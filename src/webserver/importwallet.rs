@@ -0,0 +1,40 @@
+use crate::command::cli::{cmd_import_wallet, cmd_watch_address, cmd_watch_public_key};
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct WatchWalletRequest {
+    address: Option<String>,
+    public_key: Option<String>,
+    encryption: Option<String>,
+}
+
+/// Imports a wallet file, sent as the request body
+#[post("/import-wallet")]
+pub async fn import_wallet(body: String) -> impl Responder {
+    match cmd_import_wallet(&body) {
+        Ok(address) => HttpResponse::Ok().body(address),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Watches an address, or the address of a hex public key
+#[post("/watch-wallet")]
+pub async fn watch_wallet(req: web::Json<WatchWalletRequest>) -> impl Responder {
+    let req = req.into_inner();
+    let result = match (req.address, req.public_key) {
+        (Some(address), None) => cmd_watch_address(&address).map(|_| address),
+        (None, Some(public_key)) => {
+            let encryption = match req.encryption.as_deref().unwrap_or("FNDSA").parse() {
+                Ok(encryption) => encryption,
+                Err(_) => return HttpResponse::BadRequest().body("不正な暗号方式です"),
+            };
+            cmd_watch_public_key(&public_key, encryption)
+        }
+        _ => return HttpResponse::BadRequest().body("give either address or public_key"),
+    };
+    match result {
+        Ok(address) => HttpResponse::Ok().body(address),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
@@ -1,4 +1,5 @@
 use crate::command::cil_startminer::cmd_start_miner_from_api;
+use crate::webserver::webserver::{claim_node, release_node, set_node_handle, Nodes};
 use actix_web::{post, web, HttpResponse, Responder};
 use serde::Deserialize;
 
@@ -11,7 +12,10 @@ struct StartMinerRequest {
 }
 
 #[post("/start-miner")]
-pub async fn start_miner(req: web::Json<StartMinerRequest>) -> impl Responder {
+pub async fn start_miner(
+    req: web::Json<StartMinerRequest>,
+    nodes: web::Data<Nodes>,
+) -> impl Responder {
     let req_data = req.into_inner();
     println!(
         "@start-Miner called: host={}, port={}",
@@ -22,14 +26,30 @@ pub async fn start_miner(req: web::Json<StartMinerRequest>) -> impl Responder {
     let port = req_data.port.clone();
     let bootstrap = req_data.bootstrap.clone();
     let mining_address = req_data.mining_address.clone();
+    let address = format!("{}:{}", host, port);
+    let id = match claim_node(&nodes, &address) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::Conflict()
+                .body(format!("A node is already started at {}", address))
+        }
+    };
 
     tokio::task::spawn_blocking(move || {
-        if let Err(e) =
-            cmd_start_miner_from_api(&host, &port, bootstrap.as_deref(), &mining_address)
+        let exited = {
+            let (nodes, address) = (nodes.clone(), address.clone());
+            move || release_node(&nodes, &address, id)
+        };
+        match cmd_start_miner_from_api(&host, &port, bootstrap.as_deref(), &mining_address, exited)
         {
-            eprintln!("Miner failed to start: {}", e);
-        } else {
-            println!("Miner started successfully");
+            Ok(handle) => {
+                set_node_handle(&nodes, &address, id, handle);
+                println!("Miner started successfully");
+            }
+            Err(e) => {
+                release_node(&nodes, &address, id);
+                eprintln!("Miner failed to start: {}", e);
+            }
         }
     });
 
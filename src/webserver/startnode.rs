@@ -1,4 +1,5 @@
 use crate::command::cil_startnode::cmd_start_node_from_api;
+use crate::webserver::webserver::{claim_node, release_node, set_node_handle, Nodes};
 use actix_web::{post, web, HttpResponse, Responder};
 use serde::Deserialize;
 
@@ -10,7 +11,10 @@ struct StartNodeRequest {
 }
 
 #[post("/start-node")]
-pub async fn start_node(req: web::Json<StartNodeRequest>) -> impl Responder {
+pub async fn start_node(
+    req: web::Json<StartNodeRequest>,
+    nodes: web::Data<Nodes>,
+) -> impl Responder {
     let req_data = req.into_inner();
     println!(
         "@start-Node called: host={}, port={}",
@@ -20,12 +24,29 @@ pub async fn start_node(req: web::Json<StartNodeRequest>) -> impl Responder {
     let host = req_data.host.clone();
     let port = req_data.port.clone();
     let bootstrap = req_data.bootstrap.clone();
+    let address = format!("{}:{}", host, port);
+    let id = match claim_node(&nodes, &address) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::Conflict()
+                .body(format!("A node is already started at {}", address))
+        }
+    };
 
     tokio::task::spawn_blocking(move || {
-        if let Err(e) = cmd_start_node_from_api(&host, &port, bootstrap.as_deref()) {
-            eprintln!("Node failed to start: {}", e);
-        } else {
-            println!("Node started successfully");
+        let exited = {
+            let (nodes, address) = (nodes.clone(), address.clone());
+            move || release_node(&nodes, &address, id)
+        };
+        match cmd_start_node_from_api(&host, &port, bootstrap.as_deref(), exited) {
+            Ok(handle) => {
+                set_node_handle(&nodes, &address, id, handle);
+                println!("Node started successfully");
+            }
+            Err(e) => {
+                release_node(&nodes, &address, id);
+                eprintln!("Node failed to start: {}", e);
+            }
         }
     });
 
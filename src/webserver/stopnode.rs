@@ -0,0 +1,36 @@
+use crate::webserver::webserver::Nodes;
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct StopNodeRequest {
+    host: String,
+    port: String,
+}
+
+/// Stops a node or miner started through the API once it has saved its state
+///
+/// A node that is still starting cannot be stopped yet.
+#[post("/stop-node")]
+pub async fn stop_node(req: web::Json<StopNodeRequest>, nodes: web::Data<Nodes>) -> impl Responder {
+    let address = format!("{}:{}", req.host, req.port);
+    let handle = {
+        let mut nodes = nodes.lock().unwrap();
+        match nodes.get(&address).map(|entry| entry.handle.is_some()) {
+            Some(true) => nodes.remove(&address).and_then(|entry| entry.handle),
+            Some(false) => {
+                return HttpResponse::Conflict()
+                    .body(format!("The node at {} is still starting", address))
+            }
+            None => None,
+        }
+    };
+    match handle {
+        Some(handle) => {
+            handle.shutdown();
+            handle.stopped().await;
+            HttpResponse::Ok().body(address)
+        }
+        None => HttpResponse::NotFound().body(format!("No node started at {}", address)),
+    }
+}
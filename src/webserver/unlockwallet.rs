@@ -0,0 +1,31 @@
+use crate::command::cli::{cmd_lock_wallet, cmd_unlock_wallet};
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct UnlockWalletRequest {
+    address: String,
+    passphrase: String,
+}
+
+#[derive(Deserialize)]
+struct LockWalletRequest {
+    address: String,
+}
+
+/// Unlocks a wallet so nodes started from this server can sign with it
+#[post("/unlock-wallet")]
+pub async fn unlock_wallet(req: web::Json<UnlockWalletRequest>) -> impl Responder {
+    match cmd_unlock_wallet(&req.address, &req.passphrase) {
+        Ok(()) => HttpResponse::Ok().body(req.address.clone()),
+        Err(err) => HttpResponse::Unauthorized().body(err.to_string()),
+    }
+}
+
+#[post("/lock-wallet")]
+pub async fn lock_wallet(req: web::Json<LockWalletRequest>) -> impl Responder {
+    match cmd_lock_wallet(&req.address) {
+        Ok(()) => HttpResponse::Ok().body(req.address.clone()),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
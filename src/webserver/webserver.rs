@@ -1,26 +1,110 @@
+use crate::network::server::ShutdownHandle;
+use crate::webserver::changepassphrase;
 use crate::webserver::createwallet;
+use crate::webserver::encryptwallet;
+use crate::webserver::exportwallet;
+use crate::webserver::history;
+use crate::webserver::importwallet;
 use crate::webserver::listaddresses;
 use crate::webserver::printchain;
 use crate::webserver::reindex;
 use crate::webserver::startminer;
 use crate::webserver::startnode;
-use actix_web::{App, HttpServer};
+use crate::webserver::stopnode;
+use crate::webserver::unlockwallet;
+use actix_web::{web, App, HttpServer};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Nodes started through the API, by address, so they can be stopped again
+pub type Nodes = Mutex<HashMap<String, NodeEntry>>;
+
+/// NodeEntry claims an address for a node started through the API
+///
+/// The claim is made before the node starts, so a second start at the same
+/// address is refused, and is freed when the node exits. `handle` is set
+/// once the node is running.
+pub struct NodeEntry {
+    id: u64,
+    pub handle: Option<ShutdownHandle>,
+}
+
+/// Claims `address` for a node about to start, returning the claim's id,
+/// or None if a node is already started there
+pub fn claim_node(nodes: &Nodes, address: &str) -> Option<u64> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let mut nodes = nodes.lock().unwrap();
+    if nodes.contains_key(address) {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    nodes.insert(address.to_string(), NodeEntry { id, handle: None });
+    Some(id)
+}
+
+/// Stores the handle of the node holding claim `id` on `address`
+///
+/// A node whose claim is gone was stopped while it started, so it is shut
+/// down instead.
+pub fn set_node_handle(nodes: &Nodes, address: &str, id: u64, handle: ShutdownHandle) {
+    match nodes.lock().unwrap().get_mut(address) {
+        Some(entry) if entry.id == id => entry.handle = Some(handle),
+        _ => handle.shutdown(),
+    }
+}
+
+/// Frees `address` if claim `id` still holds it
+pub fn release_node(nodes: &Nodes, address: &str, id: u64) {
+    let mut nodes = nodes.lock().unwrap();
+    if nodes.get(address).is_some_and(|entry| entry.id == id) {
+        nodes.remove(address);
+    }
+}
 
 pub struct WebServer {}
 
 impl WebServer {
+    #[allow(clippy::new_ret_no_self)]
     pub async fn new() -> std::io::Result<()> {
-        HttpServer::new(|| {
+        let nodes = web::Data::new(Nodes::default());
+        let app_nodes = nodes.clone();
+        HttpServer::new(move || {
             App::new()
+                .app_data(app_nodes.clone())
                 .service(createwallet::create_wallet)
                 .service(printchain::print_chain)
                 .service(listaddresses::list_addresses)
                 .service(reindex::reindex)
                 .service(startnode::start_node)
                 .service(startminer::start_miner)
+                .service(stopnode::stop_node)
+                .service(encryptwallet::encrypt_wallet)
+                .service(unlockwallet::unlock_wallet)
+                .service(unlockwallet::lock_wallet)
+                .service(changepassphrase::change_passphrase)
+                .service(exportwallet::export_wallet)
+                .service(importwallet::import_wallet)
+                .service(importwallet::watch_wallet)
+                .service(history::history)
         })
         .bind(("127.0.0.1", 7000))?
         .run()
-        .await
+        .await?;
+
+        // The HTTP server stops on SIGINT or SIGTERM; so do the nodes it started
+        let handles: Vec<ShutdownHandle> = nodes
+            .lock()
+            .unwrap()
+            .drain()
+            .filter_map(|(_, entry)| entry.handle)
+            .collect();
+        for handle in &handles {
+            handle.shutdown();
+        }
+        for handle in &handles {
+            handle.stopped().await;
+        }
+        Ok(())
     }
 }